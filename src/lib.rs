@@ -1,18 +1,102 @@
 use near_sdk::{
     borsh::{self, BorshDeserialize, BorshSerialize},
-    collections::{UnorderedMap, Vector},
-    env, near_bindgen, AccountId, PanicOnDefault, Promise, NearToken
+    collections::{LookupMap, UnorderedMap, UnorderedSet, Vector},
+    assert_one_yocto, env, is_promise_success, near_bindgen, AccountId, PanicOnDefault, Promise, NearToken,
+    PromiseError, PromiseOrValue, PromiseResult, StorageUsage,
 };
-use near_sdk::{json_types::U128, Gas};
+use near_sdk::{json_types::{U128, U64}, Gas};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use serde_json::json;
 use near_sdk::serde::{Deserialize, Serialize};
 use near_contract_standards::fungible_token::Balance;
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
 
 
 const DAY: u64 = 86400; // Seconds in a day
 const MONTH: u64 = 30 * DAY; // Seconds in a month
+const YEAR: u64 = 365 * DAY; // Seconds in a year
+const ESTIMATE_SAMPLE_ROUNDS: u64 = 3; // Recent rounds averaged by the yearly reward estimate
+const MAX_LEADERBOARD_SIZE: u64 = 100;
+const EMISSION_PRECISION: u128 = 1_000_000_000_000; // Scale of the accumulated reward per weight
+const BPS_DENOMINATOR: u128 = 10_000; // Basis points in 100%
+const MAX_KEEPER_BOUNTY_BPS: u32 = 500; // Keeper bounty is capped at 5% of a round
+const KEEPER_CHUNK_SIZE: u64 = 50; // Stakers processed by a keeper-triggered round in its first call
+const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(50);
+const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas::from_tgas(10);
+const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas::from_tgas(100);
+const GAS_FOR_NFT_TRANSFER: Gas = Gas::from_tgas(20);
+const GAS_FOR_NFT_TOKEN: Gas = Gas::from_tgas(10);
+const GAS_FOR_RARITY_ORACLE: Gas = Gas::from_tgas(10);
+const GAS_FOR_FT_BALANCE_OF: Gas = Gas::from_tgas(10);
+const GAS_FOR_RECONCILE: Gas = Gas::from_tgas(10);
+const GAS_FOR_RESOLVE_RESCUE: Gas = Gas::from_tgas(60); // Covers the rescue transfer it starts
+const GAS_FOR_RESOLVE_STAKE: Gas = Gas::from_tgas(20);
+const GAS_FOR_MINT_POSITION: Gas = Gas::from_tgas(20);
+const GAS_FOR_EMERGENCY_RETURN: Gas = Gas::from_tgas(5); // Bookkeeping per returned record
+const MAX_STAKING_CONSUMERS: usize = 5; // Keeps the consumer allowlist small
+const MAX_REWARD_TOKENS: u64 = 5; // Bounds the tokens settled on every staker checkpoint
+const MAX_CLAIM_FEE_BPS: u32 = 2000; // Caps the treasury fee on claims at 20%
+const MAX_CAMPAIGNS: u64 = 5; // Bounds the bonus farms updated on every staker checkpoint
+const CAMPAIGN_CLAIM_WINDOW: u64 = MONTH; // Seconds stakers have to claim after a farm ends
+const MIN_REWARD_EXPIRY: u64 = 3 * MONTH; // Stakers get at least three months to claim rewards
+const MIN_ACCOUNT_SHARE_BPS: u32 = 100; // Bounds the accounts tracked against the cap of a round
+const HIVE_SET_TYPES: [&str; 3] = ["Queen", "Worker", "Drone"]; // Types that make up a full hive set
+const MAX_SET_BONUS_BPS: u32 = 10_000; // Hive set bonus can at most double a staker's weight
+const MAX_LOCKUP_MULTIPLIER_BPS: u32 = 50_000; // Longest lockups earn at most 5x weight
+const MIN_SCARCITY_BPS: u128 = 2_500; // Scarcity weighting scales a type weight by 0.25x to 4x
+const MAX_SCARCITY_BPS: u128 = 40_000;
+const MAX_TIMELOCK_DURATION: u64 = MONTH; // Upper bound so a timelock can't freeze config forever
+const MAX_MIN_CLAIM_AMOUNT: u128 = 1_000 * 10u128.pow(24); // 1,000 SIN, so small stakers can claim
+const MAX_HALVING_INTERVAL: u64 = 10 * YEAR; // Keeps the halving schedule within u64 nanoseconds
+const STORAGE_MIN_DEPOSIT: NearToken = NearToken::from_millinear(10); // Registration covers ~1 KB
+const MAX_MEMO_LENGTH: usize = 256; // Bytes of a funding memo stored in its record
+const EVENT_STANDARD: &str = "sin_nft_staking"; // NEP-297 standard name
+const EVENT_VERSION: &str = "1.0.0";
+
+#[allow(clippy::manual_div_ceil)]
+mod u256 {
+    uint::construct_uint! {
+        /// 256-bit unsigned integer used for intermediate reward math.
+        pub struct U256(4);
+    }
+}
+use u256::U256;
+
+/// Computes `a * b / c` in 256-bit precision, rounding down.
+fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    assert!(c > 0, "Division by zero in reward calculation");
+    (U256::from(a) * U256::from(b) / U256::from(c)).as_u128()
+}
+
+/// Part of `amount`, vesting linearly from `start` to `end`, that is still locked at `now`.
+fn unvested(amount: u128, start: u64, end: u64, now: u64) -> u128 {
+    if amount == 0 || now >= end {
+        return 0;
+    }
+    mul_div(amount, (end - now) as u128, (end - start) as u128)
+}
+
+/// Adds `amount` funded at `timestamp` to the totals of its month, the last one or a new one.
+fn add_to_funding_periods(periods: &mut Vector<FundingPeriod>, timestamp: u64, amount: Balance) {
+    let period = MONTH * 1_000_000_000;
+    let period_start = timestamp / period * period;
+    let last = periods.len().checked_sub(1);
+    match last.map(|last| (last, periods.get(last).unwrap())) {
+        Some((last, mut current)) if current.period_start.0 == period_start => {
+            current.count += 1;
+            current.total.0 += amount;
+            periods.replace(last, &current);
+        }
+        _ => periods.push(&FundingPeriod {
+            period_start: U64(period_start),
+            count: 1,
+            total: U128(amount),
+        }),
+    }
+}
 
 #[derive(BorshDeserialize, BorshSerialize, Clone, Serialize, Deserialize)]
 pub struct NFTStakingRecord {
@@ -21,355 +105,8527 @@ pub struct NFTStakingRecord {
     pub start_timestamp: u64,
     pub lockup_period: u64,
     pub claimed_rewards: u128,
+    pub lockup_start: u64, // When the current lockup began; moves forward on extend_lockup
+    pub auto_renew: bool, // Rolls into a new lockup of the same period whenever one expires
+    pub position_id: u64, // Stable identifier of the record, unlike its index
+    pub tokenized: bool, // Controlled by a position receipt on the position manager
+    pub emission_weight: u128, // Weight earning streamed rewards as of the last checkpoint
+    pub emission_acc: u128, // Accumulated reward per weight at the last checkpoint
+    pub vesting_amount: u128, // Rewards locked at `vesting_start`, unlocking until `vesting_end`
+    pub vesting_start: u64,
+    pub vesting_end: u64,
+    pub last_claim: u64, // Last claim or the staking time, which starts the rewards' expiry window
+    pub rarity_scores: HashMap<String, u32>, // NFT ID -> score replacing its type weight
+    pub nft_contracts: HashMap<String, AccountId>, // NFT ID -> collection, except SIN NFTs
+    pub staked_at: HashMap<String, u64>, // NFT ID -> staking time, kept once the start is averaged
 }
 
-#[derive(BorshDeserialize, BorshSerialize)]
-pub struct StakerInfo {
-    pub stakes: Vector<NFTStakingRecord>,
-    pub total_rewards_claimed: u128,
+impl NFTStakingRecord {
+    // Start of the lockup in effect at `now`. Auto-renewing records are rolled forward lazily
+    // by whole periods, so no transaction is needed at expiry.
+    pub fn current_lockup_start(&self, now: u64) -> u64 {
+        let period = self.lockup_period * 1_000_000_000;
+        if !self.auto_renew || period == 0 || now < self.lockup_start + period {
+            return self.lockup_start;
+        }
+        self.lockup_start + (now - self.lockup_start) / period * period
+    }
+
+    pub fn unlock_timestamp(&self, now: u64) -> u64 {
+        self.current_lockup_start(now) + self.lockup_period * 1_000_000_000
+    }
+
+    // Part of the record's rewards that has not vested yet at `now`
+    pub fn locked_rewards(&self, now: u64) -> u128 {
+        unvested(self.vesting_amount, self.vesting_start, self.vesting_end, now)
+            .min(self.claimed_rewards)
+    }
+
+    pub fn claimable_rewards(&self, now: u64) -> u128 {
+        self.claimed_rewards - self.locked_rewards(now)
+    }
+
+    // Credits `amount` to the record, vesting over `vesting_period` seconds. Whatever is still
+    // locked vests again over the full period along with it.
+    pub fn add_vesting_rewards(&mut self, amount: u128, now: u64, vesting_period: u64) {
+        if vesting_period > 0 {
+            self.vesting_amount = self.locked_rewards(now) + amount;
+            self.vesting_start = now;
+            self.vesting_end = now + vesting_period * 1_000_000_000;
+        }
+        self.claimed_rewards += amount;
+    }
+
+    // Deadline to claim the record's rewards before they can be swept back into the pool. Only a
+    // claim restarts the window, so records that keep earning still expire once abandoned. Vesting
+    // is linear, so part of the rewards is always claimable within the window.
+    pub fn claim_by(&self, reward_expiry: u64) -> Option<u64> {
+        (reward_expiry > 0).then_some(self.last_claim + reward_expiry * 1_000_000_000)
+    }
+
+    // Why an NFT staked with `stake_msg` can't join this record, if it can't
+    pub fn join_conflict(&self, stake_msg: &StakeMsg, now: u64) -> Option<&'static str> {
+        if stake_msg
+            .flexible
+            .is_some_and(|flexible| flexible != (self.lockup_period == 0))
+        {
+            return Some("Staking record to join has a different lockup");
+        }
+        if stake_msg
+            .auto_renew
+            .is_some_and(|auto_renew| auto_renew != self.auto_renew)
+        {
+            return Some("Staking record to join has a different auto-renew setting");
+        }
+        if self.lockup_period > 0 && now >= self.unlock_timestamp(now) {
+            return Some("Staking record to join has expired");
+        }
+        None
+    }
+
+    // When the NFT was staked, which the minimum stake age is checked against
+    pub fn nft_staked_at(&self, token_id: &String) -> u64 {
+        self.staked_at.get(token_id).copied().unwrap_or(self.start_timestamp)
+    }
+
+    // Records each NFT's own staking time before the record's start is averaged with another
+    pub fn pin_staked_at(&mut self) {
+        for token_id in self.nft_ids.iter() {
+            if !self.staked_at.contains_key(token_id) {
+                self.staked_at.insert(token_id.clone(), self.start_timestamp);
+            }
+        }
+    }
+
+    pub fn assert_not_tokenized(&self) {
+        assert!(
+            !self.tokenized,
+            "Position {} is tokenized, redeem its receipt first",
+            self.position_id
+        );
+    }
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+// Options accepted in the `msg` of `nft_transfer_call` when staking
+#[derive(Serialize, Deserialize, Clone, Default)]
 #[serde(crate = "near_sdk::serde")]
-pub struct FundingRecord {
-    pub amount: Balance,
-    pub timestamp: u64,
+pub struct StakeMsg {
+    pub auto_renew: Option<bool>,
+    pub flexible: Option<bool>, // No lockup, at the reduced flexible weight
+    pub join_stake_index: Option<u64>, // Appends the NFT to an existing record, inheriting its lockup
+    pub join_position_id: Option<u64>, // Same, by the record's position id, which never shifts
+    pub stake_for: Option<AccountId>, // Beneficiary credited with the record instead of the sender
 }
 
 #[derive(BorshDeserialize, BorshSerialize)]
-pub struct RewardDistribution {
-    pub total_reward_pool: Balance,
-    pub last_distributed: u64, // Timestamp of last reward distribution
-    pub funding_records: Vector<FundingRecord>, // Track funding history
+pub struct StakerInfo {
+    pub stakes: Vector<NFTStakingRecord>,
+    pub total_rewards_claimed: u128,
+    pub unclaimed_rewards: u128, // Rewards returned from failed transfers, claimable via claim_all_rewards
+    pub claims: Vector<ClaimRecord>, // Successful claims, oldest first
+    pub emission_weight: u128, // Sum of the records' emission weights
+    pub token_rewards: HashMap<AccountId, StakerTokenReward>, // Rewards in tokens other than SIN
+    pub near_reward: StakerTokenReward,
+    pub campaign_rewards: HashMap<u64, StakerTokenReward>, // Bonus farm rewards by campaign id
+    pub compounding: bool, // New SIN rewards are converted into a weight boost instead of paid out
+    pub compounded_rewards: Balance, // SIN converted so far, which sets the boost
+    pub last_claim: u64, // Timestamp of the last claim, for the claim cooldown
+    pub stake_window_start: u64, // Start of the 24h window limiting new records
+    pub window_stakes: u32, // Records created since `stake_window_start`
+    pub vesting_amount: u128, // Unvested rewards of unstaked records, kept in `unclaimed_rewards`
+    pub vesting_start: u64,
+    pub vesting_end: u64,
 }
 
-#[near_bindgen]
-#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
-pub struct NFTStakingContract {
-    pub owner: AccountId,
-    sin_token: AccountId,
-    sin_nft_contract: AccountId,
-    pub stakers: UnorderedMap<AccountId, StakerInfo>,
-    pub reward_pool: u128,
-    pub last_distributed: u64,
-    pub reward_distribution: RewardDistribution,
-    pub nft_weights: HashMap<String, u32>, // Map for NFT type -> Weight
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct StakerTokenReward {
+    pub unclaimed: Balance,
+    pub acc_reward_per_weight: u128, // Token accumulator at the last settlement
 }
 
-#[near_bindgen]
-impl NFTStakingContract {
-    #[init]
-    pub fn new(owner: AccountId, sin_token: AccountId, sin_nft_contract: AccountId) -> Self {
-        let mut nft_weights = HashMap::new();
-        nft_weights.insert("Queen".to_string(), 50);
-        nft_weights.insert("Worker".to_string(), 30);
-        nft_weights.insert("Drone".to_string(), 20);
-
+impl StakerInfo {
+    pub fn new(account_id: &AccountId) -> Self {
         Self {
-            owner,
-            sin_token,
-            sin_nft_contract,
-            stakers: UnorderedMap::new(b"s".to_vec()),
-            reward_pool: 0,
-            last_distributed: env::block_timestamp(),
-            reward_distribution: RewardDistribution {
-                total_reward_pool: 0,
-                last_distributed: env::block_timestamp(),
-                funding_records: Vector::new(b"fundings".to_vec()),
-            },
-            nft_weights,
+            stakes: Vector::new(format!("stakes_{}", account_id).as_bytes().to_vec()),
+            total_rewards_claimed: 0,
+            unclaimed_rewards: 0,
+            claims: Vector::new(format!("claims_{}", account_id).as_bytes().to_vec()),
+            emission_weight: 0,
+            token_rewards: HashMap::new(),
+            near_reward: StakerTokenReward::default(),
+            campaign_rewards: HashMap::new(),
+            compounding: false,
+            compounded_rewards: 0,
+            last_claim: 0,
+            stake_window_start: 0,
+            window_stakes: 0,
+            vesting_amount: 0,
+            vesting_start: 0,
+            vesting_end: 0,
         }
     }
 
-    #[payable]
-    pub fn ft_on_transfer(
-        &mut self,
-        sender_id: AccountId,
-        amount: U128,
-        msg: String,
-    ) -> U128 {
-        env::log_str(&format!("Received {} tokens from {}", amount.0, sender_id));
-        assert_eq!(
-            sender_id,
-            self.owner,
-            "Only Only contract owners are allowed to fund this reward pool"
-        );
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.sin_token,
-            "Only SIN tokens are accepted for funding"
-        );
-        assert!(amount.0 > 0, "Funding amount must be greater than zero");
-
-        // Update total reward pool
-        self.reward_distribution.total_reward_pool += amount.0;
+    // Part of `unclaimed_rewards` that has not vested yet at `now`
+    pub fn locked_rewards(&self, now: u64) -> u128 {
+        unvested(self.vesting_amount, self.vesting_start, self.vesting_end, now)
+            .min(self.unclaimed_rewards)
+    }
 
-        // Track funding record
-        self.reward_distribution.funding_records.push(&FundingRecord {
-            amount: amount.0,
-            timestamp: env::block_timestamp(),
-        });
+    // Moves the unvested rewards of an unstaked record into `unclaimed_rewards`. They keep
+    // unlocking until the later of the two schedules ends.
+    pub fn add_locked_rewards(&mut self, stake: &NFTStakingRecord, now: u64) {
+        let locked = stake.locked_rewards(now);
+        if locked == 0 {
+            return;
+        }
+        self.vesting_amount = self.locked_rewards(now) + locked;
+        self.vesting_start = now;
+        self.vesting_end = self.vesting_end.max(stake.vesting_end);
+        self.unclaimed_rewards += locked;
+    }
 
-        env::log_str(&format!(
-            "Reward pool funded with {} SIN tokens by {} with message {}",
-            amount.0, env::predecessor_account_id(), msg
-        ));
-        // Return 0 to indicate all tokens were accepted
-        U128(0)
+    // Records created in the 24h window that is still running at `now`
+    pub fn new_stakes_in_window(&self, now: u64) -> u32 {
+        if now >= self.stake_window_start + DAY * 1_000_000_000 {
+            return 0;
+        }
+        self.window_stakes
     }
 
-    #[payable]
-    pub fn nft_on_transfer(&mut self, sender_id: AccountId, token_id: String, msg: String) -> bool {
-        env::log_str(&format!("Received NFT {} from {} with metadata {}", token_id, sender_id, msg));
-        
-        // Ensure the call is from the authorized NFT contract
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.sin_nft_contract,
-            "NFT can only be transferred from the SIN NFT contract"
-        );
-    
-        // Parse the metadata directly from the msg parameter
-        let metadata: Value = serde_json::from_str(&msg).expect("Failed to parse metadata from msg");
-    
-        // Classify the NFT type
-        let nft_type = Self::classify_nft_type(&metadata);
-    
-        // Update staker information
-        let mut staker_info = self.stakers.get(&sender_id).unwrap_or_else(|| StakerInfo {
-            stakes: Vector::new(format!("stakes_{}", sender_id).as_bytes().to_vec()),
-            total_rewards_claimed: 0,
-        });
-    
-        let mut nft_types = HashMap::new();
-        nft_types.insert(token_id.clone(), nft_type);
-    
-        staker_info.stakes.push(&NFTStakingRecord {
-            nft_ids: vec![token_id.clone()],
-            nft_types,
-            start_timestamp: env::block_timestamp(),
-            lockup_period: MONTH,
-            claimed_rewards: 0,
-        });
-    
-        self.stakers.insert(&sender_id, &staker_info);
-    
-        env::log_str(&format!("NFT {} successfully staked by {}", token_id, sender_id));
-    
-        // Returning `false` ensures the NFT is not refunded
-        false
+    // Counts a record created at `now` against the 24h window
+    pub fn count_new_stake(&mut self, now: u64) {
+        let window_stakes = self.new_stakes_in_window(now);
+        if window_stakes == 0 {
+            self.stake_window_start = now;
+        }
+        self.window_stakes = window_stakes + 1;
     }
+}
 
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClaimRecord {
+    pub amount: U128,
+    pub timestamp: U64,
+    pub stake_index: Option<u64>, // None when claimed from every record at once
+    pub receiver_id: Option<AccountId>, // Set when paid to another wallet than the staker's
+}
 
-    pub fn classify_nft_type(meta: &Value) -> String {
-        // Safely access reference_blob and attributes
-        let binding = vec![];
-        let attributes = meta
-            .get("reference_blob")
-            .and_then(|blob| blob.get("attributes"))
-            .and_then(|attrs| attrs.as_array())
-            .unwrap_or(&binding);
-    
-        let mut is_queen = false;
-        let mut is_worker = false;
-    
-        for attribute in attributes {
-            if let (Some(trait_type), Some(value)) = (
-                attribute.get("trait_type").and_then(|t| t.as_str()),
-                attribute.get("value").and_then(|v| v.as_str()),
-            ) {
-                if trait_type == "Body" && value == "Queen" {
-                    is_queen = true;
-                } else if trait_type == "Wings" && value == "Diamond" {
-                    is_worker = true;
-                }
-            }
-        }
-    
-        // Prioritize Queen over Worker
-        if is_queen {
-            "Queen".to_string()
-        } else if is_worker {
-            "Worker".to_string()
-        } else {
-            "Drone".to_string() // Default to Drone if no specific match
+impl ClaimRecord {
+    pub fn new(amount: Balance, stake_index: Option<u64>) -> Self {
+        Self {
+            amount: U128(amount),
+            timestamp: U64(env::block_timestamp()),
+            stake_index,
+            receiver_id: None,
         }
     }
+}
 
-    pub fn distribute_rewards(&mut self, amount: U128) {
-        assert_eq!(
-            env::predecessor_account_id(),
-            self.owner,
-            "Only owner can distribute rewards"
-        );
+// Maps an NFT attribute to a type; the highest-priority matching rule decides the type
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TraitRule {
+    pub trait_type: String,
+    pub value: String,
+    pub nft_type: String,
+    pub priority: u32,
+}
 
-        assert!(
-            amount.0 <= self.reward_distribution.total_reward_pool,
-            "Insufficient funds in the reward pool for distribution"
-        );
+// Another NFT collection that can be staked next to the SIN collection, sharing the reward pool.
+// Its types are namespaced as `collection:type` and its tokens as `collection:token_id`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NftCollection {
+    pub nft_weights: HashMap<String, u32>,
+    pub trait_rules: Vec<TraitRule>,
+    pub default_nft_type: String,
+    pub enabled: bool, // Disabled collections accept no new stakes
+    pub weight_multiplier_bps: u32, // Scales the weight of the collection's NFTs, e.g. for partners
+}
 
-        let reward_pool = amount.0;
-        let mut total_tpes = 0.0;
-        let mut staker_tpes: HashMap<AccountId, Vec<(usize, f64)>> = HashMap::new();
+// Points an NFT earns for having `value` as its `trait_type` attribute
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TraitPoints {
+    pub trait_type: String,
+    pub value: String,
+    pub points: u32,
+}
 
-        for (staker_id, staker_info) in self.stakers.iter() {
-            let mut stakes_tpes = vec![];
-        
-            for i in 0..staker_info.stakes.len() {
-                let stake = staker_info.stakes.get(i as u64).unwrap();
-                let mut tpes = 0.0;
-        
-                for (_nft_id, nft_type) in stake.nft_types.iter() {
-                    let weight = self.nft_weights.get(nft_type).unwrap_or(&0);
-                    tpes += *weight as f64;
-                }
-        
-                // Cast `i` to `usize` for compatibility
-                stakes_tpes.push((i as usize, tpes));
-                total_tpes += tpes;
-            }
-        
-            staker_tpes.insert(staker_id.clone(), stakes_tpes);
-        }
+// Reads a numeric rarity score from the NFT metadata and uses it as the NFT's weight instead of
+// its type weight, clamped to [min_weight, max_weight]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RarityScoreConfig {
+    pub trait_type: String, // Attribute holding the score, or a top-level metadata field
+    pub min_weight: u32,
+    pub max_weight: u32,
+}
 
-        for (staker_id, stakes_tpes) in staker_tpes {
-            let mut staker_info = self.stakers.get(&staker_id).unwrap();
+// Contract scoring NFTs at staking time. Its scores are clamped to [min_weight, max_weight].
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RarityOracleConfig {
+    pub oracle_id: AccountId,
+    pub min_weight: u32,
+    pub max_weight: u32,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LockupTier {
+    pub lockup_period: u64, // Seconds
+    pub early_exit_penalty_bps: u32, // Penalty when leaving at the start, decaying to 0 at maturity
+    pub reward_multiplier_bps: u32, // Weight multiplier for records locked in this tier
+}
 
-            for (i, tpes) in stakes_tpes {
-                let reward_percentage = reward_pool as f64 / total_tpes;
-                let reward = (tpes * reward_percentage) as u128;
+// Sensitive configuration changes that are subject to the timelock
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ConfigChange {
+    NftWeight {
+        nft_type: String,
+        weight: u32,
+    },
+    SinToken {
+        sin_token: AccountId,
+    },
+    KeeperConfig {
+        reward_per_round: U128,
+        keeper_bounty_bps: u32,
+    },
+    TimelockDuration {
+        duration: u64,
+    },
+    SetBonus {
+        set_bonus_bps: u32,
+    },
+    EarlyUnstakePenalty {
+        penalty_bps: u32,
+        fee_bps: u32,
+    },
+    LockupTier {
+        tier: LockupTier,
+    },
+    RemoveLockupTier {
+        lockup_period: u64,
+    },
+    FlexibleWeight {
+        weight_bps: u32,
+    },
+    PositionManager {
+        position_manager: Option<AccountId>,
+    },
+    EmissionRate {
+        rate_per_second: U128,
+    },
+    TypePoolShare {
+        nft_type: String,
+        share_bps: u32,
+    },
+    ClaimFee {
+        fee_bps: u32,
+        treasury: Option<AccountId>,
+    },
+    CompoundBoost {
+        unit: U128,
+        boost_bps: u32,
+        max_boost_bps: u32,
+    },
+    RewardVesting {
+        vesting_period: u64,
+    },
+    RewardExpiry {
+        expiry_period: u64,
+    },
+    MaxAccountShare {
+        max_share_bps: u32,
+    },
+    MinStakeAge {
+        min_stake_age: u64,
+    },
+    ScarcityWeights {
+        enabled: bool,
+    },
+    WithdrawUnallocated {
+        amount: U128,
+        receiver_id: AccountId,
+    },
+    HalvingInterval {
+        halving_interval: u64,
+    },
+    DistributionInterval {
+        interval: u64,
+    },
+    TraitRule {
+        rule: TraitRule,
+    },
+    RemoveTraitRule {
+        trait_type: String,
+        value: String,
+    },
+    DefaultNftType {
+        nft_type: String,
+    },
+    MaxNftsPerAccount {
+        max_nfts: Option<u32>,
+    },
+    StakingConsumer {
+        consumer_id: AccountId,
+    },
+    ClaimCooldown {
+        cooldown: u64,
+    },
+    MinClaimAmount {
+        min_claim_amount: U128,
+    },
+    MaxRewardPool {
+        max_reward_pool: Option<U128>,
+    },
+    CommunityFunding {
+        enabled: bool,
+    },
+    RarityOracle {
+        rarity_oracle: Option<RarityOracleConfig>,
+    },
+    Collection {
+        nft_contract: AccountId,
+        nft_weights: HashMap<String, u32>,
+        default_nft_type: String,
+    },
+    CollectionWeight {
+        nft_contract: AccountId,
+        nft_type: String,
+        weight: u32,
+    },
+    CollectionTraitRule {
+        nft_contract: AccountId,
+        rule: TraitRule,
+    },
+    CollectionMultiplier {
+        nft_contract: AccountId,
+        multiplier_bps: u32,
+    },
+    TypeCap {
+        nft_type: String,
+        cap: Option<u64>,
+    },
+    Sunset {},
+}
 
-                let mut stake = staker_info.stakes.get(i as u64).unwrap();
-                stake.claimed_rewards += reward;
-                staker_info.stakes.replace(i as u64, &stake);
-            }
-            self.stakers.insert(&staker_id, &staker_info);
+impl ConfigChange {
+    pub fn parameter(&self) -> &'static str {
+        match self {
+            ConfigChange::NftWeight { .. } => "nft_weight",
+            ConfigChange::SinToken { .. } => "sin_token",
+            ConfigChange::KeeperConfig { .. } => "keeper_config",
+            ConfigChange::TimelockDuration { .. } => "timelock_duration",
+            ConfigChange::SetBonus { .. } => "set_bonus",
+            ConfigChange::EarlyUnstakePenalty { .. } => "early_unstake_penalty",
+            ConfigChange::LockupTier { .. } => "lockup_tier",
+            ConfigChange::RemoveLockupTier { .. } => "lockup_tier_removed",
+            ConfigChange::FlexibleWeight { .. } => "flexible_weight",
+            ConfigChange::PositionManager { .. } => "position_manager",
+            ConfigChange::EmissionRate { .. } => "emission_rate",
+            ConfigChange::TypePoolShare { .. } => "type_pool_share",
+            ConfigChange::ClaimFee { .. } => "claim_fee",
+            ConfigChange::CompoundBoost { .. } => "compound_boost",
+            ConfigChange::RewardVesting { .. } => "reward_vesting",
+            ConfigChange::RewardExpiry { .. } => "reward_expiry",
+            ConfigChange::MaxAccountShare { .. } => "max_account_share",
+            ConfigChange::MinStakeAge { .. } => "min_stake_age",
+            ConfigChange::ScarcityWeights { .. } => "scarcity_weights",
+            ConfigChange::WithdrawUnallocated { .. } => "withdraw_unallocated",
+            ConfigChange::HalvingInterval { .. } => "halving_interval",
+            ConfigChange::DistributionInterval { .. } => "distribution_interval",
+            ConfigChange::TraitRule { .. } => "trait_rule",
+            ConfigChange::RemoveTraitRule { .. } => "trait_rule_removed",
+            ConfigChange::DefaultNftType { .. } => "default_nft_type",
+            ConfigChange::MaxNftsPerAccount { .. } => "max_nfts_per_account",
+            ConfigChange::StakingConsumer { .. } => "staking_consumer",
+            ConfigChange::ClaimCooldown { .. } => "claim_cooldown",
+            ConfigChange::MinClaimAmount { .. } => "min_claim_amount",
+            ConfigChange::MaxRewardPool { .. } => "max_reward_pool",
+            ConfigChange::CommunityFunding { .. } => "community_funding",
+            ConfigChange::RarityOracle { .. } => "rarity_oracle",
+            ConfigChange::Collection { .. } => "collection",
+            ConfigChange::CollectionWeight { .. } => "collection_weight",
+            ConfigChange::CollectionTraitRule { .. } => "collection_trait_rule",
+            ConfigChange::CollectionMultiplier { .. } => "collection_multiplier",
+            ConfigChange::TypeCap { .. } => "type_cap",
+            ConfigChange::Sunset {} => "sunset",
         }
-        self.reward_distribution.total_reward_pool -= reward_pool;
-        self.last_distributed = env::block_timestamp();
     }
+}
 
-    pub fn claim_reward(&mut self, stake_index: u64) {
-        let staker_id = env::predecessor_account_id();
-        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
-
-        assert!(
-            stake_index < staker_info.stakes.len(),
-            "Invalid staking record index"
-        );
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingChange {
+    pub id: u64,
+    pub change: ConfigChange,
+    pub executable_at: U64, // Block timestamp (ns) after which the change can be executed
+}
 
-        let mut stake = staker_info.stakes.get(stake_index).unwrap();
-        let rewards_to_claim = stake.claimed_rewards;
+// Privileged roles. The owner holds every role; other accounts are granted roles explicitly.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Role {
+    Owner,       // Configuration, role management and ownership
+    Distributor, // Runs reward distributions and expiry sweeps
+    Treasurer,   // Funds the reward pool, manages campaign sponsors and archives funding records
+}
 
-        assert!(rewards_to_claim > 0, "No rewards available to claim");
+// Operations that can be paused individually
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Operation {
+    Staking,
+    Claims,
+    Unstaking,
+    Distribution,
+}
 
-        stake.claimed_rewards = 0;
-        staker_info.total_rewards_claimed += rewards_to_claim;
-        staker_info.stakes.replace(stake_index, &stake);
-        self.stakers.insert(&staker_id, &staker_info);
+// NEP-145 storage balance of a registered account
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct AccountStorage {
+    pub deposit: Balance,
+    pub used_bytes: StorageUsage, // Bytes of contract storage attributed to the account
+}
 
-        Promise::new(self.sin_token.clone()).function_call(
-            "ft_transfer".to_string(),
-            serde_json::to_vec(&json!({
-                "receiver_id": staker_id,
-                "amount": U128(rewards_to_claim),
-            }))
-            .unwrap(),
-            NearToken::from_yoctonear(1), // Attach 1 yoctoNEAR
-            Gas::from_tgas(50),
-        );
+impl AccountStorage {
+    pub fn available(&self) -> Balance {
+        self.deposit.saturating_sub(
+            env::storage_byte_cost().as_yoctonear() * self.used_bytes as Balance,
+        )
     }
+}
 
-    pub fn unstake_nfts(&mut self, stake_index: u64) {
-        let staker_id = env::predecessor_account_id();
-        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
-
-        assert!(
-            stake_index < staker_info.stakes.len(),
-            "Invalid staking record index"
-        );
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FundingRecord {
+    pub amount: Balance,
+    pub timestamp: u64,
+    pub sender_id: AccountId, // Treasurer or, with community funding, any donor
+    pub memo: Option<String>, // Label given by the sender, e.g. "March emissions"
+}
 
-        let stake = staker_info.stakes.get(stake_index).unwrap();
-        let current_time = env::block_timestamp();
-        assert!(
-            current_time >= stake.start_timestamp + stake.lockup_period * 1_000_000_000,
-            "Cannot unstake before lockup period"
-        );
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FundingView {
+    pub amount: U128,
+    pub timestamp: U64,
+    pub sender_id: AccountId,
+    pub memo: Option<String>,
+}
 
-        let nft_ids = stake.nft_ids.clone();
-        let nft_id = nft_ids.get(0);
-        staker_info.stakes.swap_remove(stake_index);
-        self.stakers.insert(&staker_id, &staker_info);
+// SIN funding of one month, see `get_funding_summary` and `archive_funding_records`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FundingPeriod {
+    pub period_start: U64, // Start of the 30-day period, in ns
+    pub count: u64,
+    pub total: U128,
+}
 
-        Promise::new(self.sin_nft_contract.clone()).function_call(
-            "nft_transfer".to_string(),
-            serde_json::to_vec(&json!({
-                "receiver_id": staker_id,
-                "token_id": nft_id
-            }))
-            .unwrap(),
-            NearToken::from_yoctonear(1), // Attach 1 yoctoNEAR
-            Gas::from_tgas(50),
-        );
-    }
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FundingSummary {
+    pub total_funded: U128, // Lifetime SIN funding, archived records included
+    pub total_distributed: U128,
+    pub period_count: u64, // Months with funding
+    pub monthly: Vec<FundingPeriod>, // Oldest first, months without funding are left out
+}
 
-    pub fn get_staking_info(&self, staker_id: AccountId) -> Vec<serde_json::Value> {
-        if let Some(staker_info) = self.stakers.get(&staker_id) {
-            staker_info
-                .stakes
-                .iter()
-                .map(|stake| {
-                    // Aggregate NFT type counts
-                    let mut queen_count = 0;
-                    let mut worker_count = 0;
-                    let mut drone_count = 0;
-    
-                    for (_, nft_type) in stake.nft_types.iter() {
-                        match nft_type.as_str() {
-                            "Queen" => queen_count += 1,
-                            "Worker" => worker_count += 1,
-                            "Drone" => drone_count += 1,
-                            _ => (),
-                        }
-                    }
-    
-                    // Return the summarized data
-                    json!({
-                        "nft_ids": stake.nft_ids,
-                        "queen": queen_count,
-                        "worker": worker_count,
-                        "drone": drone_count,
-                        "start_timestamp": stake.start_timestamp,
-                        "lockup_period": stake.lockup_period,
-                        "claimed_rewards": stake.claimed_rewards
-                    })
-                })
-                .collect()
-        } else {
-            vec![] // Return empty if no staking info is found
-        }
-    }
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FundingDetails {
+    pub total_count: u64,
+    pub records: Vec<FundingView>,
+}
 
-    pub fn get_last_reward_distribution(&self) -> u64 {
-        self.last_distributed
-    }
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum DistributionPhase {
+    Weighing, // Summing the total staking weight
+    Snapshot, // Weighed ahead of time, waiting for the round amount
+    Paying,   // Crediting every stake with its share
+}
 
-    pub fn get_next_reward_distribution(&self) -> u64 {
-        let now = env::block_timestamp();
-        let next_distribution = self.last_distributed + MONTH;
-        if next_distribution > now {
-            (next_distribution - now) / DAY
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DistributionRound {
+    pub amount: Balance,
+    pub phase: DistributionPhase,
+    pub cursor: u64, // Index of the next staker to process
+    pub total_weight: u128,
+    pub started_at: u64,
+    pub staker_count: u64, // Stakers with a non-zero weight in this round
+    pub type_shares: HashMap<String, u32>, // Per-type pool shares in effect when the round started
+    pub type_weights: HashMap<String, u128>, // Weight of the NFTs of each earmarked type
+    pub type_amounts: HashMap<String, Balance>, // Earmarked amounts, set once weighing completes
+    pub max_share_bps: u32, // Largest share of the round a single account receives, 0 for no cap
+    // Weighed accounts that may be above the cap, with their total and earmarked type weights
+    pub share_candidates: HashMap<AccountId, (u128, HashMap<String, u128>)>,
+    pub capped_rewards: HashMap<AccountId, Balance>, // Uncapped rewards of the capped accounts
+    pub capped_excess: Balance, // Capped rewards nobody was left to receive
+    pub distributed: Balance, // Credited to stakers so far
+    pub airdrop: bool, // Started with `distribute_now`, outside the regular round cadence
+}
+
+impl DistributionRound {
+    // Splits the round between the type pools. A type with nothing staked keeps no earmark, so its
+    // share is paid to everyone instead.
+    fn internal_split(&mut self) {
+        self.type_amounts = self
+            .type_shares
+            .iter()
+            .filter(|(nft_type, _)| {
+                self.type_weights
+                    .get(*nft_type)
+                    .is_some_and(|weight| *weight > 0)
+            })
+            .map(|(nft_type, share_bps)| {
+                (nft_type.clone(), mul_div(self.amount, *share_bps as u128, BPS_DENOMINATOR))
+            })
+            .collect();
+    }
+
+    // Share of a record with `weight`, of which `type_weights` comes from earmarked types
+    fn reward(&self, weight: u128, type_weights: &HashMap<String, u128>) -> Balance {
+        let general_amount = self.amount - self.type_amounts.values().sum::<u128>();
+        let mut reward = mul_div(general_amount, weight, self.total_weight);
+        for (nft_type, type_amount) in self.type_amounts.iter() {
+            if let Some(type_weight) = type_weights.get(nft_type) {
+                reward += mul_div(*type_amount, *type_weight, self.type_weights[nft_type]);
+            }
+        }
+        reward
+    }
+
+    fn max_share(&self) -> Balance {
+        mul_div(self.amount, self.max_share_bps as u128, BPS_DENOMINATOR)
+    }
+
+    // Scales an account's rewards down to the cap if it is capped, or up by its part of the
+    // excess of the capped accounts otherwise. Whatever the scaling, the account's total reward
+    // never exceeds the cap; anything clamped off is carried into the next round.
+    fn cap_rewards(&self, account_id: &AccountId, rewards: Vec<Balance>) -> Vec<Balance> {
+        if self.max_share_bps == 0 {
+            return rewards;
+        }
+        let (numerator, denominator) = match self.capped_rewards.get(account_id) {
+            Some(uncapped) => (self.max_share(), *uncapped),
+            None if self.capped_rewards.is_empty() => (1, 1),
+            None => (
+                self.amount - self.capped_rewards.len() as u128 * self.max_share(),
+                self.amount - self.capped_rewards.values().sum::<u128>(),
+            ),
+        };
+        if denominator == 0 {
+            return rewards;
+        }
+        let rewards: Vec<Balance> = rewards
+            .into_iter()
+            .map(|reward| mul_div(reward, numerator, denominator))
+            .collect();
+        let total = rewards.iter().sum::<u128>();
+        if total <= self.max_share() {
+            return rewards;
+        }
+        rewards
+            .into_iter()
+            .map(|reward| mul_div(reward, self.max_share(), total))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DistributionRoundView {
+    pub amount: U128,
+    pub phase: DistributionPhase,
+    pub cursor: u64,
+    pub total_weight: U128,
+    pub started_at: U64,
+    pub staker_count: u64,
+    pub type_amounts: HashMap<String, U128>,
+    pub capped_accounts: Vec<AccountId>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ScheduledDistribution {
+    pub id: u64,
+    pub amount: U128,
+    pub execute_after: U64, // Block timestamp (ns) from which anyone can execute it
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DistributionRecord {
+    pub amount: U128,
+    pub timestamp: U64,
+    pub total_weight: U128,
+    pub staker_count: u64,
+    pub remainder: U128, // Rounding dust carried into the next round
+    pub airdrop: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractConfig {
+    pub owner: AccountId,
+    pub sin_token: AccountId,
+    pub sin_nft_contract: AccountId,
+    pub distribution_interval: u64, // Seconds between rounds
+    pub reward_per_round: U128,
+    pub keeper_bounty_bps: u32,
+    pub timelock_duration: u64, // Seconds
+    pub set_bonus_bps: u32,
+    pub early_unstake_penalty_bps: u32,
+    pub early_unstake_fee_bps: u32,
+    pub flexible_weight_bps: u32,
+    pub position_manager: Option<AccountId>,
+    pub rarity_oracle: Option<RarityOracleConfig>,
+    pub max_nfts_per_account: Option<u32>,
+    pub max_new_stakes_per_day: Option<u32>,
+    pub emission_rate_per_second: U128,
+    pub emission_halving_interval: u64,
+    pub type_pool_shares: HashMap<String, u32>,
+    pub type_caps: HashMap<String, u64>,
+    pub claim_fee_bps: u32,
+    pub treasury: Option<AccountId>,
+    pub compound_unit: U128,
+    pub compound_boost_bps: u32,
+    pub max_compound_boost_bps: u32,
+    pub reward_vesting_period: u64, // Seconds
+    pub claim_cooldown: u64, // Seconds
+    pub min_claim_amount: U128,
+    pub reward_expiry: u64, // Seconds
+    pub max_account_share_bps: u32,
+    pub min_stake_age: u64, // Seconds
+    pub scarcity_weights: bool,
+    pub max_reward_pool: Option<U128>,
+    pub community_funding: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeView {
+    pub stake_index: u64,
+    pub position_id: u64,
+    pub nft_ids: Vec<String>,
+    pub queen: u32,
+    pub worker: u32,
+    pub drone: u32,
+    pub start_timestamp: U64,
+    pub lockup_period: u64, // Seconds
+    pub unlock_timestamp: U64,
+    pub auto_renew: bool,
+    pub tokenized: bool,
+    pub claimed_rewards: U128, // Pending rewards of the record
+    pub vesting_rewards: U128, // Part of the pending rewards that is not claimable yet
+    pub claim_by: Option<U64>, // Rewards not claimed by then can be swept back into the pool
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NftStakerView {
+    pub account_id: AccountId,
+    pub stake_index: u64,
+    pub position_id: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakerSummary {
+    pub account_id: AccountId,
+    pub stake_count: u64,
+    pub nft_count: u64,
+    pub total_weight: U128, // Current weight, in basis points of the NFT type weights
+    pub pending_rewards: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolAccounting {
+    pub unallocated_pool: U128, // Free to distribute, stream or withdraw
+    pub reserved_for_rounds: U128, // Unpaid part of the active round and the carried remainder
+    pub pending_claims_total: U128, // Owed to stakers: credited or streamed, not paid out yet
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Reconciliation {
+    pub timestamp: U64,
+    pub balance: U128, // SIN held by the contract according to the token
+    pub accounted: U128, // SIN the contract's accounting says it holds
+    pub surplus: U128, // Held but not accounted for, e.g. sent without ft_transfer_call
+    pub deficit: U128, // Accounted for but missing from the balance
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InvariantReport {
+    pub healthy: bool,
+    pub staker_rewards: U128, // Of the page, on records, unclaimed or streamed and not settled yet
+    pub pending_claims_total: U128, // Tracked liability to stakers, streamed rewards included
+    pub accounted: U128, // SIN the contract's accounting says it holds now
+    pub last_reconciliation: Option<Reconciliation>, // Balance check the report is based on
+    pub staked_nfts: u64, // In the page
+    pub violations: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ContractStats {
+    pub total_stakers: u64, // Accounts in the staker map
+    pub total_nfts_staked: u64,
+    pub nfts_per_type: HashMap<String, u64>,
+    pub total_weight: U128, // Across the requested page of stakers
+    pub reward_pool: U128,
+    pub total_rewards_distributed: U128,
+    pub carried_remainder: U128, // Added to the next round
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardEstimate {
+    pub stake_rewards: Vec<U128>, // Estimated share of each record, by stake index
+    pub total: U128,
+    pub pending_rewards: U128, // Already credited and claimable
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakerPayout {
+    pub account_id: AccountId,
+    pub weight: U128,
+    pub payout: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DistributionPreview {
+    pub amount: U128,
+    pub total_weight: U128,
+    pub distributed: U128, // Paid to the stakers of this page
+    pub payouts: Vec<StakerPayout>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct YearlyRewardEstimate {
+    pub yearly_rewards: U128, // Projected SIN distributed over a year at the recent pace
+    pub total_weight: U128,
+    pub yearly_rewards_per_nft: HashMap<String, U128>, // By NFT type, for a default one-month lockup
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LeaderboardEntry {
+    pub account_id: AccountId,
+    pub value: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Leaderboard {
+    pub by_weight: Vec<LeaderboardEntry>,
+    pub by_rewards_claimed: Vec<LeaderboardEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NextDistribution {
+    pub timestamp: U64, // Block timestamp (ns) from which the next round can run
+    pub seconds_remaining: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CampaignView {
+    pub id: u64,
+    pub sponsor: AccountId,
+    pub token_id: AccountId,
+    pub total_reward: U128,
+    pub start: U64,
+    pub end: U64,
+    pub claimed: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CampaignRewardView {
+    pub campaign_id: u64,
+    pub token_id: AccountId,
+    pub amount: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CompoundingView {
+    pub compounding: bool,
+    pub compounded_rewards: U128,
+    pub boost_bps: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardTokenView {
+    pub token_id: AccountId,
+    pub reward_pool: U128,
+    pub total_distributed: U128,
+    pub last_distributed: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenRewardView {
+    pub token_id: AccountId,
+    pub amount: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EmissionSchedule {
+    pub initial_rate_per_second: U128,
+    pub current_rate_per_second: U128,
+    pub halving_interval: u64,
+    pub schedule_start: U64,
+    pub next_halving: Option<U64>, // None when the rate is constant or fully decayed
+}
+
+// NEP-297 events, logged as `EVENT_JSON:{"standard":..,"version":..,"event":..,"data":..}`
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum StakingEvent {
+    Stake {
+        account_id: AccountId,
+        token_id: String,
+        nft_type: String,
+    },
+    Unstake {
+        account_id: AccountId,
+        token_ids: Vec<String>,
+        rewards: U128,
+        penalty: U128,
+    },
+    StakeRestored {
+        account_id: AccountId,
+        token_ids: Vec<String>,
+    },
+    EmergencyReturn {
+        account_id: AccountId,
+        token_ids: Vec<String>,
+        rewards: U128,
+    },
+    LockupExtended {
+        account_id: AccountId,
+        token_ids: Vec<String>,
+        lockup_period: u64,
+        unlock_timestamp: U64,
+    },
+    StakesMerged {
+        account_id: AccountId,
+        token_ids: Vec<String>,
+        unlock_timestamp: U64,
+    },
+    StakeSplit {
+        account_id: AccountId,
+        token_ids: Vec<String>,
+    },
+    StakeTransferred {
+        account_id: AccountId,
+        receiver_id: AccountId,
+        token_ids: Vec<String>,
+    },
+    PositionTokenized {
+        account_id: AccountId,
+        position_id: u64,
+    },
+    PositionReleased {
+        account_id: AccountId,
+        position_id: u64,
+    },
+    OperatorApproved {
+        account_id: AccountId,
+        operator_id: AccountId,
+    },
+    OperatorRevoked {
+        account_id: AccountId,
+        operator_id: AccountId,
+    },
+    StakeSenderApproved {
+        account_id: AccountId,
+        sender_id: AccountId,
+    },
+    StakeSenderRevoked {
+        account_id: AccountId,
+        sender_id: AccountId,
+    },
+    AutoRenewUpdated {
+        account_id: AccountId,
+        token_ids: Vec<String>,
+        auto_renew: bool,
+    },
+    DistributionScheduled {
+        id: u64,
+        amount: U128,
+        execute_after: U64,
+    },
+    ScheduledDistributionCancelled {
+        id: u64,
+    },
+    DistributionStarted {
+        amount: U128,
+    },
+    SnapshotTaken {
+        timestamp: U64,
+        total_weight: U128,
+        staker_count: u64,
+    },
+    SnapshotCancelled {
+        timestamp: U64,
+    },
+    BalanceDiscrepancy {
+        balance: U128,
+        accounted: U128,
+    },
+    TokensRescued {
+        token_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    },
+    NftReturned {
+        nft_contract: AccountId,
+        token_id: String,
+        receiver_id: AccountId,
+    },
+    NftRecovered {
+        account_id: AccountId,
+        token_id: String,
+        escrow_id: AccountId,
+    },
+    NearWithdrawal {
+        receiver_id: AccountId,
+        amount: U128,
+    },
+    DistributionCompleted {
+        amount: U128,
+        total_weight: U128,
+    },
+    KeeperBounty {
+        account_id: AccountId,
+        amount: U128,
+    },
+    Claim {
+        account_id: AccountId,
+        amount: U128,
+    },
+    ClaimFee {
+        account_id: AccountId,
+        treasury: AccountId,
+        amount: U128,
+    },
+    CompoundingUpdated {
+        account_id: AccountId,
+        compounding: bool,
+    },
+    RewardsCompounded {
+        account_id: AccountId,
+        amount: U128,
+        boost_bps: u32,
+    },
+    RewardsExpired {
+        account_id: AccountId,
+        stake_index: u64,
+        amount: U128,
+    },
+    ClaimRestored {
+        account_id: AccountId,
+        amount: U128,
+    },
+    Fund {
+        sender_id: AccountId,
+        amount: U128,
+    },
+    TokenFund {
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    },
+    TokenDistribution {
+        token_id: AccountId,
+        amount: U128,
+        total_weight: U128,
+    },
+    TokenClaim {
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    },
+    TokenClaimRestored {
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    },
+    NearFund {
+        sender_id: AccountId,
+        amount: U128,
+    },
+    NearDistribution {
+        amount: U128,
+        total_weight: U128,
+    },
+    NearClaim {
+        account_id: AccountId,
+        amount: U128,
+    },
+    NearClaimRestored {
+        account_id: AccountId,
+        amount: U128,
+    },
+    CampaignCreated {
+        id: u64,
+        sponsor: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        start: U64,
+        end: U64,
+    },
+    CampaignToppedUp {
+        id: u64,
+        amount: U128,
+    },
+    CampaignClaim {
+        account_id: AccountId,
+        campaign_id: u64,
+        amount: U128,
+    },
+    CampaignClosed {
+        id: u64,
+        refund: U128,
+    },
+    ConfigUpdate {
+        parameter: String,
+        value: Value,
+    },
+    ConfigChangeQueued {
+        id: u64,
+        change: ConfigChange,
+        executable_at: U64,
+    },
+    ConfigChangeCancelled {
+        id: u64,
+    },
+}
+
+impl StakingEvent {
+    pub fn emit(&self) {
+        let mut event = json!({
+            "standard": EVENT_STANDARD,
+            "version": EVENT_VERSION,
+        });
+        if let (Some(event), Value::Object(fields)) = (event.as_object_mut(), json!(self)) {
+            event.extend(fields);
+        }
+        env::log_str(&format!("EVENT_JSON:{}", event));
+    }
+}
+
+// Continuous emission of the reward pool. Rewards accrue per unit of weight and are settled into
+// each record lazily, whenever its staker is checkpointed.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Emission {
+    pub rate_per_second: Balance, // Rate at `schedule_start`; 0 disables streaming
+    pub acc_reward_per_weight: u128, // Scaled by EMISSION_PRECISION
+    pub acc_remainder: u128, // Scaled emission truncated from the accumulator, carried forward
+    pub last_update: u64,
+    pub total_weight: u128,
+    pub halving_interval: u64, // Seconds between halvings of the rate, 0 for a constant rate
+    pub schedule_start: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct RewardDistribution {
+    pub total_reward_pool: Balance,
+    pub last_distributed: u64, // Timestamp of last reward distribution
+    pub funding_records: FundingLog, // Track funding history
+}
+
+// Funding records in funding order. Records are kept by index, so the oldest can be archived
+// without shifting the rest.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FundingLog {
+    pub first: u64, // Index of the oldest record not archived yet
+    pub next: u64, // Index of the next record
+    pub records: LookupMap<u64, FundingRecord>,
+}
+
+impl FundingLog {
+    fn new(prefix: Vec<u8>) -> Self {
+        Self {
+            first: 0,
+            next: 0,
+            records: LookupMap::new(prefix),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.next - self.first
+    }
+
+    fn push(&mut self, record: &FundingRecord) {
+        self.records.insert(&self.next, record);
+        self.next += 1;
+    }
+
+    // The record at `index`, counted from the oldest one not archived
+    fn get(&self, index: u64) -> Option<FundingRecord> {
+        if index >= self.len() {
+            return None;
+        }
+        self.records.get(&(self.first + index))
+    }
+
+    fn pop_front(&mut self) -> Option<FundingRecord> {
+        if self.first == self.next {
+            return None;
+        }
+        self.first += 1;
+        self.records.remove(&(self.first - 1))
+    }
+}
+
+// Pool of a reward token other than SIN. Distributions are credited pro rata to the stakers'
+// emission weights through an accumulator, settled when a staker is checkpointed.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct TokenRewards {
+    pub distribution: RewardDistribution,
+    pub acc_reward_per_weight: u128, // Scaled by EMISSION_PRECISION
+    pub acc_remainder: u128, // Scaled distribution truncated from the accumulator, carried forward
+    pub total_distributed: Balance,
+}
+
+impl TokenRewards {
+    // Spreads `amount` over `total_weight`. What the accumulator can't represent is carried into
+    // the next distribution rather than dropped.
+    fn accrue(&mut self, amount: Balance, total_weight: u128) {
+        let scaled = U256::from(amount) * U256::from(EMISSION_PRECISION)
+            + U256::from(self.acc_remainder);
+        let total_weight = U256::from(total_weight);
+        self.acc_reward_per_weight += (scaled / total_weight).as_u128();
+        self.acc_remainder = (scaled % total_weight).as_u128();
+    }
+}
+
+// Time-boxed bonus farm: a sponsor's token deposit streams evenly to the stakers between `start`
+// and `end`, pro rata to their emission weights
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Campaign {
+    pub id: u64,
+    pub sponsor: AccountId,
+    pub token_id: AccountId,
+    pub total_reward: Balance,
+    pub start: u64,
+    pub end: u64,
+    pub acc_reward_per_weight: u128, // Scaled by EMISSION_PRECISION
+    pub last_update: u64,
+    pub claimed: Balance,
+    pub streamed: Balance, // Streamed up to `last_update`; the rest streams evenly until `end`
+}
+
+// Partner approved to open bonus farms in one token. Binding the approval to the token keeps
+// anyone else's token contract from opening farms in the sponsor's name.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CampaignSponsor {
+    pub account_id: AccountId,
+    pub token_id: AccountId,
+}
+
+// `msg` of `ft_transfer_call` that opens a bonus farm with the transferred tokens
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CampaignMsg {
+    pub duration: u64, // Seconds
+    pub start: Option<U64>, // Defaults to now
+}
+
+// Intent of a token transfer, parsed from the `msg` of `ft_transfer_call`. Funding intents can
+// be followed by a memo, e.g. "fund:March emissions". Any other text funds the pool and is kept
+// as the memo.
+pub enum FundingMsg {
+    Fund, // "fund", an empty msg or free-form text
+    FundAndDistribute, // "fund_and_distribute": funds the SIN pool and starts a round of the amount
+    DistributeNow, // "distribute_now": airdrops the amount to the current stakers
+    Campaign(CampaignMsg), // A `CampaignMsg` JSON object
+    TopUpCampaign(u64), // "campaign:<id>": adds the amount to a running campaign of the sponsor
+}
+
+impl FundingMsg {
+    // Returns the intent and its memo, if any
+    pub fn parse(msg: &str) -> (Self, Option<String>) {
+        if msg.trim_start().starts_with('{') {
+            let campaign = serde_json::from_str(msg).expect("Invalid campaign msg");
+            return (FundingMsg::Campaign(campaign), None);
+        }
+        let (intent, memo) = match msg.split_once(':') {
+            Some((intent, memo)) => (intent, Some(memo)),
+            None => (msg, None),
+        };
+        let funding_msg = match intent {
+            "" | "fund" => FundingMsg::Fund,
+            "fund_and_distribute" => FundingMsg::FundAndDistribute,
+            "distribute_now" => FundingMsg::DistributeNow,
+            "campaign" => {
+                let id = memo.and_then(|id| id.trim().parse().ok());
+                return (FundingMsg::TopUpCampaign(id.expect("Invalid campaign id")), None);
+            }
+            _ => return (FundingMsg::Fund, Some(Self::truncate_memo(msg))),
+        };
+        (funding_msg, memo.map(Self::truncate_memo))
+    }
+
+    // Memos longer than a record holds are cut at a character boundary
+    fn truncate_memo(memo: &str) -> String {
+        let mut end = memo.len().min(MAX_MEMO_LENGTH);
+        while !memo.is_char_boundary(end) {
+            end -= 1;
+        }
+        memo[..end].to_string()
+    }
+}
+
+// State layout of the first deployed version, read once by `migrate`
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct NFTStakingRecordV1 {
+    pub nft_ids: Vec<String>,
+    pub nft_types: HashMap<String, String>,
+    pub start_timestamp: u64,
+    pub lockup_period: u64,
+    pub claimed_rewards: u128, // Credited and not claimed yet
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StakerInfoV1 {
+    pub stakes: Vector<NFTStakingRecordV1>,
+    pub total_rewards_claimed: u128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct FundingRecordV1 {
+    pub amount: Balance,
+    pub timestamp: u64,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct RewardDistributionV1 {
+    pub total_reward_pool: Balance,
+    pub last_distributed: u64,
+    pub funding_records: Vector<FundingRecordV1>,
+}
+
+// Progress of `migrate_state` through the first version's records
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StateMigration {
+    pub stakers: UnorderedMap<AccountId, StakerInfoV1>, // Stakers not converted yet
+    pub funding_records: Vector<FundingRecordV1>,
+    pub funding_cursor: u64, // Index of the next funding record to convert
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct NFTStakingContractV1 {
+    pub owner: AccountId,
+    pub sin_token: AccountId,
+    pub sin_nft_contract: AccountId,
+    pub stakers: UnorderedMap<AccountId, StakerInfoV1>,
+    pub reward_pool: u128,
+    pub last_distributed: u64,
+    pub reward_distribution: RewardDistributionV1,
+    pub nft_weights: HashMap<String, u32>,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct NFTStakingContract {
+    pub owner: AccountId,
+    sin_token: AccountId,
+    sin_nft_contract: AccountId,
+    pub stakers: UnorderedMap<AccountId, StakerInfo>,
+    pub reward_pool: u128,
+    pub last_distributed: u64,
+    pub reward_distribution: RewardDistribution,
+    pub nft_weights: HashMap<String, u32>, // Map for NFT type -> Weight
+    pub active_distribution: Option<DistributionRound>, // Round being processed in chunks
+    pub distribution_interval: u64, // Seconds that must pass before a keeper can trigger a round
+    pub reward_per_round: Balance, // Amount distributed by keeper-triggered rounds
+    pub keeper_bounty_bps: u32, // Share of a keeper-triggered round paid to the caller
+    pub storage_accounts: LookupMap<AccountId, AccountStorage>, // NEP-145 registrations
+    pub trait_rules: Vec<TraitRule>, // Classification rules for NFT types
+    pub default_nft_type: String, // Type of NFTs matching no trait rule
+    pub rarity_score: Option<RarityScoreConfig>, // Weighs newly staked NFTs by their rarity score
+    pub trait_points: Vec<TraitPoints>, // Weighs newly staked NFTs by the points of their traits
+    pub collections: HashMap<AccountId, NftCollection>, // Stakeable collections besides SIN's
+    pub rarity_oracle: Option<RarityOracleConfig>, // Contract scoring NFTs at staking time
+    pub paused: bool, // Freezes staking, claims, unstaking and distribution
+    pub paused_operations: Vec<Operation>, // Operations paused individually
+    pub emergency_shutdown: bool, // Staking and rounds are disabled for good and NFTs returned
+    pub sunset: bool, // No new stakes or funding, and records can be unstaked before unlocking
+    pub blacklist: UnorderedSet<AccountId>, // Accounts barred from staking and claiming
+    pub whitelist_only: bool, // Only whitelisted accounts can stake
+    pub whitelist: UnorderedSet<AccountId>,
+    pub token_blacklist: UnorderedSet<String>, // Flagged token IDs, refused and weighted 0
+    pub pending_owner: Option<AccountId>, // Proposed owner that still has to accept
+    pub roles: UnorderedMap<AccountId, Vec<Role>>, // Delegated roles; the owner implicitly holds all
+    pub timelock_duration: u64, // Seconds between queuing and applying a sensitive change
+    pub pending_changes: UnorderedMap<u64, PendingChange>,
+    pub next_change_id: u64,
+    pub set_bonus_bps: u32, // Weight bonus for stakers holding a full hive set
+    pub early_unstake_penalty_bps: u32, // Share of pending rewards forfeited on early unstake
+    pub early_unstake_fee_bps: u32, // Share of unclaimed account rewards charged on early unstake
+    pub lockup_tiers: Vec<LockupTier>, // Per-lockup decaying early exit penalties
+    pub flexible_weight_bps: u32, // Weight multiplier for flexible records, which have no lockup
+    pub position_manager: Option<AccountId>, // Companion NEP-171 contract issuing position receipts
+    pub next_position_id: u64,
+    pub operators: LookupMap<AccountId, Vec<AccountId>>, // Accounts allowed to manage a staker's records
+    pub stake_senders: LookupMap<AccountId, Vec<AccountId>>, // Accounts allowed to stake for one
+    pub max_nfts_per_account: Option<u32>, // Cap on the NFTs a single account can have staked
+    pub max_new_stakes_per_day: Option<u32>, // Records an account can create per 24h window
+    pub staked_tokens: LookupMap<String, AccountId>, // Token ID -> account that has it staked
+    pub staked_type_counts: HashMap<String, u64>, // NFTs of each type staked contract-wide
+    pub type_caps: HashMap<String, u64>, // Most NFTs of a type that can be staked at once
+    pub staking_consumers: Vec<AccountId>, // Contracts allowed to call `is_token_staked`
+    pub total_rewards_distributed: Balance,
+    pub carried_remainder: Balance, // Rounding dust of the last round, added to the next one
+    pub sin_in_flight: Balance, // SIN sent by transfers whose callback has not run yet
+    pub pending_claims_total: Balance, // SIN credited or streamed to stakers, not paid out yet
+    pub last_reconciliation: Option<Reconciliation>,
+    pub distribution_history: Vector<DistributionRecord>, // One entry per completed round
+    pub funding_archive: Vector<FundingPeriod>, // Archived SIN funding, oldest period first
+    pub total_funded: Balance, // Lifetime SIN funding, archived records included
+    pub funding_periods: Vector<FundingPeriod>, // Lifetime SIN funding per month, oldest first
+    pub scheduled_distributions: UnorderedMap<u64, ScheduledDistribution>,
+    pub next_schedule_id: u64,
+    pub reward_tokens: UnorderedMap<AccountId, TokenRewards>,
+    pub near_rewards: TokenRewards, // Native NEAR pool, distributed like the other reward tokens
+    pub near_rewards_claimed: Balance, // NEAR paid out of `near_rewards` to stakers
+    pub total_storage_deposits: Balance, // NEP-145 deposits held for registered accounts
+    pub campaign_sponsors: Vec<CampaignSponsor>, // Partners allowed to open bonus farms
+    pub campaigns: UnorderedMap<u64, Campaign>,
+    pub next_campaign_id: u64,
+    pub type_pool_shares: HashMap<String, u32>, // Share of each round earmarked for an NFT type
+    pub claim_fee_bps: u32, // Share of every SIN claim sent to the treasury
+    pub treasury: Option<AccountId>,
+    pub compound_unit: Balance, // Compounded SIN earning `compound_boost_bps`, 0 disables it
+    pub compound_boost_bps: u32,
+    pub max_compound_boost_bps: u32,
+    pub reward_vesting_period: u64, // Seconds over which round rewards unlock, 0 for none
+    pub claim_cooldown: u64, // Minimum seconds between two claims of an account
+    pub min_claim_amount: Balance, // Smallest SIN claim worth a transfer
+    pub reward_expiry: u64, // Seconds before unclaimed record rewards can be swept, 0 for never
+    pub max_account_share_bps: u32, // Largest share of a round one account receives, 0 for no cap
+    pub min_stake_age: u64, // Seconds a record must be staked before a round to qualify for it
+    pub scarcity_weights: bool, // Type weights scale with how underrepresented the type is
+    pub max_reward_pool: Option<Balance>, // Funding beyond it is refunded to the sender
+    pub community_funding: bool, // Lets any account donate SIN to the reward pool
+    pub emission: Emission,
+    pub migration: Option<StateMigration>, // First-version state still being converted
+    // Records of failed unstake transfers, held back from the round in progress
+    pub pending_restores: Vector<(AccountId, NFTStakingRecord)>,
+}
+
+#[near_bindgen]
+impl NFTStakingContract {
+    #[init]
+    pub fn new(owner: AccountId, sin_token: AccountId, sin_nft_contract: AccountId) -> Self {
+        let mut nft_weights = HashMap::new();
+        nft_weights.insert("Queen".to_string(), 50);
+        nft_weights.insert("Worker".to_string(), 30);
+        nft_weights.insert("Drone".to_string(), 20);
+
+        let trait_rules = vec![
+            TraitRule {
+                trait_type: "Body".to_string(),
+                value: "Queen".to_string(),
+                nft_type: "Queen".to_string(),
+                priority: 2, // Queen takes precedence over Worker
+            },
+            TraitRule {
+                trait_type: "Wings".to_string(),
+                value: "Diamond".to_string(),
+                nft_type: "Worker".to_string(),
+                priority: 1,
+            },
+        ];
+
+        Self {
+            owner,
+            sin_token,
+            sin_nft_contract,
+            stakers: UnorderedMap::new(b"s".to_vec()),
+            reward_pool: 0,
+            last_distributed: env::block_timestamp(),
+            reward_distribution: RewardDistribution {
+                total_reward_pool: 0,
+                last_distributed: env::block_timestamp(),
+                funding_records: FundingLog::new(b"fundings".to_vec()),
+            },
+            nft_weights,
+            active_distribution: None,
+            distribution_interval: MONTH,
+            reward_per_round: 0,
+            keeper_bounty_bps: 0,
+            storage_accounts: LookupMap::new(b"storage".to_vec()),
+            trait_rules,
+            default_nft_type: "Drone".to_string(),
+            rarity_score: None,
+            trait_points: vec![],
+            collections: HashMap::new(),
+            rarity_oracle: None,
+            paused: false,
+            paused_operations: vec![],
+            emergency_shutdown: false,
+            sunset: false,
+            blacklist: UnorderedSet::new(b"blacklist".to_vec()),
+            whitelist_only: false,
+            whitelist: UnorderedSet::new(b"whitelist".to_vec()),
+            token_blacklist: UnorderedSet::new(b"token_blacklist".to_vec()),
+            pending_owner: None,
+            roles: UnorderedMap::new(b"roles".to_vec()),
+            timelock_duration: DAY,
+            pending_changes: UnorderedMap::new(b"changes".to_vec()),
+            next_change_id: 0,
+            set_bonus_bps: 0,
+            early_unstake_penalty_bps: 2_500,
+            early_unstake_fee_bps: 0,
+            lockup_tiers: vec![],
+            flexible_weight_bps: 5_000,
+            position_manager: None,
+            next_position_id: 0,
+            operators: LookupMap::new(b"operators".to_vec()),
+            stake_senders: LookupMap::new(b"stake_senders".to_vec()),
+            max_nfts_per_account: None,
+            max_new_stakes_per_day: None,
+            staked_tokens: LookupMap::new(b"tokens".to_vec()),
+            staked_type_counts: HashMap::new(),
+            type_caps: HashMap::new(),
+            staking_consumers: vec![],
+            total_rewards_distributed: 0,
+            carried_remainder: 0,
+            sin_in_flight: 0,
+            pending_claims_total: 0,
+            last_reconciliation: None,
+            distribution_history: Vector::new(b"distributions".to_vec()),
+            funding_archive: Vector::new(b"archived_fundings".to_vec()),
+            total_funded: 0,
+            funding_periods: Vector::new(b"funding_periods".to_vec()),
+            scheduled_distributions: UnorderedMap::new(b"scheduled".to_vec()),
+            reward_tokens: UnorderedMap::new(b"reward_tokens".to_vec()),
+            near_rewards: TokenRewards {
+                distribution: RewardDistribution {
+                    total_reward_pool: 0,
+                    last_distributed: 0,
+                    funding_records: FundingLog::new(b"funding_near".to_vec()),
+                },
+                acc_reward_per_weight: 0,
+                acc_remainder: 0,
+                total_distributed: 0,
+            },
+            near_rewards_claimed: 0,
+            total_storage_deposits: 0,
+            campaign_sponsors: Vec::new(),
+            campaigns: UnorderedMap::new(b"campaigns".to_vec()),
+            next_campaign_id: 0,
+            type_pool_shares: HashMap::new(),
+            claim_fee_bps: 0,
+            treasury: None,
+            compound_unit: 0,
+            compound_boost_bps: 0,
+            max_compound_boost_bps: 0,
+            reward_vesting_period: 0,
+            claim_cooldown: 0,
+            min_claim_amount: 0,
+            reward_expiry: 0,
+            max_account_share_bps: 0,
+            min_stake_age: 0,
+            scarcity_weights: false,
+            max_reward_pool: None,
+            community_funding: false,
+            next_schedule_id: 0,
+            emission: Emission {
+                rate_per_second: 0,
+                acc_reward_per_weight: 0,
+                acc_remainder: 0,
+                last_update: env::block_timestamp(),
+                total_weight: 0,
+                halving_interval: 0,
+                schedule_start: env::block_timestamp(),
+            },
+            migration: None,
+            pending_restores: Vector::new(b"pending_restores".to_vec()),
+        }
+    }
+
+    // Upgrades the state of the first deployed version. Every setting added since takes its
+    // default from `new`. The records are converted in batches by `migrate_state`, with the
+    // contract paused until every staker is converted. No round can be in progress, since the
+    // first version paid rounds in one call.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: NFTStakingContractV1 = env::state_read().expect("No state to migrate");
+        let mut contract = Self::new(old.owner.clone(), old.sin_token, old.sin_nft_contract);
+        contract.reward_pool = old.reward_pool;
+        contract.last_distributed = old.last_distributed;
+        contract.nft_weights = old.nft_weights;
+        contract.reward_distribution.total_reward_pool = old.reward_distribution.total_reward_pool;
+        contract.reward_distribution.last_distributed = old.reward_distribution.last_distributed;
+        // Converted funding records keep their keys, which the log reads the same way
+        let funding_records = old.reward_distribution.funding_records;
+        contract.reward_distribution.funding_records.next = funding_records.len();
+        // The converted stakers can't share the old map's prefix while it is being emptied
+        contract.stakers = UnorderedMap::new(b"stakers".to_vec());
+        contract.paused = true;
+        contract.migration = Some(StateMigration {
+            stakers: old.stakers,
+            funding_records,
+            funding_cursor: 0,
+        });
+        contract
+    }
+
+    // Converts up to `limit` funding records and stakers of the first version, rebuilding the
+    // totals kept alongside them: the funding totals, the staked token index, the per-type
+    // counts, the liability to stakers and the emission weights. Returns true once everything is
+    // converted; the owner then unpauses the contract.
+    pub fn migrate_state(&mut self, limit: Option<u64>) -> bool {
+        self.assert_role(Role::Owner);
+        let mut migration = self.migration.take().expect("No state migration in progress");
+        let mut budget = limit.unwrap_or(50);
+
+        // Only the owner could fund the first version
+        let owner = self.owner.clone();
+        while budget > 0 && migration.funding_cursor < migration.funding_records.len() {
+            let index = migration.funding_cursor;
+            let record = migration.funding_records.get(index).unwrap();
+            let converted = FundingRecord {
+                amount: record.amount,
+                timestamp: record.timestamp,
+                sender_id: owner.clone(),
+                memo: None,
+            };
+            migration
+                .funding_records
+                .replace_raw(index, &borsh::to_vec(&converted).unwrap());
+            self.total_funded += record.amount;
+            add_to_funding_periods(&mut self.funding_periods, record.timestamp, record.amount);
+            migration.funding_cursor += 1;
+            budget -= 1;
+        }
+
+        let now = env::block_timestamp();
+        while budget > 0 && !migration.stakers.is_empty() {
+            // Taking the last staker removes it without shifting the others
+            let keys = migration.stakers.keys_as_vector();
+            let staker_id = keys.get(keys.len() - 1).unwrap();
+            let old_info = migration.stakers.remove(&staker_id).unwrap();
+            self.internal_migrate_staker(&staker_id, old_info, now);
+            budget -= 1;
+        }
+
+        let done = migration.funding_cursor == migration.funding_records.len()
+            && migration.stakers.is_empty();
+        if done {
+            env::log_str(&format!(
+                "Migrated {} stakers holding {} NFTs, {} SIN owed to them",
+                self.stakers.len(),
+                self.staked_type_counts.values().sum::<u64>(),
+                self.pending_claims_total
+            ));
+        } else {
+            self.migration = Some(migration);
+        }
+        done
+    }
+
+    // Rewrites a first-version staker in place under its old record prefix
+    fn internal_migrate_staker(&mut self, staker_id: &AccountId, old_info: StakerInfoV1, now: u64) {
+        let mut staker_info = StakerInfo::new(staker_id);
+        staker_info.total_rewards_claimed = old_info.total_rewards_claimed;
+        for old_stake in old_info.stakes.to_vec() {
+            for nft_id in old_stake.nft_ids.iter() {
+                self.staked_tokens.insert(nft_id, staker_id);
+                if let Some(nft_type) = old_stake.nft_types.get(nft_id) {
+                    self.internal_count_staked_type(nft_type, true);
+                }
+            }
+            self.pending_claims_total += old_stake.claimed_rewards;
+            staker_info.stakes.push(&NFTStakingRecord {
+                nft_ids: old_stake.nft_ids,
+                nft_types: old_stake.nft_types,
+                start_timestamp: old_stake.start_timestamp,
+                lockup_period: old_stake.lockup_period,
+                claimed_rewards: old_stake.claimed_rewards,
+                lockup_start: old_stake.start_timestamp,
+                auto_renew: false,
+                position_id: self.internal_next_position_id(),
+                tokenized: false,
+                emission_weight: 0,
+                emission_acc: 0,
+                vesting_amount: 0,
+                vesting_start: 0,
+                vesting_end: 0,
+                // The expiry window of rewards credited before the upgrade starts now
+                last_claim: now,
+                rarity_scores: HashMap::new(),
+                nft_contracts: HashMap::new(),
+                staked_at: HashMap::new(),
+            });
+        }
+        self.stakers.insert(staker_id, &staker_info);
+        // Sets the records' emission weights and adds them to the total
+        self.internal_checkpoint_staker(staker_id);
+    }
+
+    pub fn is_migrating(&self) -> bool {
+        self.migration.is_some()
+    }
+
+    #[payable]
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> U128 {
+        env::log_str(&format!("Received {} tokens from {}", amount.0, sender_id));
+        let token_id = env::predecessor_account_id();
+        assert!(self.migration.is_none(), "State migration is in progress");
+        assert!(!self.sunset, "Funding is closed during sunset");
+        let (funding_msg, memo) = FundingMsg::parse(&msg);
+        let funding_msg = match funding_msg {
+            FundingMsg::Campaign(campaign) => {
+                self.internal_create_campaign(sender_id, token_id, amount.0, campaign);
+                return U128(0);
+            }
+            FundingMsg::TopUpCampaign(campaign_id) => {
+                self.internal_top_up_campaign(sender_id, token_id, amount.0, campaign_id);
+                return U128(0);
+            }
+            funding_msg => funding_msg,
+        };
+        // With community funding on, anyone can donate SIN to the reward pool
+        assert!(
+            self.internal_has_role(&sender_id, Role::Treasurer)
+                || (self.community_funding && token_id == self.sin_token),
+            "Only treasurers are allowed to fund this reward pool"
+        );
+        if token_id != self.sin_token {
+            assert!(
+                self.reward_tokens.get(&token_id).is_some(),
+                "Only SIN and registered reward tokens are accepted for funding"
+            );
+            assert!(
+                matches!(funding_msg, FundingMsg::Fund),
+                "Only SIN funding can start a distribution"
+            );
+            assert!(amount.0 > 0, "Funding amount must be greater than zero");
+            self.internal_fund_token(&token_id, &sender_id, amount.0, memo);
+            return U128(0);
+        }
+        assert!(amount.0 > 0, "Funding amount must be greater than zero");
+
+        // Update total reward pool; emission owed so far is drawn from the pool as it was
+        self.internal_update_emission();
+        // Only what fits under the pool cap is accepted, the rest is returned to the sender
+        let accepted = self.max_reward_pool.map_or(amount.0, |max_reward_pool| {
+            amount
+                .0
+                .min(max_reward_pool.saturating_sub(self.reward_distribution.total_reward_pool))
+        });
+        if accepted == 0 {
+            env::log_str("Reward pool is at its cap, funding refunded");
+            return amount;
+        }
+        self.reward_distribution.total_reward_pool += accepted;
+
+        // Track funding record
+        self.reward_distribution.funding_records.push(&FundingRecord {
+            amount: accepted,
+            timestamp: env::block_timestamp(),
+            sender_id: sender_id.clone(),
+            memo,
+        });
+        self.total_funded += accepted;
+        add_to_funding_periods(&mut self.funding_periods, env::block_timestamp(), accepted);
+
+        StakingEvent::Fund {
+            sender_id: sender_id.clone(),
+            amount: U128(accepted),
+        }
+        .emit();
+        env::log_str(&format!(
+            "Reward pool funded with {} SIN tokens by {} with message {}",
+            accepted, env::predecessor_account_id(), msg
+        ));
+
+        if !matches!(funding_msg, FundingMsg::Fund) {
+            assert!(
+                self.internal_has_role(&sender_id, Role::Distributor),
+                "Only distributors can fund and distribute"
+            );
+            self.assert_operation_active(Operation::Distribution);
+            // Panicking here refunds the whole transfer, so funding and round go together
+            if let FundingMsg::DistributeNow = funding_msg {
+                self.internal_start_airdrop(accepted);
+            } else {
+                self.internal_start_distribution(accepted);
+            }
+            // Large staker sets are finished with `distribute_rewards_chunk`
+            self.internal_distribute_chunk(KEEPER_CHUNK_SIZE);
+        }
+        // The unused amount is refunded by the token contract
+        U128(amount.0 - accepted)
+    }
+
+    #[payable]
+    pub fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        token_id: String,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        env::log_str(&format!("Received NFT {} from {} with msg {}", token_id, sender_id, msg));
+
+        // Ensure the call is from the SIN NFT contract or an enabled collection
+        let nft_contract = env::predecessor_account_id();
+        assert!(
+            nft_contract == self.sin_nft_contract
+                || self
+                    .collections
+                    .get(&nft_contract)
+                    .is_some_and(|collection| collection.enabled),
+            "NFT can only be transferred from the SIN NFT contract or an approved collection"
+        );
+        let token_key = self.internal_token_key(&nft_contract, &token_id);
+        self.assert_operation_active(Operation::Staking);
+        assert!(!self.is_staking_closed(), "Staking is closed");
+        self.assert_no_active_distribution();
+        let mut stake_msg: StakeMsg = if msg.is_empty() {
+            StakeMsg::default()
+        } else {
+            serde_json::from_str(&msg).expect("Invalid staking msg")
+        };
+        let staker_id = stake_msg.stake_for.clone().unwrap_or_else(|| sender_id.clone());
+        assert!(
+            self.internal_can_stake_for(&staker_id, &sender_id),
+            "{} has not approved {} to stake on its behalf",
+            staker_id,
+            sender_id
+        );
+        self.assert_not_blacklisted(&sender_id);
+        self.assert_not_blacklisted(&staker_id);
+        assert!(
+            !self.whitelist_only || self.whitelist.contains(&staker_id),
+            "Staking is limited to whitelisted accounts"
+        );
+        assert!(
+            self.storage_accounts.contains_key(&staker_id),
+            "Account must be registered with storage_deposit before staking"
+        );
+        assert!(
+            self.internal_has_capacity(&staker_id, 1),
+            "Account has reached the maximum number of staked NFTs"
+        );
+        assert!(
+            !self.staked_tokens.contains_key(&token_key),
+            "NFT is already staked"
+        );
+        assert!(!self.token_blacklist.contains(&token_key), "NFT {} is blacklisted", token_key);
+        // Indices shift when records are unstaked, so the callback joins by position id
+        if let Some(index) = stake_msg.join_stake_index.take() {
+            let stake = self
+                .stakers
+                .get(&staker_id)
+                .and_then(|staker_info| staker_info.stakes.get(index))
+                .expect("Invalid staking record index to join");
+            stake_msg.join_position_id = Some(stake.position_id);
+        }
+        if let Some(position_id) = stake_msg.join_position_id {
+            let stake = self
+                .stakers
+                .get(&staker_id)
+                .and_then(|staker_info| {
+                    staker_info
+                        .stakes
+                        .iter()
+                        .find(|stake| stake.position_id == position_id)
+                })
+                .expect("Staking record to join not found");
+            if let Some(conflict) = stake.join_conflict(&stake_msg, env::block_timestamp()) {
+                env::panic_str(conflict);
+            }
+        } else {
+            if let Some(staker_info) = self.stakers.get(&staker_id) {
+                assert!(
+                    self.internal_can_open_stake(&staker_info),
+                    "Account has reached the daily limit of new staking records"
+                );
+            }
+        }
+
+        // Classify from the NFT contract's own metadata rather than the sender-supplied msg
+        let mut fetch = Promise::new(nft_contract.clone()).function_call(
+            "nft_token".to_string(),
+            serde_json::to_vec(&json!({ "token_id": token_id })).unwrap(),
+            NearToken::from_yoctonear(0),
+            GAS_FOR_NFT_TOKEN,
+        );
+        if let Some(rarity_oracle) = self.rarity_oracle.as_ref() {
+            fetch = fetch.and(Promise::new(rarity_oracle.oracle_id.clone()).function_call(
+                "get_rarity_score".to_string(),
+                serde_json::to_vec(&json!({
+                    "nft_contract": nft_contract,
+                    "token_id": token_id,
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(0),
+                GAS_FOR_RARITY_ORACLE,
+            ));
+        }
+        fetch
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_STAKE)
+                    .resolve_nft_stake(sender_id, nft_contract, token_key, stake_msg),
+            )
+            .into()
+    }
+
+    // Returns true if the NFT should be returned to the sender. The record is credited to the
+    // `stake_for` beneficiary when one is given. `token_id` is the token key.
+    #[private]
+    pub fn resolve_nft_stake(
+        &mut self,
+        sender_id: AccountId,
+        nft_contract: AccountId,
+        token_id: String,
+        stake_msg: StakeMsg,
+        #[callback_result] token: Result<Option<Value>, PromiseError>,
+    ) -> bool {
+        let token = match token {
+            Ok(Some(token)) => token,
+            _ => {
+                env::log_str(&format!("Could not fetch metadata for NFT {}, returning it", token_id));
+                return true;
+            }
+        };
+        if token.get("owner_id").and_then(|owner| owner.as_str())
+            != Some(env::current_account_id().as_str())
+        {
+            env::log_str(&format!("NFT {} is not owned by the staking contract, returning it", token_id));
+            return true;
+        }
+        if self.active_distribution.is_some()
+            || self.is_staking_closed()
+            || self.is_operation_paused(Operation::Staking)
+        {
+            env::log_str(&format!("Staking is unavailable, returning NFT {}", token_id));
+            return true;
+        }
+
+        // Classify the NFT type
+        let metadata = token.get("metadata").unwrap_or(&token);
+        let nft_type = self.internal_classify(&nft_contract, metadata);
+        let nft_score = self.internal_oracle_score().or_else(|| self.internal_nft_score(metadata));
+
+        // Update staker information
+        let staker_id = stake_msg.stake_for.clone().unwrap_or_else(|| sender_id.clone());
+        if !self.internal_can_stake_for(&staker_id, &sender_id) {
+            env::log_str(&format!(
+                "{} no longer accepts NFTs from {}, returning NFT {}",
+                staker_id, sender_id, token_id
+            ));
+            return true;
+        }
+        self.internal_checkpoint_staker(&staker_id);
+        let initial_storage = env::storage_usage();
+        let mut staker_info = self
+            .stakers
+            .get(&staker_id)
+            .unwrap_or_else(|| StakerInfo::new(&staker_id));
+        // The record may have been unstaked or changed since nft_on_transfer
+        let now = env::block_timestamp();
+        let join_index = match stake_msg.join_position_id {
+            Some(position_id) => {
+                let Some(index) = staker_info
+                    .stakes
+                    .iter()
+                    .position(|stake| stake.position_id == position_id)
+                else {
+                    env::log_str(&format!(
+                        "Staking record to join no longer exists, returning NFT {}",
+                        token_id
+                    ));
+                    return true;
+                };
+                let stake = staker_info.stakes.get(index as u64).unwrap();
+                if let Some(conflict) = stake.join_conflict(&stake_msg, now) {
+                    env::log_str(&format!("{}, returning NFT {}", conflict, token_id));
+                    return true;
+                }
+                Some(index as u64)
+            }
+            None => None,
+        };
+        if !self.internal_has_capacity(&staker_id, 1) {
+            env::log_str(&format!("Staking limit reached, returning NFT {}", token_id));
+            return true;
+        }
+        // Gifts come from senders the beneficiary approved and don't use up its daily quota
+        let gifted = staker_id != sender_id;
+        if join_index.is_none() && !gifted && !self.internal_can_open_stake(&staker_info) {
+            env::log_str(&format!("Daily record limit reached, returning NFT {}", token_id));
+            return true;
+        }
+        if self.staked_tokens.contains_key(&token_id) {
+            env::log_str(&format!("NFT {} is already staked, returning it", token_id));
+            return true;
+        }
+        if !self.internal_type_has_room(&nft_type) {
+            env::log_str(&format!("{} cap reached, returning NFT {}", nft_type, token_id));
+            return true;
+        }
+
+        StakingEvent::Stake {
+            account_id: staker_id.clone(),
+            token_id: token_id.clone(),
+            nft_type: nft_type.clone(),
+        }
+        .emit();
+
+        self.internal_count_staked_type(&nft_type, true);
+        let mut nft_types = HashMap::new();
+        nft_types.insert(token_id.clone(), nft_type);
+        let mut rarity_scores = HashMap::new();
+        if let Some(score) = nft_score {
+            rarity_scores.insert(token_id.clone(), score);
+        }
+        let mut nft_contracts = HashMap::new();
+        if nft_contract != self.sin_nft_contract {
+            nft_contracts.insert(token_id.clone(), nft_contract);
+        }
+
+        if let Some(index) = join_index {
+            let mut stake = staker_info.stakes.get(index).unwrap();
+            let joined = NFTStakingRecord {
+                nft_ids: vec![token_id.clone()],
+                nft_types,
+                rarity_scores,
+                nft_contracts,
+                staked_at: HashMap::from([(token_id.clone(), now)]),
+                start_timestamp: now,
+                ..stake.clone()
+            };
+            stake.pin_staked_at();
+            stake.start_timestamp = self.internal_merged_start_timestamp(&stake, &joined);
+            stake.nft_ids.push(token_id.clone());
+            stake.nft_types.extend(joined.nft_types);
+            stake.rarity_scores.extend(joined.rarity_scores);
+            stake.nft_contracts.extend(joined.nft_contracts);
+            stake.staked_at.extend(joined.staked_at);
+            staker_info.stakes.replace(index, &stake);
+        } else {
+            // Flexible records have no lockup and can be unstaked at any time
+            let lockup_period = if stake_msg.flexible.unwrap_or(false) { 0 } else { MONTH };
+            if !gifted {
+                staker_info.count_new_stake(now);
+            }
+            staker_info.stakes.push(&NFTStakingRecord {
+                nft_ids: vec![token_id.clone()],
+                nft_types,
+                start_timestamp: now,
+                lockup_period,
+                claimed_rewards: 0,
+                lockup_start: now,
+                auto_renew: stake_msg.auto_renew.unwrap_or(false),
+                position_id: self.internal_next_position_id(),
+                tokenized: false,
+                emission_weight: 0,
+                emission_acc: self.emission.acc_reward_per_weight,
+                vesting_amount: 0,
+                vesting_start: 0,
+                vesting_end: 0,
+                last_claim: now,
+                rarity_scores,
+                nft_contracts,
+                staked_at: HashMap::new(),
+            });
+        }
+
+        self.stakers.insert(&staker_id, &staker_info);
+        self.staked_tokens.insert(&token_id, &staker_id);
+        // Panicking here fails the callback, which makes the NFT contract return the token
+        self.internal_update_storage(&staker_id, initial_storage, true);
+        self.internal_checkpoint_staker(&staker_id);
+
+        if staker_id == sender_id {
+            env::log_str(&format!("NFT {} successfully staked by {}", token_id, sender_id));
+        } else {
+            env::log_str(&format!(
+                "NFT {} successfully staked by {} for {}",
+                token_id, sender_id, staker_id
+            ));
+        }
+
+        // Returning `false` ensures the NFT is not refunded
+        false
+    }
+
+    // Classifies metadata with the trait rules; the highest-priority matching rule wins
+    pub fn classify_nft_type(&self, meta: Value) -> String {
+        Self::classify_with(&self.trait_rules, &self.default_nft_type, &meta)
+    }
+
+    // Type of an NFT of `nft_contract`, namespaced for collections other than SIN's
+    fn internal_classify(&self, nft_contract: &AccountId, meta: &Value) -> String {
+        match self.collections.get(nft_contract) {
+            Some(collection) => format!(
+                "{}:{}",
+                nft_contract,
+                Self::classify_with(&collection.trait_rules, &collection.default_nft_type, meta)
+            ),
+            None => self.classify_nft_type(meta.clone()),
+        }
+    }
+
+    fn classify_with(trait_rules: &[TraitRule], default_nft_type: &str, meta: &Value) -> String {
+        let mut best_match: Option<&TraitRule> = None;
+
+        for (trait_type, value) in Self::metadata_attributes(meta) {
+            for rule in trait_rules.iter() {
+                if rule.trait_type == trait_type
+                    && rule.value == value
+                    && best_match
+                        .map(|best| rule.priority > best.priority)
+                        .unwrap_or(true)
+                {
+                    best_match = Some(rule);
+                }
+            }
+        }
+
+        best_match
+            .map(|rule| rule.nft_type.clone())
+            .unwrap_or_else(|| default_nft_type.to_string())
+    }
+
+    // Trait type/value pairs of an NFT across the common NEAR metadata layouts: `attributes` in
+    // the reference blob or inline, Mintbase's `extra` array in the reference blob, and Paras'
+    // `extra` JSON string. Numeric values are returned as strings.
+    fn metadata_attributes(meta: &Value) -> Vec<(String, String)> {
+        let blob = meta.get("reference_blob");
+        // Paras stores the attributes in a JSON encoded `extra` string
+        let extra = meta
+            .get("extra")
+            .and_then(|extra| extra.as_str())
+            .and_then(|extra| serde_json::from_str::<Value>(extra).ok());
+        let sources = [
+            blob.and_then(|blob| blob.get("attributes")),
+            blob.and_then(|blob| blob.get("extra")),
+            meta.get("attributes"),
+            extra.as_ref().and_then(|extra| extra.get("attributes")),
+        ];
+        sources
+            .into_iter()
+            .flatten()
+            .filter_map(|attrs| attrs.as_array())
+            .flatten()
+            .filter_map(|attribute| {
+                let trait_type = attribute
+                    .get("trait_type")
+                    .or_else(|| attribute.get("key"))
+                    .and_then(|t| t.as_str())?;
+                let value = Self::attribute_value(attribute.get("value")?)?;
+                Some((trait_type.to_string(), value))
+            })
+            .collect()
+    }
+
+    fn attribute_value(value: &Value) -> Option<String> {
+        match value {
+            Value::String(value) => Some(value.clone()),
+            Value::Number(value) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+
+    // Score returned by the rarity oracle, the second result of the staking callback when an
+    // oracle is configured. A failed or empty answer falls back to the metadata.
+    fn internal_oracle_score(&self) -> Option<u32> {
+        // The oracle may have been removed since the stake was started
+        let config = self.rarity_oracle.as_ref()?;
+        if env::promise_results_count() < 2 {
+            return None;
+        }
+        let score = match env::promise_result(1) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<Option<u32>>(&value).ok().flatten()
+            }
+            _ => None,
+        }?;
+        Some(score.clamp(config.min_weight, config.max_weight))
+    }
+
+    // Lets a curated contract score NFTs at staking time instead of their metadata. It is called
+    // as `get_rarity_score({"nft_contract", "token_id"})` and should return a weight or null,
+    // which is clamped to the configured bounds. `None` removes the oracle.
+    pub fn set_rarity_oracle(&mut self, rarity_oracle: Option<RarityOracleConfig>) {
+        self.assert_role(Role::Owner);
+        if let Some(config) = rarity_oracle.as_ref() {
+            assert!(config.max_weight > 0, "Maximum weight must be greater than zero");
+            assert!(
+                config.min_weight <= config.max_weight,
+                "Minimum weight cannot exceed the maximum weight"
+            );
+        }
+        self.internal_queue_change(ConfigChange::RarityOracle { rarity_oracle });
+    }
+
+    // Weight of an NFT replacing its type weight: its rarity score when configured, otherwise the
+    // sum of its trait points. None when neither applies.
+    fn internal_nft_score(&self, meta: &Value) -> Option<u32> {
+        self.internal_rarity_score(meta).or_else(|| self.internal_trait_points(meta))
+    }
+
+    // Sum of the points of the NFT's traits, or None if no trait earns points
+    fn internal_trait_points(&self, meta: &Value) -> Option<u32> {
+        let mut total: Option<u32> = None;
+        for (trait_type, value) in Self::metadata_attributes(meta) {
+            for rule in self.trait_points.iter() {
+                if rule.trait_type == trait_type && rule.value == value {
+                    total = Some(total.unwrap_or(0).saturating_add(rule.points));
+                }
+            }
+        }
+        total
+    }
+
+    // Scores NFTs staked from now on by their traits: every configured trait_type/value the NFT
+    // has adds its points, and the sum replaces the type weight. Replaces any existing entry for
+    // the same trait_type and value.
+    pub fn add_trait_points(&mut self, rule: TraitPoints) {
+        self.assert_role(Role::Owner);
+        self.trait_points
+            .retain(|r| !(r.trait_type == rule.trait_type && r.value == rule.value));
+        self.trait_points.push(rule.clone());
+        StakingEvent::ConfigUpdate {
+            parameter: "trait_points".to_string(),
+            value: json!(rule),
+        }
+        .emit();
+    }
+
+    pub fn remove_trait_points(&mut self, trait_type: String, value: String) {
+        self.assert_role(Role::Owner);
+        let rule_count = self.trait_points.len();
+        self.trait_points
+            .retain(|r| !(r.trait_type == trait_type && r.value == value));
+        assert!(self.trait_points.len() < rule_count, "Trait points not found");
+        StakingEvent::ConfigUpdate {
+            parameter: "trait_points_removed".to_string(),
+            value: json!({ "trait_type": trait_type, "value": value }),
+        }
+        .emit();
+    }
+
+    pub fn get_trait_points(&self) -> Vec<TraitPoints> {
+        self.trait_points.clone()
+    }
+
+    // Rarity score of an NFT from its metadata, clamped to the configured weights. Integers and
+    // decimals are accepted, as numbers or strings, and decimals are rounded down. None when
+    // rarity scores are off or the score is missing or malformed.
+    fn internal_rarity_score(&self, meta: &Value) -> Option<u32> {
+        let config = self.rarity_score.as_ref()?;
+        let value = Self::metadata_attributes(meta)
+            .into_iter()
+            .find(|(trait_type, _)| *trait_type == config.trait_type)
+            .map(|(_, value)| value)
+            .or_else(|| meta.get(&config.trait_type).and_then(Self::attribute_value))?;
+        // Parsed as a fixed-point decimal rather than a float, so every node gets the same weight
+        let value = value.trim();
+        let (integer, fraction) = value.split_once('.').unwrap_or((value, ""));
+        if !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let score = integer.parse::<u64>().ok()?.min(u32::MAX as u64) as u32;
+        Some(score.clamp(config.min_weight, config.max_weight))
+    }
+
+    // Weighs NFTs staked from now on by the rarity score read from their metadata, falling back
+    // to the type weight for NFTs without one. Already staked NFTs keep their weight. `None`
+    // turns rarity scores off.
+    pub fn set_rarity_score(&mut self, config: Option<RarityScoreConfig>) {
+        self.assert_role(Role::Owner);
+        if let Some(config) = config.as_ref() {
+            assert!(config.max_weight > 0, "Maximum weight must be greater than zero");
+            assert!(
+                config.min_weight <= config.max_weight,
+                "Minimum weight cannot exceed the maximum weight"
+            );
+        }
+        self.rarity_score = config;
+        StakingEvent::ConfigUpdate {
+            parameter: "rarity_score".to_string(),
+            value: json!(self.rarity_score),
+        }
+        .emit();
+    }
+
+    pub fn get_rarity_score(&self) -> Option<RarityScoreConfig> {
+        self.rarity_score.clone()
+    }
+
+    // Key a staked NFT is tracked under: the token id for the SIN collection and
+    // `collection:token_id` for other collections
+    fn internal_token_key(&self, nft_contract: &AccountId, token_id: &str) -> String {
+        if *nft_contract == self.sin_nft_contract {
+            return token_id.to_string();
+        }
+        format!("{}:{}", nft_contract, token_id)
+    }
+
+    // Collection and collection token id of a token staked on `stake`. The collection is stored
+    // on the record, since SIN token ids may contain ':' themselves.
+    fn internal_token_location(
+        &self,
+        stake: &NFTStakingRecord,
+        token_key: &str,
+    ) -> (AccountId, String) {
+        match stake.nft_contracts.get(token_key) {
+            Some(nft_contract) => {
+                let prefix = format!("{}:", nft_contract);
+                let token_id = token_key.strip_prefix(&prefix).unwrap_or(token_key);
+                (nft_contract.clone(), token_id.to_string())
+            }
+            None => (self.sin_nft_contract.clone(), token_key.to_string()),
+        }
+    }
+
+    // Approves another collection for staking with its own weight table and classification
+    // rules. Collections can be disabled but not removed, so their staked NFTs stay resolvable.
+    pub fn register_collection(
+        &mut self,
+        nft_contract: AccountId,
+        nft_weights: HashMap<String, u32>,
+        default_nft_type: String,
+    ) {
+        self.assert_role(Role::Owner);
+        assert!(
+            nft_contract != self.sin_nft_contract,
+            "The SIN collection is configured on the contract itself"
+        );
+        assert!(
+            !self.collections.contains_key(&nft_contract),
+            "Collection is already registered"
+        );
+        assert!(
+            nft_weights.contains_key(&default_nft_type),
+            "Unknown NFT type, set its weight first"
+        );
+        self.internal_queue_change(ConfigChange::Collection {
+            nft_contract,
+            nft_weights,
+            default_nft_type,
+        });
+    }
+
+    pub fn set_collection_enabled(&mut self, nft_contract: AccountId, enabled: bool) {
+        self.assert_role(Role::Owner);
+        self.internal_collection_mut(&nft_contract).enabled = enabled;
+        StakingEvent::ConfigUpdate {
+            parameter: "collection_enabled".to_string(),
+            value: json!({ "nft_contract": nft_contract, "enabled": enabled }),
+        }
+        .emit();
+    }
+
+    // Scales the weight of every NFT of the collection, including rarity scores and trait points,
+    // so partner collections can earn a fraction of the native weight. At most 100%. Rounds use
+    // it right away, but streamed emission weights only follow once the stakers are checkpointed,
+    // so checkpoint_stakers should be run over every page after the change is executed.
+    pub fn set_collection_multiplier(&mut self, nft_contract: AccountId, multiplier_bps: u32) {
+        self.assert_role(Role::Owner);
+        assert!(
+            multiplier_bps as u128 <= BPS_DENOMINATOR,
+            "Multiplier cannot exceed {} bps",
+            BPS_DENOMINATOR
+        );
+        assert!(self.collections.contains_key(&nft_contract), "Collection not found");
+        self.internal_queue_change(ConfigChange::CollectionMultiplier {
+            nft_contract,
+            multiplier_bps,
+        });
+    }
+
+    pub fn set_collection_weight(
+        &mut self,
+        nft_contract: AccountId,
+        nft_type: String,
+        weight: u32,
+    ) {
+        self.assert_role(Role::Owner);
+        assert!(self.collections.contains_key(&nft_contract), "Collection not found");
+        self.internal_queue_change(ConfigChange::CollectionWeight {
+            nft_contract,
+            nft_type,
+            weight,
+        });
+    }
+
+    // Adds a classification rule of the collection, replacing any rule for the same trait_type
+    // and value
+    pub fn add_collection_trait_rule(&mut self, nft_contract: AccountId, rule: TraitRule) {
+        self.assert_role(Role::Owner);
+        let collection = self.collections.get(&nft_contract).expect("Collection not found");
+        assert!(
+            collection.nft_weights.contains_key(&rule.nft_type),
+            "Unknown NFT type, set its weight first"
+        );
+        self.internal_queue_change(ConfigChange::CollectionTraitRule { nft_contract, rule });
+    }
+
+    pub fn remove_collection_trait_rule(
+        &mut self,
+        nft_contract: AccountId,
+        trait_type: String,
+        value: String,
+    ) {
+        self.assert_role(Role::Owner);
+        let collection = self.internal_collection_mut(&nft_contract);
+        let rule_count = collection.trait_rules.len();
+        collection
+            .trait_rules
+            .retain(|r| !(r.trait_type == trait_type && r.value == value));
+        assert!(collection.trait_rules.len() < rule_count, "Trait rule not found");
+        StakingEvent::ConfigUpdate {
+            parameter: "collection_trait_rule_removed".to_string(),
+            value: json!({
+                "nft_contract": nft_contract,
+                "trait_type": trait_type,
+                "value": value,
+            }),
+        }
+        .emit();
+    }
+
+    pub fn get_collections(&self) -> Vec<(AccountId, NftCollection)> {
+        self.collections
+            .iter()
+            .map(|(nft_contract, collection)| (nft_contract.clone(), collection.clone()))
+            .collect()
+    }
+
+    fn internal_collection_mut(&mut self, nft_contract: &AccountId) -> &mut NftCollection {
+        self.collections
+            .get_mut(nft_contract)
+            .expect("Collection not found")
+    }
+
+    // Adds a rule, replacing any existing rule for the same trait_type and value
+    pub fn add_trait_rule(&mut self, rule: TraitRule) {
+        self.assert_role(Role::Owner);
+        assert!(
+            self.nft_weights.contains_key(&rule.nft_type),
+            "Unknown NFT type, set its weight first"
+        );
+        self.internal_queue_change(ConfigChange::TraitRule { rule });
+    }
+
+    pub fn remove_trait_rule(&mut self, trait_type: String, value: String) {
+        self.assert_role(Role::Owner);
+        assert!(
+            self.trait_rules
+                .iter()
+                .any(|r| r.trait_type == trait_type && r.value == value),
+            "Trait rule not found"
+        );
+        self.internal_queue_change(ConfigChange::RemoveTraitRule { trait_type, value });
+    }
+
+    // Type assigned to NFTs that match no trait rule
+    pub fn set_default_nft_type(&mut self, nft_type: String) {
+        self.assert_role(Role::Owner);
+        assert!(
+            self.nft_weights.contains_key(&nft_type),
+            "Unknown NFT type, set its weight first"
+        );
+        self.internal_queue_change(ConfigChange::DefaultNftType { nft_type });
+    }
+
+    // Caps how many NFTs a single account can have staked; `None` removes the cap
+    pub fn set_max_nfts_per_account(&mut self, max_nfts: Option<u32>) {
+        self.assert_role(Role::Owner);
+        assert!(max_nfts != Some(0), "Cap must be greater than zero");
+        self.internal_queue_change(ConfigChange::MaxNftsPerAccount { max_nfts });
+    }
+
+    // Caps how many NFTs of `nft_type` can be staked contract-wide; NFTs over the cap are
+    // returned. Already staked NFTs are kept when the cap is lowered. `None` removes the cap.
+    pub fn set_type_cap(&mut self, nft_type: String, cap: Option<u64>) {
+        self.assert_role(Role::Owner);
+        self.internal_queue_change(ConfigChange::TypeCap { nft_type, cap });
+    }
+
+    pub fn get_staked_type_counts(&self) -> HashMap<String, u64> {
+        self.staked_type_counts.clone()
+    }
+
+    // Caps how many new records an account can create per 24h window, so storage can't be
+    // griefed with hundreds of single-NFT records. Joining an existing record is not limited.
+    pub fn set_max_new_stakes_per_day(&mut self, max_stakes: Option<u32>) {
+        self.assert_role(Role::Owner);
+        assert!(max_stakes != Some(0), "Limit must be greater than zero");
+        self.max_new_stakes_per_day = max_stakes;
+        StakingEvent::ConfigUpdate {
+            parameter: "max_new_stakes_per_day".to_string(),
+            value: json!(max_stakes),
+        }
+        .emit();
+    }
+
+    pub fn set_claim_cooldown(&mut self, cooldown: u64) {
+        self.assert_role(Role::Owner);
+        assert!(cooldown <= MONTH, "Cooldown cannot exceed {} seconds", MONTH);
+        self.internal_queue_change(ConfigChange::ClaimCooldown { cooldown });
+    }
+
+    pub fn set_min_claim_amount(&mut self, min_claim_amount: U128) {
+        self.assert_role(Role::Owner);
+        assert!(
+            min_claim_amount.0 <= MAX_MIN_CLAIM_AMOUNT,
+            "Minimum claim cannot exceed {}",
+            MAX_MIN_CLAIM_AMOUNT
+        );
+        self.internal_queue_change(ConfigChange::MinClaimAmount { min_claim_amount });
+    }
+
+    // Caps the unallocated SIN pool; `None` removes the cap. Funding beyond it is refunded.
+    pub fn set_max_reward_pool(&mut self, max_reward_pool: Option<U128>) {
+        self.assert_role(Role::Owner);
+        self.internal_queue_change(ConfigChange::MaxRewardPool { max_reward_pool });
+    }
+
+    // Opens the SIN reward pool to donations from any account, e.g. for community-boosted months
+    pub fn set_community_funding(&mut self, enabled: bool) {
+        self.assert_role(Role::Owner);
+        self.internal_queue_change(ConfigChange::CommunityFunding { enabled });
+    }
+
+    pub fn get_trait_rules(&self) -> Vec<TraitRule> {
+        self.trait_rules.clone()
+    }
+
+    pub fn get_default_nft_type(&self) -> String {
+        self.default_nft_type.clone()
+    }
+
+    pub fn distribute_rewards(&mut self, amount: U128) {
+        self.start_distribution(amount);
+        self.internal_distribute_chunk(u64::MAX);
+    }
+
+    // Opens a distribution round that is processed in batches via `distribute_rewards_chunk`
+    pub fn start_distribution(&mut self, amount: U128) {
+        self.assert_role(Role::Distributor);
+        self.assert_operation_active(Operation::Distribution);
+        self.internal_start_distribution(amount.0);
+    }
+
+    // Pre-schedules a round of `amount` that anyone can execute from `execute_after` (ns) on. The
+    // pool must hold the amount when the round is executed, not when it is scheduled.
+    pub fn schedule_distribution(&mut self, amount: U128, execute_after: U64) -> u64 {
+        self.assert_role(Role::Distributor);
+        assert!(amount.0 > 0, "Distribution amount must be greater than zero");
+        let id = self.next_schedule_id;
+        self.next_schedule_id += 1;
+        self.scheduled_distributions.insert(
+            &id,
+            &ScheduledDistribution {
+                id,
+                amount,
+                execute_after,
+            },
+        );
+        StakingEvent::DistributionScheduled {
+            id,
+            amount,
+            execute_after,
+        }
+        .emit();
+        id
+    }
+
+    pub fn cancel_scheduled_distribution(&mut self, id: u64) {
+        self.assert_role(Role::Distributor);
+        assert!(
+            self.scheduled_distributions.remove(&id).is_some(),
+            "Scheduled distribution not found"
+        );
+        StakingEvent::ScheduledDistributionCancelled { id }.emit();
+    }
+
+    // Starts a scheduled round once its time has come and processes its first chunk. Callable by
+    // anyone; returns true once the round is complete.
+    pub fn execute_scheduled_distribution(&mut self, id: u64) -> bool {
+        self.assert_operation_active(Operation::Distribution);
+        let scheduled = self
+            .scheduled_distributions
+            .get(&id)
+            .expect("Scheduled distribution not found");
+        assert!(
+            env::block_timestamp() >= scheduled.execute_after.0,
+            "Scheduled distribution is not due yet"
+        );
+        self.scheduled_distributions.remove(&id);
+        self.internal_start_distribution(scheduled.amount.0);
+        self.internal_distribute_chunk(KEEPER_CHUNK_SIZE)
+    }
+
+    pub fn get_scheduled_distributions(&self) -> Vec<ScheduledDistribution> {
+        self.scheduled_distributions.values().collect()
+    }
+
+    // Lets anyone start the next round once the interval has elapsed, paying them a bounty
+    pub fn trigger_distribution(&mut self) -> bool {
+        self.assert_operation_active(Operation::Distribution);
+        let caller = env::predecessor_account_id();
+        assert!(
+            self.reward_per_round > 0,
+            "Keeper distributions are not configured"
+        );
+
+        self.internal_update_emission();
+        let amount = self
+            .reward_per_round
+            .min(self.reward_distribution.total_reward_pool);
+        let bounty = mul_div(amount, self.keeper_bounty_bps as u128, BPS_DENOMINATOR);
+        self.internal_start_distribution(amount - bounty);
+
+        if bounty > 0 {
+            self.reward_distribution.total_reward_pool -= bounty;
+            self.internal_transfer_sin(&caller, bounty, None);
+            StakingEvent::KeeperBounty {
+                account_id: caller,
+                amount: U128(bounty),
+            }
+            .emit();
+        }
+
+        self.internal_distribute_chunk(KEEPER_CHUNK_SIZE)
+    }
+
+    // Every round, whoever starts it, has to wait for the distribution interval
+    // A waiting snapshot is paid out instead of weighing the stakers again
+    fn internal_start_distribution(&mut self, amount: Balance) {
+        self.assert_not_shut_down();
+        self.internal_update_emission();
+        let snapshot = self
+            .active_distribution
+            .as_ref()
+            .is_some_and(|round| round.phase == DistributionPhase::Snapshot);
+        if !snapshot {
+            self.assert_distribution_due();
+        }
+        assert!(amount > 0, "Distribution amount must be greater than zero");
+        assert!(
+            amount <= self.reward_distribution.total_reward_pool,
+            "Insufficient funds in the reward pool for distribution"
+        );
+
+        // Reserve the round amount so it cannot be distributed twice
+        self.reward_distribution.total_reward_pool -= amount;
+        let amount = amount + std::mem::take(&mut self.carried_remainder);
+        let round = if snapshot {
+            let mut round = self.active_distribution.take().unwrap();
+            round.amount = amount;
+            round.internal_split();
+            self.internal_cap_round(&mut round);
+            round.phase = DistributionPhase::Paying;
+            round.cursor = 0;
+            round
+        } else {
+            self.internal_new_round(amount, env::block_timestamp())
+        };
+        self.active_distribution = Some(round);
+        StakingEvent::DistributionStarted {
+            amount: U128(amount),
+        }
+        .emit();
+    }
+
+    // Opens a round of `amount` that is not subject to the distribution interval and leaves the
+    // regular round cadence untouched
+    fn internal_start_airdrop(&mut self, amount: Balance) {
+        self.assert_not_shut_down();
+        self.internal_update_emission();
+        assert!(
+            self.active_distribution.is_none(),
+            "A reward distribution is already in progress"
+        );
+        assert!(amount > 0, "Distribution amount must be greater than zero");
+        assert!(
+            amount <= self.reward_distribution.total_reward_pool,
+            "Insufficient funds in the reward pool for distribution"
+        );
+        self.reward_distribution.total_reward_pool -= amount;
+        let mut round = self.internal_new_round(amount, env::block_timestamp());
+        round.airdrop = true;
+        self.active_distribution = Some(round);
+        StakingEvent::DistributionStarted {
+            amount: U128(amount),
+        }
+        .emit();
+    }
+
+    fn internal_mark_distributed(&mut self, round: &DistributionRound) {
+        if !round.airdrop {
+            self.last_distributed = round.started_at;
+            self.reward_distribution.last_distributed = round.started_at;
+        }
+    }
+
+    fn assert_distribution_due(&self) {
+        assert!(
+            self.active_distribution.is_none(),
+            "A reward distribution is already in progress"
+        );
+        assert!(
+            env::block_timestamp()
+                >= self.last_distributed + self.distribution_interval * 1_000_000_000,
+            "Distribution interval has not elapsed yet"
+        );
+    }
+
+    fn internal_new_round(&self, amount: Balance, started_at: u64) -> DistributionRound {
+        DistributionRound {
+            amount,
+            phase: DistributionPhase::Weighing,
+            cursor: 0,
+            total_weight: 0,
+            started_at,
+            staker_count: 0,
+            type_shares: self.type_pool_shares.clone(),
+            type_weights: HashMap::new(),
+            type_amounts: HashMap::new(),
+            max_share_bps: self.max_account_share_bps,
+            share_candidates: HashMap::new(),
+            capped_rewards: HashMap::new(),
+            capped_excess: 0,
+            distributed: 0,
+            airdrop: false,
+        }
+    }
+
+    // Freezes the weight table now: the stakers are weighed (in batches via
+    // `distribute_rewards_chunk`) and the next round started pays against this snapshot. Staking
+    // changes are paused until that round completes. Returns true once weighing is complete.
+    pub fn take_snapshot(&mut self) -> bool {
+        self.assert_role(Role::Distributor);
+        self.assert_operation_active(Operation::Distribution);
+        self.assert_not_shut_down();
+        self.assert_distribution_due();
+        self.internal_update_emission();
+        // A round without an amount stops at the snapshot phase once weighed
+        self.active_distribution = Some(self.internal_new_round(0, env::block_timestamp()));
+        let weighed = self.internal_distribute_chunk(KEEPER_CHUNK_SIZE);
+        assert!(
+            !weighed || self.active_distribution.is_some(),
+            "No staked weight to snapshot"
+        );
+        weighed
+    }
+
+    // Drops a snapshot that is waiting for its round, resuming staking changes
+    pub fn cancel_snapshot(&mut self) {
+        self.assert_role(Role::Distributor);
+        let round = self
+            .active_distribution
+            .take()
+            .filter(|round| round.phase == DistributionPhase::Snapshot)
+            .expect("No snapshot waiting for a distribution");
+        StakingEvent::SnapshotCancelled {
+            timestamp: U64(round.started_at),
+        }
+        .emit();
+    }
+
+    // Processes up to `limit` stakers of the active round. Returns true once the round is complete.
+    pub fn distribute_rewards_chunk(&mut self, limit: u64) -> bool {
+        assert!(limit > 0, "Limit must be greater than zero");
+        self.assert_operation_active(Operation::Distribution);
+        self.internal_distribute_chunk(limit)
+    }
+
+    fn internal_distribute_chunk(&mut self, limit: u64) -> bool {
+        let mut round = self
+            .active_distribution
+            .take()
+            .expect("No reward distribution in progress");
+        assert!(
+            round.phase != DistributionPhase::Snapshot,
+            "The snapshot is waiting for a distribution to be started"
+        );
+        let staker_count = self.stakers.len();
+        let mut processed = 0;
+
+        loop {
+            if round.cursor == staker_count {
+                match round.phase {
+                    DistributionPhase::Weighing if round.total_weight > 0 && round.amount == 0 => {
+                        round.phase = DistributionPhase::Snapshot;
+                        StakingEvent::SnapshotTaken {
+                            timestamp: U64(round.started_at),
+                            total_weight: U128(round.total_weight),
+                            staker_count: round.staker_count,
+                        }
+                        .emit();
+                        self.active_distribution = Some(round);
+                        return true;
+                    }
+                    DistributionPhase::Weighing if round.amount == 0 => {
+                        // A snapshot without weight has nothing to pay against. It is dropped
+                        // without counting as a round, leaving the next one due as before.
+                        StakingEvent::SnapshotCancelled {
+                            timestamp: U64(round.started_at),
+                        }
+                        .emit();
+                        env::log_str("No staked weight to snapshot, the snapshot was dropped");
+                        return true;
+                    }
+                    DistributionPhase::Weighing if round.total_weight > 0 => {
+                        round.internal_split();
+                        self.internal_cap_round(&mut round);
+                        round.phase = DistributionPhase::Paying;
+                        round.cursor = 0;
+                        continue;
+                    }
+                    DistributionPhase::Weighing => {
+                        // Nothing is staked, so the reserved amount goes back to the pool. The round
+                        // still counts towards the interval so keepers can't farm bounties from it.
+                        self.reward_distribution.total_reward_pool += round.amount;
+                        self.internal_mark_distributed(&round);
+                        self.internal_flush_restores();
+                        StakingEvent::DistributionCompleted {
+                            amount: U128(0),
+                            total_weight: U128(0),
+                        }
+                        .emit();
+                        env::log_str(&format!(
+                            "No staked NFTs to distribute rewards to, {} returned to the pool",
+                            round.amount
+                        ));
+                        return true;
+                    }
+                    DistributionPhase::Snapshot => unreachable!(),
+                    DistributionPhase::Paying => {
+                        // Capped rewards nobody could receive go back to the pool
+                        self.reward_distribution.total_reward_pool += round.capped_excess;
+                        round.amount -= round.capped_excess;
+                        let remainder = round.amount - round.distributed;
+                        self.carried_remainder += remainder;
+                        self.total_rewards_distributed += round.distributed;
+                        self.distribution_history.push(&DistributionRecord {
+                            amount: U128(round.amount),
+                            timestamp: U64(round.started_at),
+                            total_weight: U128(round.total_weight),
+                            staker_count: round.staker_count,
+                            remainder: U128(remainder),
+                            airdrop: round.airdrop,
+                        });
+                        self.internal_mark_distributed(&round);
+                        self.internal_flush_restores();
+                        StakingEvent::DistributionCompleted {
+                            amount: U128(round.distributed),
+                            total_weight: U128(round.total_weight),
+                        }
+                        .emit();
+                        env::log_str(&format!(
+                            "Distributed {} SIN across a total weight of {}, {} carried to the next \
+                             round",
+                            round.distributed, round.total_weight, remainder
+                        ));
+                        return true;
+                    }
+                }
+            }
+            if processed == limit {
+                break;
+            }
+
+            let staker_id = self.stakers.keys_as_vector().get(round.cursor).unwrap();
+            match round.phase {
+                DistributionPhase::Weighing => {
+                    let staker_info = self.stakers.get(&staker_id).unwrap();
+                    self.internal_weigh_staker(&staker_id, &staker_info, &mut round);
+                }
+                DistributionPhase::Snapshot => unreachable!(),
+                DistributionPhase::Paying => {
+                    round.distributed += self.internal_pay_staker(&staker_id, &round);
+                }
+            }
+            round.cursor += 1;
+            processed += 1;
+        }
+
+        self.active_distribution = Some(round);
+        false
+    }
+
+    fn internal_weigh_staker(
+        &self,
+        staker_id: &AccountId,
+        staker_info: &StakerInfo,
+        round: &mut DistributionRound,
+    ) {
+        let weights = self.internal_stake_weights(staker_info, round.started_at);
+        let weight = weights.iter().sum::<u128>();
+        if weight > 0 {
+            round.total_weight += weight;
+            round.staker_count += 1;
+        }
+        let mut account_type_weights: HashMap<String, u128> = HashMap::new();
+        for (stake, weight) in staker_info.stakes.iter().zip(weights) {
+            let type_weights = self.internal_stake_type_weights(&stake, weight, &round.type_shares);
+            for (nft_type, type_weight) in type_weights {
+                *round.type_weights.entry(nft_type.clone()).or_default() += type_weight;
+                *account_type_weights.entry(nft_type).or_default() += type_weight;
+            }
+        }
+        if round.max_share_bps > 0 && weight > 0 {
+            // An account's reward share is at most its largest share of the general pool or of a
+            // type pool. An account above the cap of a final total is above it of every partial
+            // total, and fewer than 1 / cap accounts can be above it per pool.
+            round
+                .share_candidates
+                .insert(staker_id.clone(), (weight, account_type_weights));
+            let max_share_bps = round.max_share_bps as u128;
+            let above_cap = |weight: u128, total: u128| {
+                weight * BPS_DENOMINATOR > total * max_share_bps
+            };
+            let (total_weight, pool_weights) = (round.total_weight, &round.type_weights);
+            round.share_candidates.retain(|_, (weight, type_weights)| {
+                above_cap(*weight, total_weight)
+                    || type_weights
+                        .iter()
+                        .any(|(nft_type, weight)| above_cap(*weight, pool_weights[nft_type]))
+            });
+        }
+    }
+
+    // Part of a record's weight contributed by NFTs of the earmarked types
+    fn internal_stake_type_weights(
+        &self,
+        stake: &NFTStakingRecord,
+        weight: u128,
+        type_shares: &HashMap<String, u32>,
+    ) -> HashMap<String, u128> {
+        let mut type_weights: HashMap<String, u128> = HashMap::new();
+        let stake_weight = self.internal_stake_weight(stake, true);
+        if type_shares.is_empty() || stake_weight == 0 {
+            return type_weights;
+        }
+        for (nft_type, nft_weight) in self.internal_nft_weights(stake, true) {
+            if type_shares.contains_key(nft_type) {
+                *type_weights.entry(nft_type.clone()).or_default() += nft_weight;
+            }
+        }
+        type_weights
+            .into_iter()
+            .map(|(nft_type, type_weight)| (nft_type, mul_div(weight, type_weight, stake_weight)))
+            .collect()
+    }
+
+    // A fully weighed round of `amount` started at `period_end`, for estimating payouts. A waiting
+    // snapshot is used as is, since the next round pays against it.
+    fn internal_preview_round(&self, amount: Balance, period_end: u64) -> DistributionRound {
+        let snapshot = self
+            .active_distribution
+            .clone()
+            .filter(|round| round.phase == DistributionPhase::Snapshot);
+        let mut round = match snapshot {
+            Some(snapshot) => snapshot,
+            None => {
+                let mut round = self.internal_new_round(amount, period_end);
+                for (staker_id, staker_info) in self.stakers.iter() {
+                    self.internal_weigh_staker(&staker_id, &staker_info, &mut round);
+                }
+                round
+            }
+        };
+        round.amount = amount;
+        round.phase = DistributionPhase::Paying;
+        round.internal_split();
+        self.internal_cap_round(&mut round);
+        round
+    }
+
+    // Caps the accounts whose rewards exceed the round's max share, largest first. Each capped
+    // account's excess is redistributed over the remaining weight, which can push the next one
+    // over the cap.
+    fn internal_cap_round(&self, round: &mut DistributionRound) {
+        let mut candidates: Vec<(AccountId, Balance)> = round
+            .share_candidates
+            .keys()
+            .map(|staker_id| {
+                let staker_info = self.stakers.get(staker_id).unwrap();
+                let rewards = self.internal_uncapped_round_rewards(&staker_info, round);
+                (staker_id.clone(), rewards.iter().sum())
+            })
+            .collect();
+        candidates.sort_by_key(|(_, reward)| std::cmp::Reverse(*reward));
+
+        let max_share = round.max_share();
+        let mut capped_total = 0;
+        for (staker_id, reward) in candidates {
+            let capped_count = round.capped_rewards.len() as u128;
+            let share = mul_div(
+                reward,
+                round.amount - capped_count * max_share,
+                round.amount - capped_total,
+            );
+            if share <= max_share {
+                break;
+            }
+            capped_total += reward;
+            round.capped_rewards.insert(staker_id, reward);
+        }
+        if round.capped_rewards.len() as u64 == round.staker_count {
+            round.capped_excess = round.amount - round.capped_rewards.len() as u128 * max_share;
+        }
+    }
+
+    // Rewards of each of the staker's records in `round`
+    fn internal_round_rewards(
+        &self,
+        staker_id: &AccountId,
+        staker_info: &StakerInfo,
+        round: &DistributionRound,
+    ) -> Vec<Balance> {
+        round.cap_rewards(staker_id, self.internal_uncapped_round_rewards(staker_info, round))
+    }
+
+    // Rewards of each of the staker's records in `round` before the per-account cap
+    fn internal_uncapped_round_rewards(
+        &self,
+        staker_info: &StakerInfo,
+        round: &DistributionRound,
+    ) -> Vec<Balance> {
+        if round.total_weight == 0 {
+            return vec![0; staker_info.stakes.len() as usize];
+        }
+        let weights = self.internal_stake_weights(staker_info, round.started_at);
+        staker_info
+            .stakes
+            .iter()
+            .zip(weights)
+            .map(|(stake, weight)| {
+                let type_weights =
+                    self.internal_stake_type_weights(&stake, weight, &round.type_shares);
+                round.reward(weight, &type_weights)
+            })
+            .collect()
+    }
+
+    // Credits the staker's share of the round and returns it
+    fn internal_pay_staker(&mut self, staker_id: &AccountId, round: &DistributionRound) -> Balance {
+        let mut staker_info = self.stakers.get(staker_id).unwrap();
+        let rewards = self.internal_round_rewards(staker_id, &staker_info, round);
+        let paid = rewards.iter().sum();
+        if staker_info.compounding {
+            self.internal_compound(staker_id, &mut staker_info, paid);
+            self.stakers.insert(staker_id, &staker_info);
+            return paid;
+        }
+
+        // Each stake receives floor(amount * weight / total_weight) per pool; the rounding dust is
+        // carried into the next round
+        for (i, reward) in rewards.into_iter().enumerate() {
+            let mut stake = staker_info.stakes.get(i as u64).unwrap();
+
+            stake.add_vesting_rewards(reward, env::block_timestamp(), self.reward_vesting_period);
+            staker_info.stakes.replace(i as u64, &stake);
+        }
+        self.pending_claims_total += paid;
+        self.stakers.insert(staker_id, &staker_info);
+        paid
+    }
+
+    // Converts SIN rewards into the staker's weight boost. The SIN goes back to the reward pool.
+    fn internal_compound(
+        &mut self,
+        staker_id: &AccountId,
+        staker_info: &mut StakerInfo,
+        amount: Balance,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        staker_info.compounded_rewards += amount;
+        self.reward_distribution.total_reward_pool += amount;
+        StakingEvent::RewardsCompounded {
+            account_id: staker_id.clone(),
+            amount: U128(amount),
+            boost_bps: self.internal_compound_boost_bps(staker_info),
+        }
+        .emit();
+    }
+
+    fn internal_compound_boost_bps(&self, staker_info: &StakerInfo) -> u32 {
+        if self.compound_unit == 0 {
+            return 0;
+        }
+        mul_div(
+            staker_info.compounded_rewards,
+            self.compound_boost_bps as u128,
+            self.compound_unit,
+        )
+        .min(self.max_compound_boost_bps as u128) as u32
+    }
+
+    // Opts into converting future SIN rewards into a weight boost. Rewards already on the records
+    // stay claimable, and the boost is kept after opting out.
+    pub fn set_compounding(&mut self, compounding: bool) {
+        let staker_id = env::predecessor_account_id();
+        assert!(
+            !compounding || self.compound_unit > 0,
+            "Compounding is not enabled"
+        );
+        self.internal_checkpoint_staker(&staker_id);
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+        staker_info.compounding = compounding;
+        self.stakers.insert(&staker_id, &staker_info);
+        StakingEvent::CompoundingUpdated {
+            account_id: staker_id,
+            compounding,
+        }
+        .emit();
+    }
+
+    pub fn get_compounding(&self, account_id: AccountId) -> Option<CompoundingView> {
+        self.stakers.get(&account_id).map(|staker_info| CompoundingView {
+            compounding: staker_info.compounding,
+            compounded_rewards: U128(staker_info.compounded_rewards),
+            boost_bps: self.internal_compound_boost_bps(&staker_info),
+        })
+    }
+
+    fn assert_no_active_distribution(&self) {
+        assert!(
+            self.active_distribution.is_none(),
+            "Reward distribution in progress, try again once it completes"
+        );
+    }
+
+    // Distribution weight of each of the staker's records for a round ending at `period_end`,
+    // in basis points of the NFT type weights
+    fn internal_stake_weights(&self, staker_info: &StakerInfo, period_end: u64) -> Vec<u128> {
+        let stakes: Vec<NFTStakingRecord> = staker_info.stakes.iter().collect();
+        self.internal_base_weights(staker_info, &stakes, true)
+            .into_iter()
+            .zip(stakes.iter())
+            .map(|(weight, stake)| {
+                let weight = self.internal_aged_weight(stake, weight, period_end);
+                Self::time_weighted(weight, stake.start_timestamp, self.last_distributed, period_end)
+            })
+            .collect()
+    }
+
+    // Part of a record's `weight` carried by NFTs staked at least the minimum age before
+    // `period_end`. Checked per NFT, so joining or merging into an older record doesn't help.
+    fn internal_aged_weight(
+        &self,
+        stake: &NFTStakingRecord,
+        weight: u128,
+        period_end: u64,
+    ) -> u128 {
+        let min_age = self.min_stake_age * 1_000_000_000;
+        let is_aged = |token_id: &String| stake.nft_staked_at(token_id) + min_age <= period_end;
+        if stake.nft_ids.iter().all(is_aged) {
+            return weight;
+        }
+        let mut aged = stake.clone();
+        aged.nft_types.retain(|token_id, _| is_aged(token_id));
+        let stake_weight = self.internal_stake_weight(stake, true);
+        if stake_weight == 0 {
+            return 0;
+        }
+        mul_div(weight, self.internal_stake_weight(&aged, true), stake_weight)
+    }
+
+    // Weight of each record before time weighting: type weights with the hive set bonus, the
+    // compounding boost and the lockup multiplier applied. Scarcity scaling is left out with
+    // `scarcity` unset.
+    fn internal_base_weights(
+        &self,
+        staker_info: &StakerInfo,
+        stakes: &[NFTStakingRecord],
+        scarcity: bool,
+    ) -> Vec<u128> {
+        let mut multiplier_bps =
+            BPS_DENOMINATOR + self.internal_compound_boost_bps(staker_info) as u128;
+        if self.has_hive_set(stakes) {
+            multiplier_bps += self.set_bonus_bps as u128;
+        }
+
+        stakes
+            .iter()
+            .map(|stake| {
+                mul_div(
+                    self.internal_stake_weight(stake, scarcity) * multiplier_bps,
+                    self.internal_lockup_multiplier_bps(stake.lockup_period),
+                    BPS_DENOMINATOR,
+                )
+            })
+            .collect()
+    }
+
+    // Accrues the emission since the last update, drawn from the reward pool
+    fn internal_update_emission(&mut self) {
+        let now = env::block_timestamp();
+        if now <= self.emission.last_update {
+            return;
+        }
+        if self.emission.total_weight > 0 {
+            let emitted = self.internal_emitted_since_update(now);
+            self.reward_distribution.total_reward_pool -= emitted;
+            self.pending_claims_total += emitted;
+            let (increment, remainder) = self.internal_emission_step(emitted);
+            self.emission.acc_reward_per_weight += increment;
+            self.emission.acc_remainder = remainder;
+        }
+        self.emission.last_update = now;
+    }
+
+    // Emission since the last update, capped by what is left in the pool
+    fn internal_emitted_since_update(&self, now: u64) -> Balance {
+        // Integrate the rate over each halving epoch the period spans
+        let mut emitted = 0;
+        let mut from = self.emission.last_update;
+        while from < now {
+            let rate = self.internal_emission_rate_at(from);
+            if rate == 0 {
+                break;
+            }
+            let to = self
+                .internal_next_halving(from)
+                .map_or(now, |halving| halving.min(now));
+            emitted += mul_div(rate, (to - from) as u128, 1_000_000_000);
+            from = to;
+        }
+        emitted.min(self.reward_distribution.total_reward_pool)
+    }
+
+    fn internal_halvings_at(&self, timestamp: u64) -> u64 {
+        if self.emission.halving_interval == 0 {
+            return 0;
+        }
+        timestamp.saturating_sub(self.emission.schedule_start)
+            / self.emission.halving_interval.saturating_mul(1_000_000_000)
+    }
+
+    fn internal_emission_rate_at(&self, timestamp: u64) -> Balance {
+        self.emission
+            .rate_per_second
+            .checked_shr(self.internal_halvings_at(timestamp) as u32)
+            .unwrap_or(0)
+    }
+
+    // Start of the halving epoch after the one containing `timestamp`
+    fn internal_next_halving(&self, timestamp: u64) -> Option<u64> {
+        if self.emission.halving_interval == 0 {
+            return None;
+        }
+        let halvings = self.internal_halvings_at(timestamp) + 1;
+        let interval = self.emission.halving_interval.saturating_mul(1_000_000_000);
+        Some(
+            self.emission
+                .schedule_start
+                .saturating_add(halvings.saturating_mul(interval)),
+        )
+    }
+
+    fn internal_current_emission_acc(&self) -> u128 {
+        if self.emission.total_weight == 0 {
+            return self.emission.acc_reward_per_weight;
+        }
+        let emitted = self.internal_emitted_since_update(env::block_timestamp());
+        self.emission.acc_reward_per_weight + self.internal_emission_step(emitted).0
+    }
+
+    // Accumulator increment for `emitted` plus the remainder carried from the last update, and
+    // the new remainder. Nothing the pool streams is lost to truncation.
+    fn internal_emission_step(&self, emitted: Balance) -> (u128, u128) {
+        let scaled = U256::from(emitted) * U256::from(EMISSION_PRECISION)
+            + U256::from(self.emission.acc_remainder);
+        let total_weight = U256::from(self.emission.total_weight);
+        ((scaled / total_weight).as_u128(), (scaled % total_weight).as_u128())
+    }
+
+    // Streamed rewards a record has earned since its last checkpoint
+    fn internal_unsettled_emission(stake: &NFTStakingRecord, acc: u128) -> Balance {
+        mul_div(
+            stake.emission_weight,
+            acc - stake.emission_acc,
+            EMISSION_PRECISION,
+        )
+    }
+
+    // Settles the staker's streamed rewards into its records and refreshes their emission weights.
+    // Called before and after anything that changes the staker's records.
+    fn internal_checkpoint_staker(&mut self, staker_id: &AccountId) {
+        let Some(mut staker_info) = self.stakers.get(staker_id) else {
+            return;
+        };
+        self.internal_update_emission();
+        self.internal_update_campaigns();
+        self.internal_settle_token_rewards(&mut staker_info);
+        let acc = self.emission.acc_reward_per_weight;
+        let mut stakes: Vec<NFTStakingRecord> = staker_info.stakes.iter().collect();
+        // Compounding during a round would change the weight it is being paid out against
+        let compound = staker_info.compounding && self.active_distribution.is_none();
+        let mut compounded = 0;
+        for stake in stakes.iter_mut() {
+            let earned = Self::internal_unsettled_emission(stake, acc);
+            if compound {
+                compounded += earned;
+            } else {
+                stake.claimed_rewards += earned;
+            }
+            stake.emission_acc = acc;
+        }
+        // Streamed rewards were owed from the moment they were emitted
+        self.pending_claims_total -= compounded;
+        self.internal_compound(staker_id, &mut staker_info, compounded);
+
+        // Scarcity moves with every stake anywhere, which stored weights could not follow, so
+        // streamed rewards use the configured weights
+        let weights = self.internal_base_weights(&staker_info, &stakes, false);
+        for (i, (mut stake, weight)) in stakes.into_iter().zip(weights).enumerate() {
+            stake.emission_weight = weight;
+            staker_info.stakes.replace(i as u64, &stake);
+        }
+        let emission_weight = staker_info.stakes.iter().map(|stake| stake.emission_weight).sum();
+        self.emission.total_weight =
+            self.emission.total_weight - staker_info.emission_weight + emission_weight;
+        staker_info.emission_weight = emission_weight;
+        self.stakers.insert(staker_id, &staker_info);
+    }
+
+    // Credits the staker's share of every token distribution since its last checkpoint, at the
+    // emission weight it held in the meantime
+    fn internal_settle_token_rewards(&self, staker_info: &mut StakerInfo) {
+        for (token_id, token_rewards) in self.reward_tokens.iter() {
+            let reward = staker_info.token_rewards.entry(token_id).or_default();
+            reward.unclaimed += mul_div(
+                staker_info.emission_weight,
+                token_rewards.acc_reward_per_weight - reward.acc_reward_per_weight,
+                EMISSION_PRECISION,
+            );
+            reward.acc_reward_per_weight = token_rewards.acc_reward_per_weight;
+        }
+        let reward = &mut staker_info.near_reward;
+        reward.unclaimed += mul_div(
+            staker_info.emission_weight,
+            self.near_rewards.acc_reward_per_weight - reward.acc_reward_per_weight,
+            EMISSION_PRECISION,
+        );
+        reward.acc_reward_per_weight = self.near_rewards.acc_reward_per_weight;
+
+        // Entries of closed campaigns are dropped
+        staker_info
+            .campaign_rewards
+            .retain(|campaign_id, _| self.campaigns.get(campaign_id).is_some());
+        for (campaign_id, campaign) in self.campaigns.iter() {
+            let reward = staker_info.campaign_rewards.entry(campaign_id).or_default();
+            reward.unclaimed += mul_div(
+                staker_info.emission_weight,
+                campaign.acc_reward_per_weight - reward.acc_reward_per_weight,
+                EMISSION_PRECISION,
+            );
+            reward.acc_reward_per_weight = campaign.acc_reward_per_weight;
+        }
+    }
+
+    // Streams every campaign up to now at the current total emission weight. Must run before the
+    // total weight changes.
+    fn internal_update_campaigns(&mut self) {
+        let now = env::block_timestamp();
+        let campaigns: Vec<Campaign> = self.campaigns.values().collect();
+        for mut campaign in campaigns {
+            if campaign.last_update >= campaign.end.min(now) {
+                continue;
+            }
+            (campaign.acc_reward_per_weight, campaign.streamed) =
+                self.internal_campaign_acc(&campaign, now);
+            campaign.last_update = now.min(campaign.end);
+            self.campaigns.insert(&campaign.id, &campaign);
+        }
+    }
+
+    // Accumulated reward per weight and total streamed as of `now`. What is left to stream is
+    // spread evenly over the rest of the campaign, so top-ups stream from when they are made.
+    // What streams while nothing is staked is not credited to anyone and is refunded on close.
+    fn internal_campaign_acc(&self, campaign: &Campaign, now: u64) -> (u128, Balance) {
+        let from = campaign.last_update.max(campaign.start);
+        let to = now.min(campaign.end);
+        if to <= from {
+            return (campaign.acc_reward_per_weight, campaign.streamed);
+        }
+        let streamed = mul_div(
+            campaign.total_reward - campaign.streamed,
+            (to - from) as u128,
+            (campaign.end - from) as u128,
+        );
+        if self.emission.total_weight == 0 {
+            return (campaign.acc_reward_per_weight, campaign.streamed + streamed);
+        }
+        let acc = campaign.acc_reward_per_weight
+            + mul_div(streamed, EMISSION_PRECISION, self.emission.total_weight);
+        (acc, campaign.streamed + streamed)
+    }
+
+    // Returns the rewards of a page of stakers' records that passed their claim-by deadline to
+    // the reward pool, so abandoned accounts don't strand pool funds
+    pub fn sweep_expired_rewards(&mut self, from_index: Option<u64>, limit: Option<u64>) -> U128 {
+        self.assert_role(Role::Distributor);
+        assert!(self.reward_expiry > 0, "Reward expiry is disabled");
+        let now = env::block_timestamp();
+        let keys = self.stakers.keys_as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let to_index = keys.len().min(from_index.saturating_add(limit.unwrap_or(50)));
+        let staker_ids: Vec<AccountId> =
+            (from_index..to_index).map(|index| keys.get(index).unwrap()).collect();
+
+        let mut total_swept = 0;
+        for staker_id in staker_ids {
+            let mut staker_info = self.stakers.get(&staker_id).unwrap();
+            let mut swept = false;
+            for i in 0..staker_info.stakes.len() {
+                let mut stake = staker_info.stakes.get(i).unwrap();
+                let amount = stake.claimable_rewards(now);
+                if amount == 0 || now < stake.claim_by(self.reward_expiry).unwrap() {
+                    continue;
+                }
+                // Rewards that are still vesting never expire
+                stake.claimed_rewards -= amount;
+                staker_info.stakes.replace(i, &stake);
+                total_swept += amount;
+                swept = true;
+                StakingEvent::RewardsExpired {
+                    account_id: staker_id.clone(),
+                    stake_index: i,
+                    amount: U128(amount),
+                }
+                .emit();
+            }
+            if swept {
+                self.stakers.insert(&staker_id, &staker_info);
+            }
+        }
+        self.reward_distribution.total_reward_pool += total_swept;
+        self.pending_claims_total -= total_swept;
+        U128(total_swept)
+    }
+
+    // Refreshes the emission weights of a page of stakers, e.g. after a weight config change.
+    // Callable by anyone.
+    pub fn checkpoint_stakers(&mut self, from_index: Option<u64>, limit: Option<u64>) {
+        let keys = self.stakers.keys_as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let to_index = keys.len().min(from_index.saturating_add(limit.unwrap_or(50)));
+        let staker_ids: Vec<AccountId> =
+            (from_index..to_index).map(|index| keys.get(index).unwrap()).collect();
+        for staker_id in staker_ids.iter() {
+            self.internal_checkpoint_staker(staker_id);
+        }
+    }
+
+    // Scales a weight by the fraction of the period [period_start, period_end] it was staked for
+    fn time_weighted(weight: u128, staked_at: u64, period_start: u64, period_end: u64) -> u128 {
+        if staked_at <= period_start || period_end <= period_start {
+            return weight;
+        }
+        let staked_for = period_end.saturating_sub(staked_at);
+        mul_div(weight, staked_for as u128, (period_end - period_start) as u128)
+    }
+
+    // Start timestamp for a record combining `a` and `b`: the average of their starts within the
+    // current round, weighted by type weight, so the combined record earns what both would have
+    fn internal_merged_start_timestamp(&self, a: &NFTStakingRecord, b: &NFTStakingRecord) -> u64 {
+        let a_start = a.start_timestamp.max(self.last_distributed);
+        let b_start = b.start_timestamp.max(self.last_distributed);
+        let a_weight = self.internal_stake_weight(a, true);
+        let b_weight = self.internal_stake_weight(b, true);
+        if a_weight + b_weight == 0 {
+            return a_start.max(b_start);
+        }
+        (mul_div(a_weight, a_start as u128, a_weight + b_weight)
+            + mul_div(b_weight, b_start as u128, a_weight + b_weight)) as u64
+    }
+
+    fn internal_lockup_multiplier_bps(&self, lockup_period: u64) -> u128 {
+        if lockup_period == 0 {
+            return self.flexible_weight_bps as u128;
+        }
+        self.lockup_tiers
+            .iter()
+            .find(|tier| tier.lockup_period == lockup_period)
+            .map_or(BPS_DENOMINATOR, |tier| tier.reward_multiplier_bps as u128)
+    }
+
+    // A staker completes the hive set with at least one Queen, one Worker and one Drone staked
+    fn has_hive_set(&self, stakes: &[NFTStakingRecord]) -> bool {
+        HIVE_SET_TYPES.iter().all(|set_type| {
+            stakes.iter().any(|stake| {
+                self.internal_nft_weights(stake, false).any(|(nft_type, _)| nft_type == set_type)
+            })
+        })
+    }
+
+    fn internal_stake_weight(&self, stake: &NFTStakingRecord, scarcity: bool) -> u128 {
+        self.internal_nft_weights(stake, scarcity).map(|(_, weight)| weight).sum()
+    }
+
+    // Weight of one NFT of `nft_type`. With scarcity weights and `scarcity` set, the configured
+    // weight is scaled by the average number of staked NFTs per type over the number staked of
+    // this type, so types that are underrepresented earn more.
+    fn internal_type_weight(&self, nft_type: &str, scarcity: bool) -> u128 {
+        let weight = self.internal_configured_weight(nft_type);
+        let type_count = self.nft_weights.len()
+            + self
+                .collections
+                .values()
+                .map(|collection| collection.nft_weights.len())
+                .sum::<usize>();
+        if !scarcity || !self.scarcity_weights || type_count == 0 {
+            return weight;
+        }
+        let total_staked: u64 = self.staked_type_counts.values().sum();
+        let staked = self.staked_type_counts.get(nft_type).copied().unwrap_or(0).max(1);
+        let scarcity_bps = mul_div(
+            total_staked as u128,
+            BPS_DENOMINATOR,
+            staked as u128 * type_count as u128,
+        );
+        mul_div(
+            weight,
+            scarcity_bps.clamp(MIN_SCARCITY_BPS, MAX_SCARCITY_BPS),
+            BPS_DENOMINATOR,
+        )
+    }
+
+    // Weight set for a type, in the weight table of its collection for namespaced types
+    fn internal_configured_weight(&self, nft_type: &str) -> u128 {
+        let weight = match self.internal_type_collection(nft_type) {
+            Some((collection, collection_type)) => {
+                collection.nft_weights.get(collection_type).copied().unwrap_or(0)
+            }
+            None => *self.nft_weights.get(nft_type).unwrap_or(&0),
+        };
+        weight as u128
+    }
+
+    // Collection of a namespaced type, with the type within the collection
+    fn internal_type_collection<'a>(&self, nft_type: &'a str) -> Option<(&NftCollection, &'a str)> {
+        let (nft_contract, collection_type) = nft_type.split_once(':')?;
+        let collection = self.collections.get(&nft_contract.parse::<AccountId>().ok()?)?;
+        Some((collection, collection_type))
+    }
+
+    fn internal_collection_multiplier_bps(&self, nft_type: &str) -> u128 {
+        self.internal_type_collection(nft_type)
+            .map_or(BPS_DENOMINATOR, |(collection, _)| {
+                collection.weight_multiplier_bps as u128
+            })
+    }
+
+    // Type and weight of each of the record's NFTs that carries weight; blacklisted tokens don't.
+    // NFTs staked with a rarity score are weighted by it instead of their type, and the score is
+    // not scaled by scarcity weights, which only apply to type weights. Weights of other
+    // collections are scaled by the collection's multiplier.
+    fn internal_nft_weights<'a>(
+        &'a self,
+        stake: &'a NFTStakingRecord,
+        scarcity: bool,
+    ) -> impl Iterator<Item = (&'a String, u128)> + 'a {
+        stake
+            .nft_types
+            .iter()
+            .filter(|(token_id, _)| !self.token_blacklist.contains(token_id))
+            .map(move |(token_id, nft_type)| {
+                let weight = match stake.rarity_scores.get(token_id) {
+                    Some(score) => *score as u128,
+                    None => self.internal_type_weight(nft_type, scarcity),
+                };
+                let multiplier_bps = self.internal_collection_multiplier_bps(nft_type);
+                (nft_type, mul_div(weight, multiplier_bps, BPS_DENOMINATOR))
+            })
+    }
+
+    // Approved operators can claim for a staker by passing `staker_id`; rewards go to the staker.
+    // Stakers can route the payout to another wallet with `receiver_id`. `amount` claims only part
+    // of the record's SIN rewards and leaves the rest on it.
+    pub fn claim_reward(
+        &mut self,
+        stake_index: u64,
+        staker_id: Option<AccountId>,
+        receiver_id: Option<AccountId>,
+        amount: Option<U128>,
+    ) {
+        self.assert_operation_active(Operation::Claims);
+        let staker_id = self.internal_acting_staker(staker_id);
+        let receiver_id = self.internal_claim_receiver(&staker_id, receiver_id);
+        self.assert_not_blacklisted(&staker_id);
+        self.assert_not_blacklisted(&receiver_id);
+        self.internal_checkpoint_staker(&staker_id);
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+        self.internal_start_claim(&mut staker_info);
+
+        assert!(
+            stake_index < staker_info.stakes.len(),
+            "Invalid staking record index"
+        );
+
+        let mut stake = staker_info.stakes.get(stake_index).unwrap();
+        let claimable = stake.claimable_rewards(self.internal_vesting_now());
+        let rewards_to_claim = match amount {
+            Some(amount) => {
+                assert!(amount.0 > 0, "Claim amount must be greater than zero");
+                assert!(
+                    amount.0 <= claimable,
+                    "Claim amount exceeds the record's vested rewards"
+                );
+                amount.0
+            }
+            None => claimable,
+        };
+        // NEAR rewards are credited per staker, so any claim pays them out
+        let near_to_claim = std::mem::take(&mut staker_info.near_reward.unclaimed);
+
+        assert!(
+            rewards_to_claim > 0 || near_to_claim > 0,
+            "No rewards available to claim"
+        );
+        self.assert_min_claim(rewards_to_claim);
+
+        stake.claimed_rewards -= rewards_to_claim;
+        stake.last_claim = env::block_timestamp();
+        self.pending_claims_total -= rewards_to_claim;
+        let fee = self.internal_claim_fee(rewards_to_claim);
+        staker_info.total_rewards_claimed += rewards_to_claim - fee;
+        staker_info.stakes.replace(stake_index, &stake);
+        self.stakers.insert(&staker_id, &staker_info);
+
+        self.internal_transfer_near_reward(&staker_id, &receiver_id, near_to_claim);
+        let mut claim = ClaimRecord::new(rewards_to_claim - fee, Some(stake_index));
+        claim.receiver_id = Some(receiver_id).filter(|receiver_id| *receiver_id != staker_id);
+        self.internal_pay_sin_claim(&staker_id, rewards_to_claim, fee, claim);
+    }
+
+    // Claims a record's SIN rewards into a contract with `ft_transfer_call`, e.g. to deposit them
+    // into a DEX or locker. Whatever the receiver does not use is credited back to the staker.
+    pub fn claim_and_call(&mut self, stake_index: u64, receiver_id: AccountId, msg: String) -> Promise {
+        self.assert_operation_active(Operation::Claims);
+        let staker_id = env::predecessor_account_id();
+        self.assert_not_blacklisted(&staker_id);
+        self.assert_not_blacklisted(&receiver_id);
+        self.internal_checkpoint_staker(&staker_id);
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+        self.internal_start_claim(&mut staker_info);
+
+        assert!(
+            stake_index < staker_info.stakes.len(),
+            "Invalid staking record index"
+        );
+
+        let mut stake = staker_info.stakes.get(stake_index).unwrap();
+        let rewards_to_claim = stake.claimable_rewards(self.internal_vesting_now());
+        assert!(rewards_to_claim > 0, "No rewards available to claim");
+        self.assert_min_claim(rewards_to_claim);
+
+        stake.claimed_rewards -= rewards_to_claim;
+        stake.last_claim = env::block_timestamp();
+        self.pending_claims_total -= rewards_to_claim;
+        let fee = self.internal_claim_fee(rewards_to_claim);
+        staker_info.total_rewards_claimed += rewards_to_claim - fee;
+        staker_info.stakes.replace(stake_index, &stake);
+        self.stakers.insert(&staker_id, &staker_info);
+
+        let payout = self.internal_pay_claim_fee(&staker_id, rewards_to_claim, fee);
+        let mut claim = ClaimRecord::new(payout, Some(stake_index));
+        claim.receiver_id = Some(receiver_id.clone());
+
+        self.sin_in_flight += payout;
+        Promise::new(self.sin_token.clone())
+            .function_call(
+                "ft_transfer_call".to_string(),
+                serde_json::to_vec(&json!({
+                    "receiver_id": receiver_id,
+                    "amount": U128(payout),
+                    "msg": msg,
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(1), // Attach 1 yoctoNEAR
+                GAS_FOR_FT_TRANSFER_CALL,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .claim_and_call_callback(staker_id, U128(payout), claim),
+            )
+    }
+
+    // Credits back what the receiver refunded, or everything if the transfer failed. Returns the
+    // amount actually transferred.
+    #[private]
+    pub fn claim_and_call_callback(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        claim: ClaimRecord,
+        #[callback_result] used: Result<U128, PromiseError>,
+    ) -> U128 {
+        self.sin_in_flight -= amount.0;
+        let used = used.map_or(0, |used| used.0.min(amount.0));
+        let refund = amount.0 - used;
+        let Some(mut staker_info) = self.stakers.get(&account_id) else {
+            self.reward_distribution.total_reward_pool += refund;
+            return U128(used);
+        };
+
+        let initial_storage = env::storage_usage();
+        if used > 0 {
+            staker_info.claims.push(&ClaimRecord {
+                amount: U128(used),
+                ..claim
+            });
+        }
+        if refund > 0 {
+            staker_info.unclaimed_rewards += refund;
+            self.pending_claims_total += refund;
+            staker_info.total_rewards_claimed =
+                staker_info.total_rewards_claimed.saturating_sub(refund);
+            StakingEvent::ClaimRestored {
+                account_id: account_id.clone(),
+                amount: U128(refund),
+            }
+            .emit();
+        }
+        self.stakers.insert(&account_id, &staker_info);
+        // Recording must not fail, so the account may temporarily exceed its deposit
+        self.internal_update_storage(&account_id, initial_storage, false);
+        U128(used)
+    }
+
+    pub fn claim_all_rewards(&mut self, receiver_id: Option<AccountId>) {
+        self.assert_operation_active(Operation::Claims);
+        let staker_id = env::predecessor_account_id();
+        let receiver_id = self.internal_claim_receiver(&staker_id, receiver_id);
+        self.assert_not_blacklisted(&staker_id);
+        self.assert_not_blacklisted(&receiver_id);
+        self.internal_checkpoint_staker(&staker_id);
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+        self.internal_start_claim(&mut staker_info);
+
+        let vesting_now = self.internal_vesting_now();
+        let locked_rewards = staker_info.locked_rewards(vesting_now);
+        let mut rewards_to_claim = staker_info.unclaimed_rewards - locked_rewards;
+        staker_info.unclaimed_rewards = locked_rewards;
+        for i in 0..staker_info.stakes.len() {
+            let mut stake = staker_info.stakes.get(i).unwrap();
+            let claimable = stake.claimable_rewards(vesting_now);
+            rewards_to_claim += claimable;
+            stake.claimed_rewards -= claimable;
+            stake.last_claim = env::block_timestamp();
+            staker_info.stakes.replace(i, &stake);
+        }
+
+        let near_to_claim = std::mem::take(&mut staker_info.near_reward.unclaimed);
+
+        assert!(
+            rewards_to_claim > 0 || near_to_claim > 0,
+            "No rewards available to claim"
+        );
+        self.assert_min_claim(rewards_to_claim);
+
+        self.pending_claims_total -= rewards_to_claim;
+        let fee = self.internal_claim_fee(rewards_to_claim);
+        staker_info.total_rewards_claimed += rewards_to_claim - fee;
+        self.stakers.insert(&staker_id, &staker_info);
+
+        self.internal_transfer_near_reward(&staker_id, &receiver_id, near_to_claim);
+        let mut claim = ClaimRecord::new(rewards_to_claim - fee, None);
+        claim.receiver_id = Some(receiver_id).filter(|receiver_id| *receiver_id != staker_id);
+        self.internal_pay_sin_claim(&staker_id, rewards_to_claim, fee, claim);
+    }
+
+    fn assert_min_claim(&self, amount: Balance) {
+        assert!(
+            amount == 0 || amount >= self.min_claim_amount,
+            "Claims must be at least {} SIN",
+            self.min_claim_amount
+        );
+    }
+
+    // Enforces the claim cooldown and starts a new one
+    fn internal_start_claim(&self, staker_info: &mut StakerInfo) {
+        let now = env::block_timestamp();
+        assert!(
+            staker_info.last_claim == 0
+                || now >= staker_info.last_claim + self.claim_cooldown * 1_000_000_000,
+            "Claim cooldown has not elapsed, next claim possible at {}",
+            staker_info.last_claim + self.claim_cooldown * 1_000_000_000
+        );
+        staker_info.last_claim = now;
+    }
+
+    // Only the staker itself can send its rewards elsewhere, operators always pay the staker.
+    // Transfers that fail are credited back to the staker, whatever the receiver.
+    fn internal_claim_receiver(
+        &self,
+        staker_id: &AccountId,
+        receiver_id: Option<AccountId>,
+    ) -> AccountId {
+        match receiver_id {
+            Some(receiver_id) if receiver_id != *staker_id => {
+                assert_eq!(
+                    env::predecessor_account_id(),
+                    *staker_id,
+                    "Only the staker can choose another receiver"
+                );
+                assert!(
+                    receiver_id != env::current_account_id() && receiver_id != self.sin_token,
+                    "Rewards cannot be sent to the staking or SIN token contract"
+                );
+                receiver_id
+            }
+            _ => staker_id.clone(),
+        }
+    }
+
+    // Treasury fee on a SIN claim of `amount`
+    fn internal_claim_fee(&self, amount: Balance) -> Balance {
+        if self.treasury.is_none() {
+            return 0;
+        }
+        mul_div(amount, self.claim_fee_bps as u128, BPS_DENOMINATOR)
+    }
+
+    // Sends a claim of `amount` to the staker (or the claim's receiver), less the `fee` sent to the
+    // treasury. `claim` is recorded in the staker's history once the transfer succeeds.
+    fn internal_pay_sin_claim(
+        &mut self,
+        staker_id: &AccountId,
+        amount: Balance,
+        fee: Balance,
+        claim: ClaimRecord,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        let payout = self.internal_pay_claim_fee(staker_id, amount, fee);
+        if payout > 0 {
+            self.internal_transfer_sin(staker_id, payout, Some(claim));
+        }
+    }
+
+    // Sends the treasury fee of a SIN claim and returns what is left for the staker
+    fn internal_pay_claim_fee(
+        &mut self,
+        staker_id: &AccountId,
+        amount: Balance,
+        fee: Balance,
+    ) -> Balance {
+        if let Some(treasury) = self.treasury.clone().filter(|_| fee > 0) {
+            StakingEvent::ClaimFee {
+                account_id: staker_id.clone(),
+                treasury: treasury.clone(),
+                amount: U128(fee),
+            }
+            .emit();
+            self.internal_transfer_sin(&treasury, fee, None);
+        }
+        let payout = amount - fee;
+        if payout > 0 {
+            StakingEvent::Claim {
+                account_id: staker_id.clone(),
+                amount: U128(payout),
+            }
+            .emit();
+        }
+        payout
+    }
+
+    fn internal_transfer_near_reward(
+        &mut self,
+        staker_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        self.near_rewards_claimed += amount;
+        StakingEvent::NearClaim {
+            account_id: staker_id.clone(),
+            amount: U128(amount),
+        }
+        .emit();
+        Promise::new(receiver_id.clone())
+            .transfer(NearToken::from_yoctonear(amount))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .near_claim_callback(staker_id.clone(), U128(amount)),
+            );
+    }
+
+    // Credits back NEAR rewards whose transfer bounced, e.g. to an account that doesn't exist
+    #[private]
+    pub fn near_claim_callback(&mut self, account_id: AccountId, amount: U128) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        self.near_rewards_claimed -= amount.0;
+        if let Some(mut staker_info) = self.stakers.get(&account_id) {
+            staker_info.near_reward.unclaimed += amount.0;
+            self.stakers.insert(&account_id, &staker_info);
+        } else {
+            // Nobody is owed it anymore, so it goes back to the NEAR pool
+            self.near_rewards.total_distributed -= amount.0;
+            self.near_rewards.distribution.total_reward_pool += amount.0;
+        }
+        StakingEvent::NearClaimRestored {
+            account_id: account_id.clone(),
+            amount,
+        }
+        .emit();
+        env::log_str(&format!(
+            "Transfer of {} yoctoNEAR for {} failed, amount restored",
+            amount.0, account_id
+        ));
+        false
+    }
+
+    // Funds the NEAR reward pool with the attached deposit, labelled with an optional memo
+    #[payable]
+    pub fn fund_near(&mut self, memo: Option<String>) {
+        self.assert_role(Role::Treasurer);
+        assert!(!self.sunset, "Funding is closed during sunset");
+        let amount = env::attached_deposit().as_yoctonear();
+        assert!(amount > 0, "Funding amount must be greater than zero");
+        if let Some(memo) = memo.as_ref() {
+            assert!(
+                memo.len() <= MAX_MEMO_LENGTH,
+                "Memo cannot exceed {} bytes",
+                MAX_MEMO_LENGTH
+            );
+        }
+        self.near_rewards.distribution.total_reward_pool += amount;
+        self.near_rewards.distribution.funding_records.push(&FundingRecord {
+            amount,
+            timestamp: env::block_timestamp(),
+            sender_id: env::predecessor_account_id(),
+            memo,
+        });
+
+        StakingEvent::NearFund {
+            sender_id: env::predecessor_account_id(),
+            amount: U128(amount),
+        }
+        .emit();
+    }
+
+    // Distributes `amount` of NEAR to the stakers pro rata to their emission weights, paid out
+    // with their next claim
+    pub fn distribute_near_rewards(&mut self, amount: U128) {
+        self.assert_role(Role::Distributor);
+        self.assert_operation_active(Operation::Distribution);
+        assert!(amount.0 > 0, "Distribution amount must be greater than zero");
+        assert!(
+            amount.0 <= self.near_rewards.distribution.total_reward_pool,
+            "Insufficient reward pool"
+        );
+        assert!(self.emission.total_weight > 0, "No stakers to distribute to");
+
+        self.near_rewards.distribution.total_reward_pool -= amount.0;
+        self.near_rewards.distribution.last_distributed = env::block_timestamp();
+        self.near_rewards.accrue(amount.0, self.emission.total_weight);
+        self.near_rewards.total_distributed += amount.0;
+
+        StakingEvent::NearDistribution {
+            amount,
+            total_weight: U128(self.emission.total_weight),
+        }
+        .emit();
+    }
+
+    pub fn add_campaign_sponsor(&mut self, account_id: AccountId, token_id: AccountId) {
+        self.assert_role(Role::Treasurer);
+        let sponsor = CampaignSponsor {
+            account_id,
+            token_id,
+        };
+        assert!(
+            !self.campaign_sponsors.contains(&sponsor),
+            "Sponsor is already registered for this token"
+        );
+        self.campaign_sponsors.push(sponsor.clone());
+        StakingEvent::ConfigUpdate {
+            parameter: "campaign_sponsor".to_string(),
+            value: json!(sponsor),
+        }
+        .emit();
+    }
+
+    pub fn remove_campaign_sponsor(&mut self, account_id: AccountId, token_id: AccountId) {
+        self.assert_role(Role::Treasurer);
+        let sponsor = CampaignSponsor {
+            account_id,
+            token_id,
+        };
+        let sponsor_count = self.campaign_sponsors.len();
+        self.campaign_sponsors.retain(|approved| *approved != sponsor);
+        assert!(self.campaign_sponsors.len() < sponsor_count, "Sponsor not found");
+        StakingEvent::ConfigUpdate {
+            parameter: "campaign_sponsor_removed".to_string(),
+            value: json!(sponsor),
+        }
+        .emit();
+    }
+
+    pub fn get_campaign_sponsors(&self) -> Vec<CampaignSponsor> {
+        self.campaign_sponsors.clone()
+    }
+
+    fn internal_create_campaign(
+        &mut self,
+        sponsor: AccountId,
+        token_id: AccountId,
+        amount: Balance,
+        terms: CampaignMsg,
+    ) {
+        // The token is the predecessor, so only the approved token contract can vouch for it
+        assert!(
+            self.campaign_sponsors.iter().any(|approved| {
+                approved.account_id == sponsor && approved.token_id == token_id
+            }),
+            "Only approved sponsors can open a bonus farm in this token"
+        );
+        assert!(amount > 0, "Funding amount must be greater than zero");
+        assert!(
+            (DAY..=YEAR).contains(&terms.duration),
+            "Campaign duration must be between {} and {} seconds",
+            DAY,
+            YEAR
+        );
+        assert!(
+            self.campaigns.len() < MAX_CAMPAIGNS,
+            "Cannot run more than {} campaigns, close a finished one first",
+            MAX_CAMPAIGNS
+        );
+        let now = env::block_timestamp();
+        let start = terms.start.map_or(now, |start| start.0.max(now));
+        let end = start + terms.duration * 1_000_000_000;
+
+        let id = self.next_campaign_id;
+        self.next_campaign_id += 1;
+        self.campaigns.insert(
+            &id,
+            &Campaign {
+                id,
+                sponsor: sponsor.clone(),
+                token_id: token_id.clone(),
+                total_reward: amount,
+                start,
+                end,
+                acc_reward_per_weight: 0,
+                last_update: start,
+                claimed: 0,
+                streamed: 0,
+            },
+        );
+
+        StakingEvent::CampaignCreated {
+            id,
+            sponsor,
+            token_id,
+            amount: U128(amount),
+            start: U64(start),
+            end: U64(end),
+        }
+        .emit();
+    }
+
+    // Adds to the reward of a campaign that has not ended, streaming over its remaining time
+    fn internal_top_up_campaign(
+        &mut self,
+        sponsor: AccountId,
+        token_id: AccountId,
+        amount: Balance,
+        campaign_id: u64,
+    ) {
+        assert!(amount > 0, "Funding amount must be greater than zero");
+        let campaign = self.campaigns.get(&campaign_id).expect("Campaign not found");
+        assert!(
+            campaign.sponsor == sponsor && campaign.token_id == token_id,
+            "Only the campaign's sponsor can top it up, in the campaign's token"
+        );
+        assert!(env::block_timestamp() < campaign.end, "Campaign has ended");
+        self.internal_update_campaigns();
+        let mut campaign = self.campaigns.get(&campaign_id).unwrap();
+        campaign.total_reward += amount;
+        self.campaigns.insert(&campaign_id, &campaign);
+
+        StakingEvent::CampaignToppedUp {
+            id: campaign_id,
+            amount: U128(amount),
+        }
+        .emit();
+    }
+
+    pub fn get_campaigns(&self) -> Vec<CampaignView> {
+        self.campaigns
+            .values()
+            .map(|campaign| CampaignView {
+                id: campaign.id,
+                sponsor: campaign.sponsor,
+                token_id: campaign.token_id,
+                total_reward: U128(campaign.total_reward),
+                start: U64(campaign.start),
+                end: U64(campaign.end),
+                claimed: U128(campaign.claimed),
+            })
+            .collect()
+    }
+
+    // Bonus farm rewards of the account, including what has streamed since its last checkpoint
+    pub fn get_campaign_rewards(&self, account_id: AccountId) -> Vec<CampaignRewardView> {
+        let Some(staker_info) = self.stakers.get(&account_id) else {
+            return vec![];
+        };
+        let now = env::block_timestamp();
+        self.campaigns
+            .values()
+            .map(|campaign| {
+                let (unclaimed, acc) = staker_info
+                    .campaign_rewards
+                    .get(&campaign.id)
+                    .map_or((0, 0), |reward| (reward.unclaimed, reward.acc_reward_per_weight));
+                let unsettled = mul_div(
+                    staker_info.emission_weight,
+                    self.internal_campaign_acc(&campaign, now).0 - acc,
+                    EMISSION_PRECISION,
+                );
+                CampaignRewardView {
+                    campaign_id: campaign.id,
+                    token_id: campaign.token_id,
+                    amount: U128(unclaimed + unsettled),
+                }
+            })
+            .collect()
+    }
+
+    pub fn claim_campaign_rewards(&mut self, campaign_id: u64) -> Promise {
+        self.assert_operation_active(Operation::Claims);
+        let staker_id = env::predecessor_account_id();
+        self.assert_not_blacklisted(&staker_id);
+        self.internal_checkpoint_staker(&staker_id);
+        let mut campaign = self.campaigns.get(&campaign_id).expect("Campaign not found");
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+        let amount = staker_info
+            .campaign_rewards
+            .get_mut(&campaign_id)
+            .map_or(0, |reward| std::mem::take(&mut reward.unclaimed));
+        assert!(amount > 0, "No rewards to claim");
+        self.stakers.insert(&staker_id, &staker_info);
+        campaign.claimed += amount;
+        self.campaigns.insert(&campaign_id, &campaign);
+
+        StakingEvent::CampaignClaim {
+            account_id: staker_id.clone(),
+            campaign_id,
+            amount: U128(amount),
+        }
+        .emit();
+
+        if campaign.token_id == self.sin_token {
+            self.sin_in_flight += amount;
+        }
+        Promise::new(campaign.token_id.clone())
+            .function_call(
+                "ft_transfer".to_string(),
+                serde_json::to_vec(&json!({
+                    "receiver_id": staker_id,
+                    "amount": U128(amount),
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(1), // Attach 1 yoctoNEAR
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .claim_campaign_callback(
+                        staker_id,
+                        campaign_id,
+                        campaign.token_id,
+                        U128(amount),
+                    ),
+            )
+    }
+
+    // Restores the farm rewards of a failed ft_transfer. Returns the amount actually transferred.
+    #[private]
+    pub fn claim_campaign_callback(
+        &mut self,
+        receiver_id: AccountId,
+        campaign_id: u64,
+        token_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        if token_id == self.sin_token {
+            self.sin_in_flight -= amount.0;
+        }
+        if is_promise_success() {
+            return amount;
+        }
+
+        // A campaign closed in the meantime already refunded the amount to its sponsor
+        if let (Some(mut campaign), Some(mut staker_info)) =
+            (self.campaigns.get(&campaign_id), self.stakers.get(&receiver_id))
+        {
+            staker_info
+                .campaign_rewards
+                .entry(campaign_id)
+                .or_default()
+                .unclaimed += amount.0;
+            self.stakers.insert(&receiver_id, &staker_info);
+            campaign.claimed -= amount.0;
+            self.campaigns.insert(&campaign_id, &campaign);
+        }
+        env::log_str(&format!(
+            "Transfer of {} from campaign {} to {} failed, amount restored",
+            amount.0, campaign_id, receiver_id
+        ));
+        U128(0)
+    }
+
+    // Removes a finished campaign once its claim window has passed and returns whatever was not
+    // claimed to the sponsor
+    pub fn close_campaign(&mut self, campaign_id: u64) {
+        let campaign = self.campaigns.get(&campaign_id).expect("Campaign not found");
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.owner || caller == campaign.sponsor,
+            "Only the owner or the sponsor can close a campaign"
+        );
+        assert!(
+            env::block_timestamp() >= campaign.end + CAMPAIGN_CLAIM_WINDOW * 1_000_000_000,
+            "Campaign claim window has not ended"
+        );
+        self.campaigns.remove(&campaign_id);
+
+        let refund = campaign.total_reward - campaign.claimed;
+        StakingEvent::CampaignClosed {
+            id: campaign_id,
+            refund: U128(refund),
+        }
+        .emit();
+        if refund > 0 {
+            Promise::new(campaign.token_id).function_call(
+                "ft_transfer".to_string(),
+                serde_json::to_vec(&json!({
+                    "receiver_id": campaign.sponsor,
+                    "amount": U128(refund),
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(1), // Attach 1 yoctoNEAR
+                GAS_FOR_FT_TRANSFER,
+            );
+        }
+    }
+
+    // NEAR held beyond what the contract needs: its storage staking, the accounts' storage
+    // deposits, the NEAR reward pool and NEAR rewards owed to stakers. This is NEAR attached to
+    // payable methods by mistake or sent to the account directly.
+    pub fn get_withdrawable_near(&self) -> U128 {
+        let storage_cost =
+            env::storage_byte_cost().as_yoctonear() * env::storage_usage() as Balance;
+        let owed_rewards = self.near_rewards.total_distributed - self.near_rewards_claimed;
+        let reserved = storage_cost
+            + self.total_storage_deposits
+            + self.near_rewards.distribution.total_reward_pool
+            + owed_rewards;
+        U128(env::account_balance().as_yoctonear().saturating_sub(reserved))
+    }
+
+    pub fn withdraw_near(&mut self, amount: U128, receiver_id: AccountId) -> Promise {
+        self.assert_role(Role::Owner);
+        assert!(amount.0 > 0, "Withdrawal amount must be greater than zero");
+        let withdrawable = self.get_withdrawable_near().0;
+        assert!(
+            amount.0 <= withdrawable,
+            "Only {} yoctoNEAR can be withdrawn",
+            withdrawable
+        );
+        StakingEvent::NearWithdrawal {
+            receiver_id: receiver_id.clone(),
+            amount,
+        }
+        .emit();
+        Promise::new(receiver_id).transfer(NearToken::from_yoctonear(amount.0))
+    }
+
+    pub fn get_near_reward_pool(&self) -> U128 {
+        U128(self.near_rewards.distribution.total_reward_pool)
+    }
+
+    // Unclaimed NEAR rewards of the account
+    pub fn get_near_rewards(&self, account_id: AccountId) -> U128 {
+        let Some(staker_info) = self.stakers.get(&account_id) else {
+            return U128(0);
+        };
+        let reward = &staker_info.near_reward;
+        U128(
+            reward.unclaimed
+                + mul_div(
+                    staker_info.emission_weight,
+                    self.near_rewards.acc_reward_per_weight - reward.acc_reward_per_weight,
+                    EMISSION_PRECISION,
+                ),
+        )
+    }
+
+    // Sends SIN to `account_id`, or to the claim's receiver. Transfers of staking rewards pass
+    // their claim record so that successful ones land in the account's claim history, and failed
+    // ones are credited back to the account. Any other failed transfer goes back to the pool.
+    fn internal_transfer_sin(
+        &mut self,
+        account_id: &AccountId,
+        amount: Balance,
+        claim: Option<ClaimRecord>,
+    ) -> Promise {
+        self.sin_in_flight += amount;
+        let receiver_id = claim
+            .as_ref()
+            .and_then(|claim| claim.receiver_id.clone())
+            .unwrap_or_else(|| account_id.clone());
+        Promise::new(self.sin_token.clone())
+            .function_call(
+                "ft_transfer".to_string(),
+                serde_json::to_vec(&json!({
+                    "receiver_id": receiver_id,
+                    "amount": U128(amount),
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(1), // Attach 1 yoctoNEAR
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .claim_reward_callback(account_id.clone(), U128(amount), claim),
+            )
+    }
+
+    // Restores the rewards of a failed ft_transfer. Returns the amount actually transferred.
+    #[private]
+    pub fn claim_reward_callback(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        claim: Option<ClaimRecord>,
+    ) -> U128 {
+        self.sin_in_flight -= amount.0;
+        if is_promise_success() {
+            if let (Some(claim), Some(mut staker_info)) = (claim, self.stakers.get(&account_id)) {
+                let initial_storage = env::storage_usage();
+                staker_info.claims.push(&claim);
+                // The vector's length lives in the staker record, so it must be saved as well
+                self.stakers.insert(&account_id, &staker_info);
+                // Recording must not fail, so the account may temporarily exceed its deposit
+                self.internal_update_storage(&account_id, initial_storage, false);
+            }
+            return amount;
+        }
+
+        let staker_info = claim.and_then(|_| self.stakers.get(&account_id));
+        if let Some(mut staker_info) = staker_info {
+            staker_info.unclaimed_rewards += amount.0;
+            self.pending_claims_total += amount.0;
+            staker_info.total_rewards_claimed =
+                staker_info.total_rewards_claimed.saturating_sub(amount.0);
+            self.stakers.insert(&account_id, &staker_info);
+        } else {
+            // Not a claim (e.g. a keeper bounty or a fee), so the funds go back to the pool
+            self.reward_distribution.total_reward_pool += amount.0;
+        }
+        StakingEvent::ClaimRestored {
+            account_id: account_id.clone(),
+            amount,
+        }
+        .emit();
+        env::log_str(&format!(
+            "Transfer of {} SIN for {} failed, amount restored",
+            amount.0, account_id
+        ));
+        U128(0)
+    }
+
+    // Registers a NEP-141 token that can fund and be distributed alongside SIN
+    pub fn add_reward_token(&mut self, token_id: AccountId) {
+        self.assert_role(Role::Owner);
+        assert!(token_id != self.sin_token, "SIN is always a reward token");
+        assert!(
+            self.reward_tokens.get(&token_id).is_none(),
+            "Reward token is already registered"
+        );
+        assert!(
+            self.reward_tokens.len() < MAX_REWARD_TOKENS,
+            "Cannot register more than {} reward tokens",
+            MAX_REWARD_TOKENS
+        );
+        let prefix = format!("funding_{}", token_id);
+        self.reward_tokens.insert(
+            &token_id,
+            &TokenRewards {
+                distribution: RewardDistribution {
+                    total_reward_pool: 0,
+                    last_distributed: 0,
+                    funding_records: FundingLog::new(prefix.as_bytes().to_vec()),
+                },
+                acc_reward_per_weight: 0,
+                acc_remainder: 0,
+                total_distributed: 0,
+            },
+        );
+        StakingEvent::ConfigUpdate {
+            parameter: "reward_token".to_string(),
+            value: json!(token_id),
+        }
+        .emit();
+    }
+
+    pub fn get_reward_tokens(&self) -> Vec<RewardTokenView> {
+        self.reward_tokens
+            .iter()
+            .map(|(token_id, token_rewards)| RewardTokenView {
+                token_id,
+                reward_pool: U128(token_rewards.distribution.total_reward_pool),
+                total_distributed: U128(token_rewards.total_distributed),
+                last_distributed: U64(token_rewards.distribution.last_distributed),
+            })
+            .collect()
+    }
+
+    fn internal_fund_token(
+        &mut self,
+        token_id: &AccountId,
+        sender_id: &AccountId,
+        amount: Balance,
+        memo: Option<String>,
+    ) {
+        let mut token_rewards = self.reward_tokens.get(token_id).unwrap();
+        token_rewards.distribution.total_reward_pool += amount;
+        token_rewards.distribution.funding_records.push(&FundingRecord {
+            amount,
+            timestamp: env::block_timestamp(),
+            sender_id: sender_id.clone(),
+            memo,
+        });
+        self.reward_tokens.insert(token_id, &token_rewards);
+
+        StakingEvent::TokenFund {
+            token_id: token_id.clone(),
+            sender_id: sender_id.clone(),
+            amount: U128(amount),
+        }
+        .emit();
+    }
+
+    // Distributes `amount` of a reward token to the stakers pro rata to their emission weights.
+    // Unlike SIN rounds this completes in one call; stakers are credited when next checkpointed.
+    pub fn distribute_token_rewards(&mut self, token_id: AccountId, amount: U128) {
+        self.assert_role(Role::Distributor);
+        self.assert_operation_active(Operation::Distribution);
+        let mut token_rewards = self.reward_tokens.get(&token_id).expect("Reward token not found");
+        assert!(amount.0 > 0, "Distribution amount must be greater than zero");
+        assert!(
+            amount.0 <= token_rewards.distribution.total_reward_pool,
+            "Insufficient reward pool"
+        );
+        assert!(self.emission.total_weight > 0, "No stakers to distribute to");
+
+        token_rewards.distribution.total_reward_pool -= amount.0;
+        token_rewards.distribution.last_distributed = env::block_timestamp();
+        token_rewards.accrue(amount.0, self.emission.total_weight);
+        token_rewards.total_distributed += amount.0;
+        self.reward_tokens.insert(&token_id, &token_rewards);
+
+        StakingEvent::TokenDistribution {
+            token_id,
+            amount,
+            total_weight: U128(self.emission.total_weight),
+        }
+        .emit();
+    }
+
+    // Unclaimed rewards of the account in every registered token other than SIN
+    pub fn get_token_rewards(&self, account_id: AccountId) -> Vec<TokenRewardView> {
+        let Some(staker_info) = self.stakers.get(&account_id) else {
+            return vec![];
+        };
+        self.reward_tokens
+            .iter()
+            .map(|(token_id, token_rewards)| {
+                let (unclaimed, acc) = staker_info
+                    .token_rewards
+                    .get(&token_id)
+                    .map_or((0, 0), |reward| (reward.unclaimed, reward.acc_reward_per_weight));
+                let unsettled = mul_div(
+                    staker_info.emission_weight,
+                    token_rewards.acc_reward_per_weight - acc,
+                    EMISSION_PRECISION,
+                );
+                TokenRewardView {
+                    token_id,
+                    amount: U128(unclaimed + unsettled),
+                }
+            })
+            .collect()
+    }
+
+    pub fn claim_token_rewards(&mut self, token_id: AccountId) -> Promise {
+        self.assert_operation_active(Operation::Claims);
+        assert!(self.reward_tokens.get(&token_id).is_some(), "Reward token not found");
+        let staker_id = env::predecessor_account_id();
+        self.assert_not_blacklisted(&staker_id);
+        self.internal_checkpoint_staker(&staker_id);
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+        let amount = staker_info
+            .token_rewards
+            .get_mut(&token_id)
+            .map_or(0, |reward| std::mem::take(&mut reward.unclaimed));
+        assert!(amount > 0, "No rewards to claim");
+        self.stakers.insert(&staker_id, &staker_info);
+
+        StakingEvent::TokenClaim {
+            account_id: staker_id.clone(),
+            token_id: token_id.clone(),
+            amount: U128(amount),
+        }
+        .emit();
+
+        Promise::new(token_id.clone())
+            .function_call(
+                "ft_transfer".to_string(),
+                serde_json::to_vec(&json!({
+                    "receiver_id": staker_id,
+                    "amount": U128(amount),
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(1), // Attach 1 yoctoNEAR
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .claim_token_callback(staker_id, token_id, U128(amount)),
+            )
+    }
+
+    // Restores the token rewards of a failed ft_transfer. Returns the amount actually transferred.
+    #[private]
+    pub fn claim_token_callback(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        if is_promise_success() {
+            return amount;
+        }
+
+        if let Some(mut staker_info) = self.stakers.get(&receiver_id) {
+            staker_info
+                .token_rewards
+                .entry(token_id.clone())
+                .or_default()
+                .unclaimed += amount.0;
+            self.stakers.insert(&receiver_id, &staker_info);
+        } else if let Some(mut token_rewards) = self.reward_tokens.get(&token_id) {
+            token_rewards.distribution.total_reward_pool += amount.0;
+            self.reward_tokens.insert(&token_id, &token_rewards);
+        }
+        StakingEvent::TokenClaimRestored {
+            account_id: receiver_id.clone(),
+            token_id: token_id.clone(),
+            amount,
+        }
+        .emit();
+        env::log_str(&format!(
+            "Transfer of {} {} to {} failed, amount restored",
+            amount.0, token_id, receiver_id
+        ));
+        U128(0)
+    }
+
+    // Unstaking before the lockup ends requires `accept_penalty`, forfeiting part of the pending
+    // rewards to the reward pool. During sunset or an emergency shutdown lockups are waived.
+    // Rewards that have not vested yet keep unlocking on schedule and are claimed with
+    // claim_all_rewards, as are all of the record's rewards while claims are paused.
+    pub fn unstake_nfts(
+        &mut self,
+        stake_index: u64,
+        accept_penalty: Option<bool>,
+        staker_id: Option<AccountId>,
+    ) {
+        self.assert_operation_active(Operation::Unstaking);
+        self.assert_no_active_distribution();
+        let staker_id = self.internal_acting_staker(staker_id);
+        self.assert_not_blacklisted(&staker_id);
+        self.internal_checkpoint_staker(&staker_id);
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+
+        assert!(
+            stake_index < staker_info.stakes.len(),
+            "Invalid staking record index"
+        );
+
+        let stake = staker_info.stakes.get(stake_index).unwrap();
+        stake.assert_not_tokenized();
+        let current_time = env::block_timestamp();
+        let vesting_now = self.internal_vesting_now();
+        let locked_rewards = stake.locked_rewards(vesting_now);
+        let vested_rewards = stake.claimed_rewards - locked_rewards;
+        let mut penalty = 0;
+        let mut exit_fee = 0;
+        if !self.is_staking_closed() && current_time < stake.unlock_timestamp(current_time) {
+            assert!(
+                accept_penalty.unwrap_or(false),
+                "Cannot unstake before lockup period without accepting the early unstake penalty"
+            );
+            penalty = mul_div(
+                vested_rewards,
+                self.internal_early_exit_penalty_bps(&stake, current_time),
+                BPS_DENOMINATOR,
+            );
+            exit_fee = mul_div(
+                staker_info.unclaimed_rewards,
+                self.early_unstake_fee_bps as u128,
+                BPS_DENOMINATOR,
+            );
+            staker_info.unclaimed_rewards -= exit_fee;
+            self.reward_distribution.total_reward_pool += penalty + exit_fee;
+        }
+
+        let initial_storage = env::storage_usage();
+        let mut pending_rewards = vested_rewards - penalty;
+        // Dust is kept for a later claim_all_rewards rather than transferred on its own, and so is
+        // everything while claims are paused
+        if pending_rewards < self.min_claim_amount || self.is_operation_paused(Operation::Claims) {
+            staker_info.unclaimed_rewards += pending_rewards;
+            pending_rewards = 0;
+        }
+        staker_info.add_locked_rewards(&stake, vesting_now);
+        // Whatever else was on the record is forfeited or paid out now
+        self.pending_claims_total -= penalty + exit_fee + pending_rewards;
+        let fee = self.internal_claim_fee(pending_rewards);
+        staker_info.stakes.swap_remove(stake_index);
+        staker_info.total_rewards_claimed += pending_rewards - fee;
+        self.stakers.insert(&staker_id, &staker_info);
+        for nft_id in stake.nft_ids.iter() {
+            self.internal_remove_staked_token(nft_id, &stake);
+        }
+        self.internal_update_storage(&staker_id, initial_storage, false);
+        self.internal_checkpoint_staker(&staker_id);
+
+        // Pay out rewards still sitting on the record so they are not lost with it
+        let claim = ClaimRecord::new(pending_rewards - fee, Some(stake_index));
+        self.internal_pay_sin_claim(&staker_id, pending_rewards, fee, claim);
+
+        StakingEvent::Unstake {
+            account_id: staker_id.clone(),
+            token_ids: stake.nft_ids.clone(),
+            rewards: U128(pending_rewards),
+            penalty: U128(penalty + exit_fee),
+        }
+        .emit();
+        for nft_id in stake.nft_ids.iter() {
+            self.internal_transfer_nft(&staker_id, nft_id, &stake);
+        }
+    }
+
+    // Withdraws one NFT of an unlocked record. The record keeps its pending rewards and its weight
+    // drops by the withdrawn NFT; withdrawing the last NFT unstakes the whole record.
+    pub fn unstake_token(&mut self, stake_index: u64, token_id: String) {
+        self.assert_operation_active(Operation::Unstaking);
+        self.assert_no_active_distribution();
+        let staker_id = env::predecessor_account_id();
+        self.assert_not_blacklisted(&staker_id);
+        self.internal_checkpoint_staker(&staker_id);
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+
+        assert!(
+            stake_index < staker_info.stakes.len(),
+            "Invalid staking record index"
+        );
+
+        let mut stake = staker_info.stakes.get(stake_index).unwrap();
+        stake.assert_not_tokenized();
+        assert!(
+            stake.nft_ids.contains(&token_id),
+            "NFT is not part of this staking record"
+        );
+        if stake.nft_ids.len() == 1 {
+            return self.unstake_nfts(stake_index, None, None);
+        }
+        assert!(
+            env::block_timestamp() >= stake.unlock_timestamp(env::block_timestamp()),
+            "Cannot unstake before lockup period"
+        );
+
+        let initial_storage = env::storage_usage();
+        let withdrawn = stake.clone();
+        stake.nft_ids.retain(|id| *id != token_id);
+        stake.nft_types.remove(&token_id);
+        stake.rarity_scores.remove(&token_id);
+        stake.nft_contracts.remove(&token_id);
+        stake.staked_at.remove(&token_id);
+        staker_info.stakes.replace(stake_index, &stake);
+        self.stakers.insert(&staker_id, &staker_info);
+        self.internal_remove_staked_token(&token_id, &withdrawn);
+        self.internal_update_storage(&staker_id, initial_storage, false);
+        self.internal_checkpoint_staker(&staker_id);
+
+        StakingEvent::Unstake {
+            account_id: staker_id.clone(),
+            token_ids: vec![token_id.clone()],
+            rewards: U128(0),
+            penalty: U128(0),
+        }
+        .emit();
+        self.internal_transfer_nft(&staker_id, &token_id, &withdrawn);
+    }
+
+    // Combines several records into one that keeps the latest unlock date among them. Returns the
+    // index of the merged record.
+    pub fn merge_stakes(&mut self, indices: Vec<u64>) -> u64 {
+        self.assert_operation_active(Operation::Staking);
+        // Merging changes record weights, which would skew a round that is being paid out
+        self.assert_no_active_distribution();
+        let staker_id = env::predecessor_account_id();
+        self.internal_checkpoint_staker(&staker_id);
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+
+        let mut indices = indices;
+        indices.sort_unstable();
+        indices.dedup();
+        assert!(indices.len() >= 2, "At least two distinct records are required to merge");
+        assert!(
+            *indices.last().unwrap() < staker_info.stakes.len(),
+            "Invalid staking record index"
+        );
+
+        let now = env::block_timestamp();
+        let mut stakes: Vec<NFTStakingRecord> = indices
+            .iter()
+            .map(|index| staker_info.stakes.get(*index).unwrap())
+            .collect();
+        stakes.iter().for_each(NFTStakingRecord::assert_not_tokenized);
+        // The record locked the longest provides the lockup of the merged record
+        stakes.sort_by_key(|stake| std::cmp::Reverse(stake.unlock_timestamp(now)));
+        let mut merged = stakes[0].clone();
+        merged.pin_staked_at();
+        for mut stake in stakes.into_iter().skip(1) {
+            stake.pin_staked_at();
+            merged.start_timestamp = self.internal_merged_start_timestamp(&merged, &stake);
+            // Both locked parts vest until the later of the two ends
+            merged.vesting_amount = merged.locked_rewards(now) + stake.locked_rewards(now);
+            merged.vesting_start = now;
+            merged.vesting_end = merged.vesting_end.max(stake.vesting_end);
+            merged.last_claim = merged.last_claim.max(stake.last_claim);
+            merged.nft_ids.extend(stake.nft_ids);
+            merged.nft_types.extend(stake.nft_types);
+            merged.rarity_scores.extend(stake.rarity_scores);
+            merged.nft_contracts.extend(stake.nft_contracts);
+            merged.staked_at.extend(stake.staked_at);
+            merged.claimed_rewards += stake.claimed_rewards;
+        }
+
+        let initial_storage = env::storage_usage();
+        // Removing from the highest index down keeps the remaining indices valid
+        for index in indices.iter().rev() {
+            staker_info.stakes.swap_remove(*index);
+        }
+        staker_info.stakes.push(&merged);
+        let merged_index = staker_info.stakes.len() - 1;
+        self.stakers.insert(&staker_id, &staker_info);
+        self.internal_update_storage(&staker_id, initial_storage, false);
+        self.internal_checkpoint_staker(&staker_id);
+
+        StakingEvent::StakesMerged {
+            account_id: staker_id,
+            token_ids: merged.nft_ids.clone(),
+            unlock_timestamp: U64(merged.unlock_timestamp(now)),
+        }
+        .emit();
+        merged_index
+    }
+
+    // Moves `token_ids` into a new record with the same timestamps and lockup. Pending rewards stay
+    // on the original record. Returns the index of the new record.
+    pub fn split_stake(&mut self, stake_index: u64, token_ids: Vec<String>) -> u64 {
+        self.assert_operation_active(Operation::Staking);
+        self.assert_no_active_distribution();
+        let staker_id = env::predecessor_account_id();
+        self.internal_checkpoint_staker(&staker_id);
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+
+        assert!(
+            stake_index < staker_info.stakes.len(),
+            "Invalid staking record index"
+        );
+
+        let mut stake = staker_info.stakes.get(stake_index).unwrap();
+        stake.assert_not_tokenized();
+        assert!(!token_ids.is_empty(), "No NFTs to split off");
+        assert!(
+            token_ids.iter().all(|token_id| stake.nft_ids.contains(token_id)),
+            "NFT is not part of this staking record"
+        );
+        let mut split = stake.clone();
+        stake.nft_ids.retain(|id| !token_ids.contains(id));
+        assert!(!stake.nft_ids.is_empty(), "At least one NFT must remain in the record");
+        split.nft_ids.retain(|id| token_ids.contains(id));
+        stake.nft_types.retain(|id, _| !token_ids.contains(id));
+        split.nft_types.retain(|id, _| token_ids.contains(id));
+        stake.rarity_scores.retain(|id, _| !token_ids.contains(id));
+        split.rarity_scores.retain(|id, _| token_ids.contains(id));
+        stake.nft_contracts.retain(|id, _| !token_ids.contains(id));
+        split.nft_contracts.retain(|id, _| token_ids.contains(id));
+        stake.staked_at.retain(|id, _| !token_ids.contains(id));
+        split.staked_at.retain(|id, _| token_ids.contains(id));
+        split.claimed_rewards = 0;
+        split.vesting_amount = 0;
+        split.position_id = self.internal_next_position_id();
+        assert!(
+            self.internal_can_open_stake(&staker_info),
+            "Daily record limit reached"
+        );
+        staker_info.count_new_stake(env::block_timestamp());
+
+        let initial_storage = env::storage_usage();
+        staker_info.stakes.replace(stake_index, &stake);
+        staker_info.stakes.push(&split);
+        let split_index = staker_info.stakes.len() - 1;
+        self.stakers.insert(&staker_id, &staker_info);
+        self.internal_update_storage(&staker_id, initial_storage, true);
+        self.internal_checkpoint_staker(&staker_id);
+
+        StakingEvent::StakeSplit {
+            account_id: staker_id,
+            token_ids: split.nft_ids,
+        }
+        .emit();
+        split_index
+    }
+
+    // Reassigns a record with its NFTs, lockup and pending rewards to another registered account
+    #[payable]
+    pub fn transfer_stake(&mut self, stake_index: u64, receiver_id: AccountId) {
+        assert_one_yocto();
+        self.assert_operation_active(Operation::Staking);
+        // The receiver may be added to the staker map, which would reorder a running round
+        self.assert_no_active_distribution();
+        let staker_id = env::predecessor_account_id();
+        assert!(receiver_id != staker_id, "Cannot transfer a stake to yourself");
+        self.assert_not_blacklisted(&staker_id);
+        self.assert_not_blacklisted(&receiver_id);
+        assert!(
+            self.storage_accounts.contains_key(&receiver_id),
+            "Receiver must be registered with storage_deposit"
+        );
+        let staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+
+        assert!(
+            stake_index < staker_info.stakes.len(),
+            "Invalid staking record index"
+        );
+        let stake = staker_info.stakes.get(stake_index).unwrap();
+        stake.assert_not_tokenized();
+        assert!(
+            self.internal_has_capacity(&receiver_id, stake.nft_ids.len() as u64),
+            "Receiver has reached the maximum number of staked NFTs"
+        );
+
+        self.internal_move_stake(&staker_id, stake_index, &receiver_id, true);
+    }
+
+    // Forgets a token leaving `stake`, releasing its place under the type cap
+    fn internal_remove_staked_token(&mut self, token_id: &String, stake: &NFTStakingRecord) {
+        self.staked_tokens.remove(token_id);
+        if let Some(nft_type) = stake.nft_types.get(token_id) {
+            self.internal_count_staked_type(nft_type, false);
+        }
+    }
+
+    fn internal_count_staked_type(&mut self, nft_type: &str, staked: bool) {
+        let count = self.staked_type_counts.entry(nft_type.to_string()).or_default();
+        if staked {
+            *count += 1;
+        } else {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    fn internal_type_has_room(&self, nft_type: &str) -> bool {
+        let Some(cap) = self.type_caps.get(nft_type) else {
+            return true;
+        };
+        self.staked_type_counts.get(nft_type).copied().unwrap_or(0) < *cap
+    }
+
+    fn internal_can_open_stake(&self, staker_info: &StakerInfo) -> bool {
+        let Some(max_stakes) = self.max_new_stakes_per_day else {
+            return true;
+        };
+        staker_info.new_stakes_in_window(env::block_timestamp()) < max_stakes
+    }
+
+    // Whether the account can stake `additional` more NFTs under the per-account cap
+    fn internal_has_capacity(&self, account_id: &AccountId, additional: u64) -> bool {
+        let Some(max_nfts) = self.max_nfts_per_account else {
+            return true;
+        };
+        let staked: u64 = self.stakers.get(account_id).map_or(0, |staker_info| {
+            staker_info
+                .stakes
+                .iter()
+                .map(|stake| stake.nft_ids.len() as u64)
+                .sum()
+        });
+        staked + additional <= max_nfts as u64
+    }
+
+    fn internal_move_stake(
+        &mut self,
+        staker_id: &AccountId,
+        stake_index: u64,
+        receiver_id: &AccountId,
+        enforce_deposit: bool,
+    ) {
+        self.internal_checkpoint_staker(staker_id);
+        self.internal_checkpoint_staker(receiver_id);
+        let mut staker_info = self.stakers.get(staker_id).expect("Staker not found");
+        let initial_storage = env::storage_usage();
+        let stake = staker_info.stakes.swap_remove(stake_index);
+        self.stakers.insert(staker_id, &staker_info);
+        self.internal_update_storage(staker_id, initial_storage, false);
+
+        let initial_storage = env::storage_usage();
+        let mut receiver_info = self
+            .stakers
+            .get(receiver_id)
+            .unwrap_or_else(|| StakerInfo::new(receiver_id));
+        assert!(
+            self.internal_can_open_stake(&receiver_info),
+            "Receiver has reached its daily record limit"
+        );
+        receiver_info.count_new_stake(env::block_timestamp());
+        receiver_info.stakes.push(&stake);
+        self.stakers.insert(receiver_id, &receiver_info);
+        for nft_id in stake.nft_ids.iter() {
+            self.staked_tokens.insert(nft_id, receiver_id);
+        }
+        self.internal_update_storage(receiver_id, initial_storage, enforce_deposit);
+        self.internal_checkpoint_staker(staker_id);
+        self.internal_checkpoint_staker(receiver_id);
+
+        StakingEvent::StakeTransferred {
+            account_id: staker_id.clone(),
+            receiver_id: receiver_id.clone(),
+            token_ids: stake.nft_ids.clone(),
+        }
+        .emit();
+    }
+
+    // Hands control of a record to a receipt minted on the position manager. While tokenized, the
+    // record can only be moved by transferring the receipt and is unlocked by redeeming it.
+    #[payable]
+    pub fn tokenize_position(&mut self, stake_index: u64) -> Promise {
+        assert_one_yocto();
+        self.assert_operation_active(Operation::Staking);
+        let position_manager = self
+            .position_manager
+            .clone()
+            .expect("No position manager configured");
+        let staker_id = env::predecessor_account_id();
+        self.assert_not_blacklisted(&staker_id);
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+
+        assert!(
+            stake_index < staker_info.stakes.len(),
+            "Invalid staking record index"
+        );
+
+        let mut stake = staker_info.stakes.get(stake_index).unwrap();
+        stake.assert_not_tokenized();
+        stake.tokenized = true;
+        staker_info.stakes.replace(stake_index, &stake);
+        self.stakers.insert(&staker_id, &staker_info);
+
+        StakingEvent::PositionTokenized {
+            account_id: staker_id.clone(),
+            position_id: stake.position_id,
+        }
+        .emit();
+        Promise::new(position_manager)
+            .function_call(
+                "mint_position".to_string(),
+                serde_json::to_vec(&json!({
+                    "position_id": stake.position_id,
+                    "owner_id": staker_id,
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(0),
+                GAS_FOR_MINT_POSITION,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .resolve_tokenize_position(staker_id, stake.position_id),
+            )
+    }
+
+    // Unlocks the record again if the receipt could not be minted
+    #[private]
+    pub fn resolve_tokenize_position(&mut self, staker_id: AccountId, position_id: u64) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        self.internal_release_position(&staker_id, position_id);
+        false
+    }
+
+    // Called by the position manager when a receipt changes hands, so the record follows it.
+    // Fails for blacklisted accounts or a receiver at its daily record limit, in which case the
+    // manager must roll the receipt back.
+    pub fn position_transfer(&mut self, owner_id: AccountId, position_id: u64, receiver_id: AccountId) {
+        self.assert_position_manager();
+        self.assert_not_blacklisted(&owner_id);
+        self.assert_not_blacklisted(&receiver_id);
+        // The receiver may be added to the staker map, which would reorder a running round
+        self.assert_no_active_distribution();
+        let stake_index = self.internal_position_index(&owner_id, position_id);
+        let staker_info = self.stakers.get(&owner_id).unwrap();
+        assert!(
+            staker_info.stakes.get(stake_index).unwrap().tokenized,
+            "Position is not tokenized"
+        );
+        // The receiver of a receipt has not necessarily registered storage
+        self.internal_move_stake(&owner_id, stake_index, &receiver_id, false);
+    }
+
+    // Called by the position manager when a receipt is redeemed, handing control back to its owner
+    pub fn position_release(&mut self, owner_id: AccountId, position_id: u64) {
+        self.assert_position_manager();
+        self.internal_release_position(&owner_id, position_id);
+    }
+
+    fn internal_release_position(&mut self, owner_id: &AccountId, position_id: u64) {
+        let stake_index = self.internal_position_index(owner_id, position_id);
+        let mut staker_info = self.stakers.get(owner_id).unwrap();
+        let mut stake = staker_info.stakes.get(stake_index).unwrap();
+        assert!(stake.tokenized, "Position is not tokenized");
+        stake.tokenized = false;
+        staker_info.stakes.replace(stake_index, &stake);
+        self.stakers.insert(owner_id, &staker_info);
+
+        StakingEvent::PositionReleased {
+            account_id: owner_id.clone(),
+            position_id,
+        }
+        .emit();
+    }
+
+    fn internal_position_index(&self, owner_id: &AccountId, position_id: u64) -> u64 {
+        let staker_info = self.stakers.get(owner_id).expect("Staker not found");
+        staker_info
+            .stakes
+            .iter()
+            .position(|stake| stake.position_id == position_id)
+            .expect("Position not found") as u64
+    }
+
+    fn internal_next_position_id(&mut self) -> u64 {
+        let position_id = self.next_position_id;
+        self.next_position_id += 1;
+        position_id
+    }
+
+    fn assert_position_manager(&self) {
+        assert_eq!(
+            Some(env::predecessor_account_id()),
+            self.position_manager,
+            "Only the position manager can call this method"
+        );
+    }
+
+    // Cheap cross-contract check for the allowlisted consumer contracts, e.g. the SIN game gating
+    // in-game utility on staked bees. Others can look tokens up with `get_nft_staker`.
+    // `nft_contract` defaults to the SIN collection.
+    pub fn is_token_staked(&self, token_id: String, nft_contract: Option<AccountId>) -> bool {
+        assert!(
+            self.staking_consumers.contains(&env::predecessor_account_id()),
+            "Only approved consumer contracts can call this method"
+        );
+        let nft_contract = nft_contract.unwrap_or_else(|| self.sin_nft_contract.clone());
+        self.staked_tokens
+            .contains_key(&self.internal_token_key(&nft_contract, &token_id))
+    }
+
+    // Allows a consumer contract, e.g. the SIN game contract, to call `is_token_staked`
+    pub fn add_staking_consumer(&mut self, consumer_id: AccountId) {
+        self.assert_role(Role::Owner);
+        assert!(
+            !self.staking_consumers.contains(&consumer_id),
+            "Consumer is already registered"
+        );
+        assert!(
+            self.staking_consumers.len() < MAX_STAKING_CONSUMERS,
+            "Cannot register more than {} consumers",
+            MAX_STAKING_CONSUMERS
+        );
+        self.internal_queue_change(ConfigChange::StakingConsumer { consumer_id });
+    }
+
+    pub fn remove_staking_consumer(&mut self, consumer_id: AccountId) {
+        self.assert_role(Role::Owner);
+        let consumer_count = self.staking_consumers.len();
+        self.staking_consumers.retain(|consumer| *consumer != consumer_id);
+        assert!(self.staking_consumers.len() < consumer_count, "Consumer not found");
+        StakingEvent::ConfigUpdate {
+            parameter: "staking_consumer_removed".to_string(),
+            value: json!(consumer_id),
+        }
+        .emit();
+    }
+
+    pub fn get_staking_consumers(&self) -> Vec<AccountId> {
+        self.staking_consumers.clone()
+    }
+
+    // Account that has `token_id` staked, with the index and position id of the record holding it.
+    // `nft_contract` defaults to the SIN collection.
+    pub fn get_nft_staker(
+        &self,
+        token_id: String,
+        nft_contract: Option<AccountId>,
+    ) -> Option<NftStakerView> {
+        let nft_contract = nft_contract.unwrap_or_else(|| self.sin_nft_contract.clone());
+        let token_id = self.internal_token_key(&nft_contract, &token_id);
+        let account_id = self.staked_tokens.get(&token_id)?;
+        let staker_info = self.stakers.get(&account_id)?;
+        let (stake_index, stake) = staker_info
+            .stakes
+            .iter()
+            .enumerate()
+            .find(|(_, stake)| stake.nft_ids.contains(&token_id))?;
+        Some(NftStakerView {
+            account_id,
+            stake_index: stake_index as u64,
+            position_id: stake.position_id,
+        })
+    }
+
+    // Record with the given position id, with its current index in the owner's records
+    pub fn get_position(&self, owner_id: AccountId, position_id: u64) -> Option<StakeView> {
+        let staker_info = self.stakers.get(&owner_id)?;
+        let (stake_index, stake) = staker_info
+            .stakes
+            .iter()
+            .enumerate()
+            .find(|(_, stake)| stake.position_id == position_id)?;
+        Some(self.stake_view(stake_index as u64, &stake))
+    }
+
+    // Re-locks a record into the `new_period` tier from now on, keeping its stake age
+    pub fn extend_lockup(&mut self, stake_index: u64, new_period: u64, staker_id: Option<AccountId>) {
+        self.assert_operation_active(Operation::Staking);
+        // The new multiplier would change the weight of a round that is being paid out
+        self.assert_no_active_distribution();
+        let staker_id = self.internal_acting_staker(staker_id);
+        self.internal_checkpoint_staker(&staker_id);
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+
+        assert!(
+            stake_index < staker_info.stakes.len(),
+            "Invalid staking record index"
+        );
+        assert!(
+            self.lockup_tiers
+                .iter()
+                .any(|tier| tier.lockup_period == new_period),
+            "No lockup tier for this period"
+        );
+
+        let mut stake = staker_info.stakes.get(stake_index).unwrap();
+        let now = env::block_timestamp();
+        assert!(
+            now + new_period * 1_000_000_000 > stake.unlock_timestamp(now),
+            "New lockup must end after the current one"
+        );
+
+        stake.lockup_start = now;
+        stake.lockup_period = new_period;
+        staker_info.stakes.replace(stake_index, &stake);
+        self.stakers.insert(&staker_id, &staker_info);
+        self.internal_checkpoint_staker(&staker_id);
+
+        StakingEvent::LockupExtended {
+            account_id: staker_id,
+            token_ids: stake.nft_ids,
+            lockup_period: new_period,
+            unlock_timestamp: U64(stake.lockup_start + new_period * 1_000_000_000),
+        }
+        .emit();
+    }
+
+    // Opts a record in or out of auto-renewal. Opting out keeps the lockup currently in effect;
+    // the record unlocks when it ends.
+    pub fn set_auto_renew(&mut self, stake_index: u64, auto_renew: bool) {
+        self.assert_operation_active(Operation::Staking);
+        let staker_id = env::predecessor_account_id();
+        let mut staker_info = self.stakers.get(&staker_id).expect("Staker not found");
+
+        assert!(
+            stake_index < staker_info.stakes.len(),
+            "Invalid staking record index"
+        );
+
+        let mut stake = staker_info.stakes.get(stake_index).unwrap();
+        stake.lockup_start = stake.current_lockup_start(env::block_timestamp());
+        stake.auto_renew = auto_renew;
+        staker_info.stakes.replace(stake_index, &stake);
+        self.stakers.insert(&staker_id, &staker_info);
+
+        StakingEvent::AutoRenewUpdated {
+            account_id: staker_id,
+            token_ids: stake.nft_ids,
+            auto_renew,
+        }
+        .emit();
+    }
+
+    // Lets `operator_id` claim, extend lockups and unstake on the caller's behalf. NFTs and rewards
+    // are always sent to the staker, never to the operator.
+    pub fn approve_operator(&mut self, operator_id: AccountId) {
+        let staker_id = env::predecessor_account_id();
+        assert!(operator_id != staker_id, "Cannot approve yourself as an operator");
+        assert!(
+            self.storage_accounts.contains_key(&staker_id),
+            "Account must be registered with storage_deposit"
+        );
+        let initial_storage = env::storage_usage();
+        let mut operators = self.operators.get(&staker_id).unwrap_or_default();
+        if !operators.contains(&operator_id) {
+            operators.push(operator_id.clone());
+            self.operators.insert(&staker_id, &operators);
+        }
+        self.internal_update_storage(&staker_id, initial_storage, true);
+
+        StakingEvent::OperatorApproved {
+            account_id: staker_id,
+            operator_id,
+        }
+        .emit();
+    }
+
+    pub fn revoke_operator(&mut self, operator_id: AccountId) {
+        let staker_id = env::predecessor_account_id();
+        let mut operators = self.operators.get(&staker_id).expect("No operators approved");
+        assert!(operators.contains(&operator_id), "Account is not an approved operator");
+        let initial_storage = env::storage_usage();
+        operators.retain(|operator| *operator != operator_id);
+        if operators.is_empty() {
+            self.operators.remove(&staker_id);
+        } else {
+            self.operators.insert(&staker_id, &operators);
+        }
+        self.internal_update_storage(&staker_id, initial_storage, false);
+
+        StakingEvent::OperatorRevoked {
+            account_id: staker_id,
+            operator_id,
+        }
+        .emit();
+    }
+
+    pub fn get_operators(&self, account_id: AccountId) -> Vec<AccountId> {
+        self.operators.get(&account_id).unwrap_or_default()
+    }
+
+    pub fn is_approved_operator(&self, account_id: AccountId, operator_id: AccountId) -> bool {
+        self.operators
+            .get(&account_id)
+            .is_some_and(|operators| operators.contains(&operator_id))
+    }
+
+    // Lets `sender_id` stake NFTs with `stake_for` on the caller's behalf. The records use the
+    // caller's storage deposit and NFT cap, so nobody else can create them.
+    pub fn approve_stake_sender(&mut self, sender_id: AccountId) {
+        let account_id = env::predecessor_account_id();
+        assert!(sender_id != account_id, "Cannot approve yourself as a stake sender");
+        assert!(
+            self.storage_accounts.contains_key(&account_id),
+            "Account must be registered with storage_deposit"
+        );
+        let initial_storage = env::storage_usage();
+        let mut senders = self.stake_senders.get(&account_id).unwrap_or_default();
+        if !senders.contains(&sender_id) {
+            senders.push(sender_id.clone());
+            self.stake_senders.insert(&account_id, &senders);
+        }
+        self.internal_update_storage(&account_id, initial_storage, true);
+
+        StakingEvent::StakeSenderApproved {
+            account_id,
+            sender_id,
+        }
+        .emit();
+    }
+
+    pub fn revoke_stake_sender(&mut self, sender_id: AccountId) {
+        let account_id = env::predecessor_account_id();
+        let mut senders = self.stake_senders.get(&account_id).expect("No stake senders approved");
+        assert!(senders.contains(&sender_id), "Account is not an approved stake sender");
+        let initial_storage = env::storage_usage();
+        senders.retain(|sender| *sender != sender_id);
+        if senders.is_empty() {
+            self.stake_senders.remove(&account_id);
+        } else {
+            self.stake_senders.insert(&account_id, &senders);
+        }
+        self.internal_update_storage(&account_id, initial_storage, false);
+
+        StakingEvent::StakeSenderRevoked {
+            account_id,
+            sender_id,
+        }
+        .emit();
+    }
+
+    pub fn get_stake_senders(&self, account_id: AccountId) -> Vec<AccountId> {
+        self.stake_senders.get(&account_id).unwrap_or_default()
+    }
+
+    fn internal_can_stake_for(&self, staker_id: &AccountId, sender_id: &AccountId) -> bool {
+        staker_id == sender_id
+            || self
+                .stake_senders
+                .get(staker_id)
+                .is_some_and(|senders| senders.contains(sender_id))
+    }
+
+    // Staker a call acts for: the caller itself, or `staker_id` if the caller is its operator
+    fn internal_acting_staker(&self, staker_id: Option<AccountId>) -> AccountId {
+        let caller = env::predecessor_account_id();
+        match staker_id {
+            Some(staker_id) if staker_id != caller => {
+                assert!(
+                    self.is_approved_operator(staker_id.clone(), caller),
+                    "Caller is not an approved operator of {}",
+                    staker_id
+                );
+                staker_id
+            }
+            _ => caller,
+        }
+    }
+
+    // Penalty for leaving a record at `now`. Records in a configured lockup tier pay a penalty that
+    // decays linearly to zero at maturity; other records pay the flat early unstake penalty.
+    fn internal_early_exit_penalty_bps(&self, stake: &NFTStakingRecord, now: u64) -> u128 {
+        let unlock_at = stake.unlock_timestamp(now);
+        if now >= unlock_at {
+            return 0;
+        }
+        match self
+            .lockup_tiers
+            .iter()
+            .find(|tier| tier.lockup_period == stake.lockup_period)
+        {
+            Some(tier) => mul_div(
+                tier.early_exit_penalty_bps as u128,
+                (unlock_at - now) as u128,
+                stake.lockup_period as u128 * 1_000_000_000,
+            ),
+            None => self.early_unstake_penalty_bps as u128,
+        }
+    }
+
+    // Returns a staked NFT to its owner. If the transfer fails, the token is re-staked as its own
+    // record with the original timestamps so it is never stranded without a record.
+    fn internal_transfer_nft(
+        &self,
+        receiver_id: &AccountId,
+        token_id: &String,
+        stake: &NFTStakingRecord,
+    ) -> Promise {
+        let mut nft_types = HashMap::new();
+        if let Some(nft_type) = stake.nft_types.get(token_id) {
+            nft_types.insert(token_id.clone(), nft_type.clone());
+        }
+        let mut rarity_scores = HashMap::new();
+        if let Some(score) = stake.rarity_scores.get(token_id) {
+            rarity_scores.insert(token_id.clone(), *score);
+        }
+        let mut nft_contracts = HashMap::new();
+        if let Some(nft_contract) = stake.nft_contracts.get(token_id) {
+            nft_contracts.insert(token_id.clone(), nft_contract.clone());
+        }
+        let staked_at = HashMap::from([(token_id.clone(), stake.nft_staked_at(token_id))]);
+        let restore = NFTStakingRecord {
+            nft_ids: vec![token_id.clone()],
+            nft_types,
+            start_timestamp: stake.start_timestamp,
+            lockup_period: stake.lockup_period,
+            lockup_start: stake.lockup_start,
+            auto_renew: stake.auto_renew,
+            position_id: stake.position_id, // Replaced with a fresh id if the record is restored
+            tokenized: false,
+            claimed_rewards: 0,
+            emission_weight: 0,
+            emission_acc: 0,
+            vesting_amount: 0,
+            vesting_start: 0,
+            vesting_end: 0,
+            last_claim: stake.last_claim,
+            rarity_scores,
+            nft_contracts,
+            staked_at,
+        };
+
+        let (nft_contract, collection_token_id) = self.internal_token_location(stake, token_id);
+        Promise::new(nft_contract)
+            .function_call(
+                "nft_transfer".to_string(),
+                serde_json::to_vec(&json!({
+                    "receiver_id": receiver_id,
+                    "token_id": collection_token_id
+                }))
+                .unwrap(),
+                NearToken::from_yoctonear(1), // Attach 1 yoctoNEAR
+                GAS_FOR_NFT_TRANSFER,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .unstake_nft_callback(receiver_id.clone(), restore),
+            )
+    }
+
+    #[private]
+    pub fn unstake_nft_callback(&mut self, staker_id: AccountId, stake: NFTStakingRecord) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        // A round in progress already weighed the stakers, so the record would be paid on top of
+        // its total. It is restored once the round completes; the tokens stay indexed meanwhile.
+        if self.active_distribution.is_some() {
+            for nft_id in stake.nft_ids.iter() {
+                self.staked_tokens.insert(nft_id, &staker_id);
+            }
+            env::log_str(&format!(
+                "Transfer of NFT {:?} to {} failed, stake restored once the distribution completes",
+                stake.nft_ids, staker_id
+            ));
+            self.pending_restores.push(&(staker_id, stake));
+            return false;
+        }
+        self.internal_restore_stake(&staker_id, stake);
+        false
+    }
+
+    // Restores the records of failed unstake transfers held back during a round
+    fn internal_flush_restores(&mut self) {
+        while let Some((staker_id, stake)) = self.pending_restores.pop() {
+            self.internal_restore_stake(&staker_id, stake);
+        }
+    }
+
+    fn internal_restore_stake(&mut self, staker_id: &AccountId, stake: NFTStakingRecord) {
+        let mut stake = stake;
+        stake.position_id = self.internal_next_position_id();
+        let initial_storage = env::storage_usage();
+        let mut staker_info = self
+            .stakers
+            .get(staker_id)
+            .unwrap_or_else(|| StakerInfo::new(staker_id));
+        staker_info.stakes.push(&stake);
+        self.stakers.insert(staker_id, &staker_info);
+        for nft_id in stake.nft_ids.iter() {
+            self.staked_tokens.insert(nft_id, staker_id);
+            if let Some(nft_type) = stake.nft_types.get(nft_id) {
+                // Restored even above the type cap, since the token is back on the contract
+                self.internal_count_staked_type(nft_type, true);
+            }
+        }
+        // Restoring must not fail, so the account may temporarily exceed its deposit
+        self.internal_update_storage(staker_id, initial_storage, false);
+        self.internal_checkpoint_staker(staker_id);
+
+        StakingEvent::StakeRestored {
+            account_id: staker_id.clone(),
+            token_ids: stake.nft_ids.clone(),
+        }
+        .emit();
+        env::log_str(&format!(
+            "Transfer of NFT {:?} to {} failed, stake restored",
+            stake.nft_ids, staker_id
+        ));
+    }
+
+    // Pages through every staker in the staker map's order
+    pub fn get_stakers(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<StakerSummary> {
+        let keys = self.stakers.keys_as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(50);
+        (from_index..keys.len().min(from_index.saturating_add(limit)))
+            .map(|index| {
+                let account_id = keys.get(index).unwrap();
+                let staker_info = self.stakers.get(&account_id).unwrap();
+                StakerSummary {
+                    stake_count: staker_info.stakes.len(),
+                    nft_count: staker_info
+                        .stakes
+                        .iter()
+                        .map(|stake| stake.nft_ids.len() as u64)
+                        .sum(),
+                    total_weight: U128(
+                        self.internal_stake_weights(&staker_info, env::block_timestamp())
+                            .iter()
+                            .sum(),
+                    ),
+                    pending_rewards: U128(self.internal_pending_rewards(&staker_info)),
+                    account_id,
+                }
+            })
+            .collect()
+    }
+
+    // Staker ids of the page starting at `from_index`, in the staker map's order
+    fn internal_staker_page(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<AccountId> {
+        let keys = self.stakers.keys_as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let to_index = keys.len().min(from_index.saturating_add(limit.unwrap_or(50)));
+        (from_index..to_index).map(|index| keys.get(index).unwrap()).collect()
+    }
+
+    // Contract-wide staker, NFT and pool totals. The total weight is computed on the fly across
+    // a page of stakers, so sum it over the pages for the whole contract.
+    pub fn get_stats(&self, from_index: Option<u64>, limit: Option<u64>) -> ContractStats {
+        let nfts_per_type: HashMap<String, u64> = self
+            .staked_type_counts
+            .iter()
+            .filter(|(_, count)| **count > 0)
+            .map(|(nft_type, count)| (nft_type.clone(), *count))
+            .collect();
+        ContractStats {
+            total_stakers: self.stakers.len(),
+            total_nfts_staked: nfts_per_type.values().sum(),
+            nfts_per_type,
+            total_weight: self.get_total_weight(from_index, limit),
+            reward_pool: U128(self.reward_distribution.total_reward_pool),
+            total_rewards_distributed: U128(self.total_rewards_distributed),
+            carried_remainder: U128(self.carried_remainder),
+        }
+    }
+
+    // Weight a page of stakers adds to the total a round started now would divide its amount by;
+    // sum the pages for the whole total. While a round is being paid out or a snapshot is waiting,
+    // this is the total weight it locked in, whatever the page.
+    pub fn get_total_weight(&self, from_index: Option<u64>, limit: Option<u64>) -> U128 {
+        if let Some(round) = self
+            .active_distribution
+            .as_ref()
+            .filter(|round| round.phase != DistributionPhase::Weighing)
+        {
+            return U128(round.total_weight);
+        }
+        let now = env::block_timestamp();
+        U128(
+            self.internal_staker_page(from_index, limit)
+                .iter()
+                .map(|staker_id| {
+                    let staker_info = self.stakers.get(staker_id).unwrap();
+                    self.internal_stake_weights(&staker_info, now).iter().sum::<u128>()
+                })
+                .sum(),
+        )
+    }
+
+    // What the account would receive if a round of `amount` were distributed right now
+    pub fn get_pending_rewards(&self, account_id: AccountId, amount: U128) -> RewardEstimate {
+        let Some(staker_info) = self.stakers.get(&account_id) else {
+            return RewardEstimate {
+                stake_rewards: vec![],
+                total: U128(0),
+                pending_rewards: U128(0),
+            };
+        };
+        let round = self.internal_preview_round(amount.0, env::block_timestamp());
+        let stake_rewards: Vec<U128> = self
+            .internal_round_rewards(&account_id, &staker_info, &round)
+            .into_iter()
+            .map(U128)
+            .collect();
+        RewardEstimate {
+            total: U128(stake_rewards.iter().map(|reward| reward.0).sum()),
+            stake_rewards,
+            pending_rewards: U128(self.internal_pending_rewards(&staker_info)),
+        }
+    }
+
+    // Dry run of a round of `amount` started now for a page of stakers, for sanity-checking a round
+    // before starting it. Payouts are rounded per record exactly like a real round. Without a
+    // waiting snapshot every staker is weighed first, so take one for large staker sets.
+    pub fn preview_distribution(
+        &self,
+        amount: U128,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> DistributionPreview {
+        let now = env::block_timestamp();
+        let round = self.internal_preview_round(amount.0, now);
+        let payouts: Vec<StakerPayout> = self
+            .internal_staker_page(from_index, limit)
+            .into_iter()
+            .map(|account_id| (self.stakers.get(&account_id).unwrap(), account_id))
+            .filter(|(staker_info, _)| !staker_info.stakes.is_empty())
+            .map(|(staker_info, account_id)| StakerPayout {
+                weight: U128(self.internal_stake_weights(&staker_info, now).iter().sum()),
+                payout: U128(
+                    self.internal_round_rewards(&account_id, &staker_info, &round)
+                        .iter()
+                        .sum(),
+                ),
+                account_id,
+            })
+            .collect();
+        let total_weight = round.total_weight;
+        let distributed: u128 = payouts.iter().map(|payout| payout.payout.0).sum();
+
+        DistributionPreview {
+            amount,
+            total_weight: U128(total_weight),
+            distributed: U128(distributed),
+            payouts,
+        }
+    }
+
+    // Approximate yearly SIN earned per NFT of each type, projecting the average of the recent rounds
+    // (or the keeper round amount before any round ran) over a year of distribution intervals.
+    // Set bonuses and longer lockup tiers earn more than this.
+    pub fn get_estimated_yearly_rewards(&self) -> YearlyRewardEstimate {
+        let history_len = self.distribution_history.len();
+        let sample = history_len.min(ESTIMATE_SAMPLE_ROUNDS);
+        let round_amount = if sample > 0 {
+            (history_len - sample..history_len)
+                .map(|index| self.distribution_history.get(index).unwrap().amount.0)
+                .sum::<u128>()
+                / sample as u128
+        } else {
+            self.reward_per_round
+        };
+        let yearly_rewards = mul_div(
+            round_amount,
+            YEAR as u128,
+            self.distribution_interval.max(1) as u128,
+        );
+
+        // Stored emission weights are full-period weights, like the NFT being estimated
+        let total_weight = self.emission.total_weight;
+        let multiplier_bps = self.internal_lockup_multiplier_bps(MONTH);
+        let yearly_rewards_per_nft = self
+            .nft_weights
+            .iter()
+            .map(|(nft_type, weight)| {
+                let nft_weight = *weight as u128 * multiplier_bps;
+                // A new NFT adds its own weight to the total it is divided by
+                let reward = mul_div(yearly_rewards, nft_weight, total_weight + nft_weight.max(1));
+                (nft_type.clone(), U128(reward))
+            })
+            .collect();
+
+        YearlyRewardEstimate {
+            yearly_rewards: U128(yearly_rewards),
+            total_weight: U128(total_weight),
+            yearly_rewards_per_nft,
+        }
+    }
+
+    // Top `limit` accounts by current staked weight and by lifetime rewards claimed, among the
+    // `page_size` stakers from `from_index` on. Merge the pages for the contract-wide ranking.
+    pub fn get_leaderboard(
+        &self,
+        limit: Option<u64>,
+        from_index: Option<u64>,
+        page_size: Option<u64>,
+    ) -> Leaderboard {
+        let limit = limit.unwrap_or(10).min(MAX_LEADERBOARD_SIZE) as usize;
+        let now = env::block_timestamp();
+        let mut by_weight = vec![];
+        let mut by_rewards_claimed = vec![];
+        for account_id in self.internal_staker_page(from_index, page_size) {
+            let staker_info = self.stakers.get(&account_id).unwrap();
+            let weight: u128 = self.internal_stake_weights(&staker_info, now).iter().sum();
+            if weight > 0 {
+                by_weight.push(LeaderboardEntry {
+                    account_id: account_id.clone(),
+                    value: U128(weight),
+                });
+            }
+            if staker_info.total_rewards_claimed > 0 {
+                by_rewards_claimed.push(LeaderboardEntry {
+                    account_id,
+                    value: U128(staker_info.total_rewards_claimed),
+                });
+            }
+        }
+        by_weight.sort_by_key(|entry| std::cmp::Reverse(entry.value.0));
+        by_weight.truncate(limit);
+        by_rewards_claimed.sort_by_key(|entry| std::cmp::Reverse(entry.value.0));
+        by_rewards_claimed.truncate(limit);
+        Leaderboard {
+            by_weight,
+            by_rewards_claimed,
+        }
+    }
+
+    pub fn get_staker_count(&self) -> u64 {
+        self.stakers.len()
+    }
+
+    // Rewards credited to the staker that have not been claimed yet, including streamed rewards
+    // not yet settled into its records
+    fn internal_pending_rewards(&self, staker_info: &StakerInfo) -> u128 {
+        let acc = self.internal_current_emission_acc();
+        staker_info.unclaimed_rewards
+            + staker_info
+                .stakes
+                .iter()
+                .map(|stake| stake.claimed_rewards + Self::internal_unsettled_emission(&stake, acc))
+                .sum::<u128>()
+    }
+
+    // Successful claims of the account, oldest first
+    pub fn get_claim_history(
+        &self,
+        account_id: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<ClaimRecord> {
+        let Some(staker_info) = self.stakers.get(&account_id) else {
+            return vec![];
+        };
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(50);
+        (from_index..staker_info.claims.len().min(from_index.saturating_add(limit)))
+            .map(|index| staker_info.claims.get(index).unwrap())
+            .collect()
+    }
+
+    pub fn get_staking_info(
+        &self,
+        staker_id: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<StakeView> {
+        if let Some(staker_info) = self.stakers.get(&staker_id) {
+            let from_index = from_index.unwrap_or(0);
+            let limit = limit.unwrap_or(50);
+            (from_index..staker_info.stakes.len().min(from_index.saturating_add(limit)))
+                .map(|stake_index| {
+                    self.stake_view(stake_index, &staker_info.stakes.get(stake_index).unwrap())
+                })
+                .collect()
+        } else {
+            vec![] // Return empty if no staking info is found
+        }
+    }
+
+    fn stake_view(&self, stake_index: u64, stake: &NFTStakingRecord) -> StakeView {
+        // Aggregate NFT type counts
+        let mut queen_count = 0;
+        let mut worker_count = 0;
+        let mut drone_count = 0;
+
+        for nft_type in stake.nft_types.values() {
+            match nft_type.as_str() {
+                "Queen" => queen_count += 1,
+                "Worker" => worker_count += 1,
+                "Drone" => drone_count += 1,
+                _ => (),
+            }
+        }
+
+        StakeView {
+            stake_index,
+            position_id: stake.position_id,
+            nft_ids: stake.nft_ids.clone(),
+            queen: queen_count,
+            worker: worker_count,
+            drone: drone_count,
+            start_timestamp: U64(stake.start_timestamp),
+            lockup_period: stake.lockup_period,
+            unlock_timestamp: U64(stake.unlock_timestamp(env::block_timestamp())),
+            auto_renew: stake.auto_renew,
+            tokenized: stake.tokenized,
+            vesting_rewards: U128(stake.locked_rewards(self.internal_vesting_now())),
+            claim_by: stake.claim_by(self.reward_expiry).map(U64),
+            claimed_rewards: U128(
+                stake.claimed_rewards
+                    + Self::internal_unsettled_emission(stake, self.internal_current_emission_acc()),
+            ),
+        }
+    }
+
+    pub fn get_stake_count(&self, account_id: AccountId) -> u64 {
+        self.stakers
+            .get(&account_id)
+            .map_or(0, |staker_info| staker_info.stakes.len())
+    }
+
+    pub fn set_keeper_config(&mut self, reward_per_round: U128, keeper_bounty_bps: u32) {
+        self.assert_role(Role::Owner);
+        assert!(
+            keeper_bounty_bps <= MAX_KEEPER_BOUNTY_BPS,
+            "Keeper bounty cannot exceed {} bps",
+            MAX_KEEPER_BOUNTY_BPS
+        );
+        self.internal_queue_change(ConfigChange::KeeperConfig {
+            reward_per_round,
+            keeper_bounty_bps,
+        });
+    }
+
+    pub fn set_nft_weight(&mut self, nft_type: String, weight: u32) {
+        self.assert_role(Role::Owner);
+        self.internal_queue_change(ConfigChange::NftWeight { nft_type, weight });
+    }
+
+    // Earmarks `share_bps` of every round for the NFTs of `nft_type`, split by their weight. The
+    // rest of a round is split across all NFTs. A share of 0 removes the earmark.
+    pub fn set_type_pool_share(&mut self, nft_type: String, share_bps: u32) {
+        self.assert_role(Role::Owner);
+        self.internal_assert_type_pool_share(&nft_type, share_bps);
+        self.internal_queue_change(ConfigChange::TypePoolShare { nft_type, share_bps });
+    }
+
+    fn internal_assert_type_pool_share(&self, nft_type: &String, share_bps: u32) {
+        assert!(self.nft_weights.contains_key(nft_type), "Unknown NFT type");
+        let other_shares: u32 = self
+            .type_pool_shares
+            .iter()
+            .filter(|(other_type, _)| *other_type != nft_type)
+            .map(|(_, share)| share)
+            .sum();
+        assert!(
+            other_shares + share_bps <= BPS_DENOMINATOR as u32,
+            "Type pool shares cannot exceed 10000 basis points in total"
+        );
+    }
+
+    // Deducts `fee_bps` from every SIN claim and sends it to `treasury`. No fee is taken without a
+    // treasury.
+    pub fn set_claim_fee(&mut self, fee_bps: u32, treasury: Option<AccountId>) {
+        self.assert_role(Role::Owner);
+        assert!(
+            fee_bps <= MAX_CLAIM_FEE_BPS,
+            "Claim fee cannot exceed {} basis points",
+            MAX_CLAIM_FEE_BPS
+        );
+        self.internal_queue_change(ConfigChange::ClaimFee { fee_bps, treasury });
+    }
+
+    // Every `unit` of SIN a staker compounds adds `boost_bps` to its weight, up to `max_boost_bps`.
+    // A unit of 0 disables compounding.
+    pub fn set_compound_boost(&mut self, unit: U128, boost_bps: u32, max_boost_bps: u32) {
+        self.assert_role(Role::Owner);
+        assert!(
+            max_boost_bps as u128 <= BPS_DENOMINATOR,
+            "Compounding boost cannot exceed 100%"
+        );
+        self.internal_queue_change(ConfigChange::CompoundBoost {
+            unit,
+            boost_bps,
+            max_boost_bps,
+        });
+    }
+
+    // Round rewards unlock linearly over `vesting_period` seconds instead of all at once
+    pub fn set_reward_vesting(&mut self, vesting_period: u64) {
+        self.assert_role(Role::Owner);
+        assert!(
+            vesting_period <= YEAR,
+            "Vesting period cannot exceed {} seconds",
+            YEAR
+        );
+        self.internal_queue_change(ConfigChange::RewardVesting { vesting_period });
+    }
+
+    // Rewards left unclaimed on a record for `expiry_period` seconds can be swept back into the
+    // pool with `sweep_expired_rewards`
+    pub fn set_reward_expiry(&mut self, expiry_period: u64) {
+        self.assert_role(Role::Owner);
+        assert!(
+            expiry_period == 0 || expiry_period >= MIN_REWARD_EXPIRY,
+            "Reward expiry must be 0 or at least {} seconds",
+            MIN_REWARD_EXPIRY
+        );
+        self.internal_queue_change(ConfigChange::RewardExpiry { expiry_period });
+    }
+
+    // Caps the share of a round any single account receives. The excess is redistributed among
+    // the other accounts.
+    pub fn set_max_account_share(&mut self, max_share_bps: u32) {
+        self.assert_role(Role::Owner);
+        assert!(
+            max_share_bps == 0
+                || (MIN_ACCOUNT_SHARE_BPS..=BPS_DENOMINATOR as u32).contains(&max_share_bps),
+            "Max account share must be 0 or between {} and {} bps",
+            MIN_ACCOUNT_SHARE_BPS,
+            BPS_DENOMINATOR
+        );
+        self.internal_queue_change(ConfigChange::MaxAccountShare { max_share_bps });
+    }
+
+    // Records staked less than `min_stake_age` seconds before a round starts get nothing from it,
+    // so staking right before a round doesn't dilute long-term stakers
+    pub fn set_min_stake_age(&mut self, min_stake_age: u64) {
+        self.assert_role(Role::Owner);
+        assert!(
+            min_stake_age <= MONTH,
+            "Minimum stake age cannot exceed {} seconds",
+            MONTH
+        );
+        self.internal_queue_change(ConfigChange::MinStakeAge { min_stake_age });
+    }
+
+    // Scales type weights by scarcity so rewards rebalance when stakers pile into one type. Round
+    // weights follow the staked counts as stakes change. Streamed emission, token rewards and
+    // campaigns keep the configured weights, since stored weights would go stale.
+    pub fn set_scarcity_weights(&mut self, enabled: bool) {
+        self.assert_role(Role::Owner);
+        self.internal_queue_change(ConfigChange::ScarcityWeights { enabled });
+    }
+
+    // Recovers surplus SIN from the unallocated pool, e.g. after over-funding. Rewards owed to
+    // stakers or reserved for rounds can't be withdrawn.
+    pub fn withdraw_unallocated(&mut self, amount: U128, receiver_id: AccountId) {
+        self.assert_role(Role::Owner);
+        assert!(amount.0 > 0, "Withdrawal amount must be greater than zero");
+        assert!(
+            amount.0 <= self.get_pool_accounting().unallocated_pool.0,
+            "Withdrawal exceeds the unallocated reward pool"
+        );
+        self.internal_queue_change(ConfigChange::WithdrawUnallocated {
+            amount,
+            receiver_id,
+        });
+    }
+
+    pub fn set_sin_token(&mut self, sin_token: AccountId) {
+        self.assert_role(Role::Owner);
+        self.internal_queue_change(ConfigChange::SinToken { sin_token });
+    }
+
+    pub fn set_timelock_duration(&mut self, duration: u64) {
+        self.assert_role(Role::Owner);
+        assert!(
+            duration <= MAX_TIMELOCK_DURATION,
+            "Timelock cannot exceed {} seconds",
+            MAX_TIMELOCK_DURATION
+        );
+        self.internal_queue_change(ConfigChange::TimelockDuration { duration });
+    }
+
+    // Bonus applied to the weight of stakers holding a full Queen + Worker + Drone set
+    pub fn set_hive_set_bonus(&mut self, set_bonus_bps: u32) {
+        self.assert_role(Role::Owner);
+        assert!(
+            set_bonus_bps <= MAX_SET_BONUS_BPS,
+            "Set bonus cannot exceed {} bps",
+            MAX_SET_BONUS_BPS
+        );
+        self.internal_queue_change(ConfigChange::SetBonus { set_bonus_bps });
+    }
+
+    // Unstaking before the lockup ends forfeits `penalty_bps` of the record's pending rewards, plus
+    // a fee of `fee_bps` of the rewards the account has not claimed yet
+    pub fn set_early_unstake_penalty(&mut self, penalty_bps: u32, fee_bps: u32) {
+        self.assert_role(Role::Owner);
+        assert!(
+            penalty_bps as u128 <= BPS_DENOMINATOR,
+            "Penalty cannot exceed 100%"
+        );
+        assert!(fee_bps as u128 <= BPS_DENOMINATOR, "Fee cannot exceed 100%");
+        self.internal_queue_change(ConfigChange::EarlyUnstakePenalty {
+            penalty_bps,
+            fee_bps,
+        });
+    }
+
+    // Adds or replaces the tier for `lockup_period`
+    pub fn set_lockup_tier(&mut self, tier: LockupTier) {
+        self.assert_role(Role::Owner);
+        assert!(
+            tier.lockup_period > 0,
+            "Lockup period must be greater than zero, flexible stakes use set_flexible_weight"
+        );
+        assert!(
+            tier.early_exit_penalty_bps as u128 <= BPS_DENOMINATOR,
+            "Penalty cannot exceed 100%"
+        );
+        assert!(
+            tier.reward_multiplier_bps <= MAX_LOCKUP_MULTIPLIER_BPS,
+            "Reward multiplier cannot exceed {} bps",
+            MAX_LOCKUP_MULTIPLIER_BPS
+        );
+        self.internal_queue_change(ConfigChange::LockupTier { tier });
+    }
+
+    pub fn remove_lockup_tier(&mut self, lockup_period: u64) {
+        self.assert_role(Role::Owner);
+        assert!(
+            self.lockup_tiers
+                .iter()
+                .any(|tier| tier.lockup_period == lockup_period),
+            "Lockup tier not found"
+        );
+        self.internal_queue_change(ConfigChange::RemoveLockupTier { lockup_period });
+    }
+
+    // Weight multiplier for flexible records, which can be unstaked at any time
+    pub fn set_flexible_weight(&mut self, weight_bps: u32) {
+        self.assert_role(Role::Owner);
+        assert!(
+            weight_bps as u128 <= BPS_DENOMINATOR,
+            "Flexible weight cannot exceed 100%"
+        );
+        self.internal_queue_change(ConfigChange::FlexibleWeight { weight_bps });
+    }
+
+    // Seconds that must pass between distribution rounds
+    pub fn set_distribution_interval(&mut self, interval: u64) {
+        self.assert_role(Role::Owner);
+        assert!(
+            (DAY..=YEAR).contains(&interval),
+            "Distribution interval must be between {} and {} seconds",
+            DAY,
+            YEAR
+        );
+        self.internal_queue_change(ConfigChange::DistributionInterval { interval });
+    }
+
+    // Companion contract that mints NEP-171 receipts for tokenized positions
+    pub fn set_position_manager(&mut self, position_manager: Option<AccountId>) {
+        self.assert_role(Role::Owner);
+        self.internal_queue_change(ConfigChange::PositionManager { position_manager });
+    }
+
+    // SIN streamed per second to stakers from the reward pool, pro rata to their weight
+    pub fn set_emission_rate(&mut self, rate_per_second: U128) {
+        self.assert_role(Role::Owner);
+        self.internal_queue_change(ConfigChange::EmissionRate { rate_per_second });
+    }
+
+    // Halves the emission rate every `halving_interval` seconds; 0 keeps the rate constant
+    pub fn set_halving_interval(&mut self, halving_interval: u64) {
+        self.assert_role(Role::Owner);
+        assert!(
+            halving_interval == 0 || halving_interval >= DAY,
+            "Halving interval must be 0 or at least {} seconds",
+            DAY
+        );
+        assert!(
+            halving_interval <= MAX_HALVING_INTERVAL,
+            "Halving interval cannot exceed {} seconds",
+            MAX_HALVING_INTERVAL
+        );
+        self.internal_queue_change(ConfigChange::HalvingInterval { halving_interval });
+    }
+
+    pub fn get_emission_schedule(&self) -> EmissionSchedule {
+        let now = env::block_timestamp();
+        let current_rate = self.internal_emission_rate_at(now);
+        EmissionSchedule {
+            initial_rate_per_second: U128(self.emission.rate_per_second),
+            current_rate_per_second: U128(current_rate),
+            halving_interval: self.emission.halving_interval,
+            schedule_start: U64(self.emission.schedule_start),
+            next_halving: self
+                .internal_next_halving(now)
+                .filter(|_| current_rate > 0)
+                .map(U64),
+        }
+    }
+
+    pub fn get_lockup_tiers(&self) -> Vec<LockupTier> {
+        self.lockup_tiers.clone()
+    }
+
+    // Applies a sensitive change right away when no timelock is set, otherwise queues it
+    fn internal_queue_change(&mut self, change: ConfigChange) {
+        if self.timelock_duration == 0 {
+            self.internal_apply_change(change);
+            return;
+        }
+
+        let id = self.next_change_id;
+        self.next_change_id += 1;
+        let pending = PendingChange {
+            id,
+            change,
+            executable_at: U64(env::block_timestamp() + self.timelock_duration * 1_000_000_000),
+        };
+        self.pending_changes.insert(&id, &pending);
+        StakingEvent::ConfigChangeQueued {
+            id,
+            change: pending.change,
+            executable_at: pending.executable_at,
+        }
+        .emit();
+    }
+
+    // Applies a queued change once its timelock has expired. Callable by anyone.
+    pub fn execute_config_change(&mut self, id: u64) {
+        let pending = self.pending_changes.get(&id).expect("Config change not found");
+        assert!(
+            env::block_timestamp() >= pending.executable_at.0,
+            "Config change is still timelocked"
+        );
+        self.pending_changes.remove(&id);
+        self.internal_apply_change(pending.change);
+    }
+
+    pub fn cancel_config_change(&mut self, id: u64) {
+        self.assert_role(Role::Owner);
+        assert!(
+            self.pending_changes.remove(&id).is_some(),
+            "Config change not found"
+        );
+        StakingEvent::ConfigChangeCancelled { id }.emit();
+    }
+
+    pub fn get_pending_changes(&self) -> Vec<PendingChange> {
+        self.pending_changes.values().collect()
+    }
+
+    fn internal_apply_change(&mut self, change: ConfigChange) {
+        // Accrue at the old settings; stakers pick up new weights when checkpointed
+        self.internal_update_emission();
+        match &change {
+            ConfigChange::NftWeight { nft_type, weight } => {
+                // Changing weights mid-round would pay out against a stale total weight
+                self.assert_no_active_distribution();
+                self.nft_weights.insert(nft_type.clone(), *weight);
+            }
+            ConfigChange::SinToken { sin_token } => {
+                self.sin_token = sin_token.clone();
+            }
+            ConfigChange::KeeperConfig {
+                reward_per_round,
+                keeper_bounty_bps,
+            } => {
+                self.reward_per_round = reward_per_round.0;
+                self.keeper_bounty_bps = *keeper_bounty_bps;
+            }
+            ConfigChange::TimelockDuration { duration } => {
+                self.timelock_duration = *duration;
+            }
+            ConfigChange::SetBonus { set_bonus_bps } => {
+                self.assert_no_active_distribution();
+                self.set_bonus_bps = *set_bonus_bps;
+            }
+            ConfigChange::EarlyUnstakePenalty {
+                penalty_bps,
+                fee_bps,
+            } => {
+                self.early_unstake_penalty_bps = *penalty_bps;
+                self.early_unstake_fee_bps = *fee_bps;
+            }
+            ConfigChange::LockupTier { tier } => {
+                // A tier's multiplier weighs its records, which a round in progress already did
+                self.assert_no_active_distribution();
+                self.lockup_tiers
+                    .retain(|t| t.lockup_period != tier.lockup_period);
+                self.lockup_tiers.push(tier.clone());
+            }
+            ConfigChange::RemoveLockupTier { lockup_period } => {
+                self.assert_no_active_distribution();
+                self.lockup_tiers
+                    .retain(|t| t.lockup_period != *lockup_period);
+            }
+            ConfigChange::FlexibleWeight { weight_bps } => {
+                self.assert_no_active_distribution();
+                self.flexible_weight_bps = *weight_bps;
+            }
+            ConfigChange::PositionManager { position_manager } => {
+                self.position_manager = position_manager.clone();
+            }
+            ConfigChange::TypePoolShare { nft_type, share_bps } => {
+                // Other shares may have grown while this change was queued
+                self.internal_assert_type_pool_share(nft_type, *share_bps);
+                if *share_bps == 0 {
+                    self.type_pool_shares.remove(nft_type);
+                } else {
+                    self.type_pool_shares.insert(nft_type.clone(), *share_bps);
+                }
+            }
+            ConfigChange::ClaimFee { fee_bps, treasury } => {
+                self.claim_fee_bps = *fee_bps;
+                self.treasury = treasury.clone();
+            }
+            ConfigChange::CompoundBoost {
+                unit,
+                boost_bps,
+                max_boost_bps,
+            } => {
+                // Boosts change every compounding staker's weight
+                self.assert_no_active_distribution();
+                self.compound_unit = unit.0;
+                self.compound_boost_bps = *boost_bps;
+                self.max_compound_boost_bps = *max_boost_bps;
+            }
+            ConfigChange::RewardVesting { vesting_period } => {
+                self.reward_vesting_period = *vesting_period;
+            }
+            ConfigChange::RewardExpiry { expiry_period } => {
+                self.reward_expiry = *expiry_period;
+            }
+            ConfigChange::MaxAccountShare { max_share_bps } => {
+                // Rounds keep the cap they started with
+                self.max_account_share_bps = *max_share_bps;
+            }
+            ConfigChange::MinStakeAge { min_stake_age } => {
+                // Would change the weights of a round being weighed
+                self.assert_no_active_distribution();
+                self.min_stake_age = *min_stake_age;
+            }
+            ConfigChange::ScarcityWeights { enabled } => {
+                // Changing weights mid-round would pay out against a stale total weight
+                self.assert_no_active_distribution();
+                self.scarcity_weights = *enabled;
+            }
+            ConfigChange::WithdrawUnallocated {
+                amount,
+                receiver_id,
+            } => {
+                // Checked again as the pool may have been distributed or streamed in the meantime
+                assert!(
+                    amount.0 <= self.reward_distribution.total_reward_pool,
+                    "Withdrawal exceeds the unallocated reward pool"
+                );
+                self.reward_distribution.total_reward_pool -= amount.0;
+                self.internal_transfer_sin(receiver_id, amount.0, None);
+            }
+            ConfigChange::EmissionRate { rate_per_second } => {
+                // A new rate restarts the halving schedule
+                self.emission.rate_per_second = rate_per_second.0;
+                self.emission.schedule_start = env::block_timestamp();
+            }
+            ConfigChange::HalvingInterval { halving_interval } => {
+                // Rebase on the rate in effect so past halvings are kept
+                self.emission.rate_per_second =
+                    self.internal_emission_rate_at(env::block_timestamp());
+                self.emission.schedule_start = env::block_timestamp();
+                self.emission.halving_interval = *halving_interval;
+            }
+            ConfigChange::DistributionInterval { interval } => {
+                self.distribution_interval = *interval;
+            }
+            ConfigChange::TraitRule { rule } => {
+                self.trait_rules
+                    .retain(|r| !(r.trait_type == rule.trait_type && r.value == rule.value));
+                self.trait_rules.push(rule.clone());
+            }
+            ConfigChange::RemoveTraitRule { trait_type, value } => {
+                self.trait_rules
+                    .retain(|r| !(r.trait_type == *trait_type && r.value == *value));
+            }
+            ConfigChange::DefaultNftType { nft_type } => {
+                self.default_nft_type = nft_type.clone();
+            }
+            ConfigChange::MaxNftsPerAccount { max_nfts } => {
+                self.max_nfts_per_account = *max_nfts;
+            }
+            ConfigChange::StakingConsumer { consumer_id } => {
+                // Other consumers may have been queued in the meantime
+                assert!(
+                    self.staking_consumers.len() < MAX_STAKING_CONSUMERS,
+                    "Cannot register more than {} consumers",
+                    MAX_STAKING_CONSUMERS
+                );
+                if !self.staking_consumers.contains(consumer_id) {
+                    self.staking_consumers.push(consumer_id.clone());
+                }
+            }
+            ConfigChange::ClaimCooldown { cooldown } => {
+                self.claim_cooldown = *cooldown;
+            }
+            ConfigChange::MinClaimAmount { min_claim_amount } => {
+                self.min_claim_amount = min_claim_amount.0;
+            }
+            ConfigChange::MaxRewardPool { max_reward_pool } => {
+                self.max_reward_pool = max_reward_pool.map(|max_reward_pool| max_reward_pool.0);
+            }
+            ConfigChange::CommunityFunding { enabled } => {
+                self.community_funding = *enabled;
+            }
+            ConfigChange::RarityOracle { rarity_oracle } => {
+                self.rarity_oracle = rarity_oracle.clone();
+            }
+            ConfigChange::Collection {
+                nft_contract,
+                nft_weights,
+                default_nft_type,
+            } => {
+                // The same collection may have been queued twice
+                assert!(
+                    !self.collections.contains_key(nft_contract),
+                    "Collection is already registered"
+                );
+                self.collections.insert(
+                    nft_contract.clone(),
+                    NftCollection {
+                        nft_weights: nft_weights.clone(),
+                        trait_rules: vec![],
+                        default_nft_type: default_nft_type.clone(),
+                        enabled: true,
+                        weight_multiplier_bps: BPS_DENOMINATOR as u32,
+                    },
+                );
+            }
+            ConfigChange::CollectionWeight {
+                nft_contract,
+                nft_type,
+                weight,
+            } => {
+                self.assert_no_active_distribution();
+                self.internal_collection_mut(nft_contract)
+                    .nft_weights
+                    .insert(nft_type.clone(), *weight);
+            }
+            ConfigChange::CollectionTraitRule { nft_contract, rule } => {
+                let collection = self.internal_collection_mut(nft_contract);
+                collection
+                    .trait_rules
+                    .retain(|r| !(r.trait_type == rule.trait_type && r.value == rule.value));
+                collection.trait_rules.push(rule.clone());
+            }
+            ConfigChange::CollectionMultiplier {
+                nft_contract,
+                multiplier_bps,
+            } => {
+                self.assert_no_active_distribution();
+                self.internal_collection_mut(nft_contract).weight_multiplier_bps = *multiplier_bps;
+            }
+            ConfigChange::TypeCap { nft_type, cap } => {
+                match cap {
+                    Some(cap) => self.type_caps.insert(nft_type.clone(), *cap),
+                    None => self.type_caps.remove(nft_type),
+                };
+            }
+            ConfigChange::Sunset {} => {
+                self.sunset = true;
+            }
+        }
+        StakingEvent::ConfigUpdate {
+            parameter: change.parameter().to_string(),
+            value: json!(change),
+        }
+        .emit();
+    }
+
+    pub fn get_nft_weights(&self) -> HashMap<String, u32> {
+        self.nft_weights.clone()
+    }
+
+    // Weights currently in effect, which differ from the configured ones with scarcity weights
+    pub fn get_effective_nft_weights(&self) -> HashMap<String, U128> {
+        self.nft_weights
+            .keys()
+            .map(|nft_type| (nft_type.clone(), U128(self.internal_type_weight(nft_type, true))))
+            .collect()
+    }
+
+    // Attributes the storage change since `initial_storage` to the account
+    fn internal_update_storage(
+        &mut self,
+        account_id: &AccountId,
+        initial_storage: StorageUsage,
+        enforce_deposit: bool,
+    ) {
+        let Some(mut account) = self.storage_accounts.get(account_id) else {
+            return;
+        };
+        let current_storage = env::storage_usage();
+        if current_storage >= initial_storage {
+            account.used_bytes += current_storage - initial_storage;
+        } else {
+            account.used_bytes = account
+                .used_bytes
+                .saturating_sub(initial_storage - current_storage);
+        }
+        if enforce_deposit {
+            let required =
+                env::storage_byte_cost().as_yoctonear() * account.used_bytes as Balance;
+            assert!(
+                account.deposit >= required,
+                "Insufficient storage deposit, {} yoctoNEAR required",
+                required
+            );
+        }
+        self.storage_accounts.insert(account_id, &account);
+    }
+
+    pub fn pause(&mut self) {
+        self.assert_role(Role::Owner);
+        self.paused = true;
+        StakingEvent::ConfigUpdate {
+            parameter: "paused".to_string(),
+            value: json!(true),
+        }
+        .emit();
+    }
+
+    pub fn unpause(&mut self) {
+        self.assert_role(Role::Owner);
+        assert!(self.migration.is_none(), "State migration is in progress");
+        self.paused = false;
+        StakingEvent::ConfigUpdate {
+            parameter: "paused".to_string(),
+            value: json!(false),
+        }
+        .emit();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Winds the contract down for good: staking and new rounds are disabled, and every staked NFT
+    // is returned through `emergency_return_nfts`. Claims keep working.
+    pub fn start_emergency_shutdown(&mut self) {
+        self.assert_role(Role::Owner);
+        assert!(!self.emergency_shutdown, "Emergency shutdown is already active");
+        self.emergency_shutdown = true;
+        StakingEvent::ConfigUpdate {
+            parameter: "emergency_shutdown".to_string(),
+            value: json!(true),
+        }
+        .emit();
+    }
+
+    pub fn is_emergency_shutdown(&self) -> bool {
+        self.emergency_shutdown
+    }
+
+    // Winds the contract down gracefully: new stakes and funding are refused and stakers can
+    // unstake at their own pace without waiting for their lockups or paying penalties. Vesting
+    // rewards are released as well. Queued behind the timelock, and it can't be undone.
+    pub fn set_sunset(&mut self) {
+        self.assert_role(Role::Owner);
+        assert!(!self.sunset, "Contract is already in sunset");
+        self.internal_queue_change(ConfigChange::Sunset {});
+    }
+
+    pub fn is_sunset(&self) -> bool {
+        self.sunset
+    }
+
+    // Time rewards vest against. Vesting is released during sunset, so nothing is locked anymore.
+    fn internal_vesting_now(&self) -> u64 {
+        if self.sunset {
+            return u64::MAX;
+        }
+        env::block_timestamp()
+    }
+
+    fn is_staking_closed(&self) -> bool {
+        self.emergency_shutdown || self.sunset
+    }
+
+    fn assert_not_shut_down(&self) {
+        assert!(!self.emergency_shutdown, "Contract is shut down");
+    }
+
+    // Returns staked NFTs to their owners regardless of lockups and penalties. Record rewards are
+    // credited to the staker's unclaimed rewards for claim_all_rewards. Processes records while
+    // the attached gas lasts and returns the staker index to continue from, or None once done.
+    // NFTs whose transfer fails are restored as records and need another pass from index 0.
+    // Blacklisted stakers are skipped; their NFTs stay until they are cleared or the tokens are
+    // recovered with recover_blacklisted_nft. Tokenized records are skipped as well, since their
+    // receipts would be left pointing at nothing; holders redeem them and unstake instead.
+    pub fn emergency_return_nfts(&mut self, from_index: Option<u64>) -> Option<u64> {
+        self.assert_role(Role::Owner);
+        assert!(self.emergency_shutdown, "Emergency shutdown is not active");
+        self.assert_no_active_distribution();
+        let mut index = from_index.unwrap_or(0);
+        while let Some(staker_id) = self.stakers.keys_as_vector().get(index) {
+            if !self.blacklist.contains(&staker_id) && !self.internal_emergency_return(&staker_id) {
+                return Some(index);
+            }
+            index += 1;
+        }
+        None
+    }
+
+    // Returns the records of `staker_id` while gas lasts. True once only tokenized ones are left.
+    fn internal_emergency_return(&mut self, staker_id: &AccountId) -> bool {
+        self.internal_checkpoint_staker(staker_id);
+        let initial_storage = env::storage_usage();
+        let mut staker_info = self.stakers.get(staker_id).unwrap();
+        let transfer_gas = GAS_FOR_NFT_TRANSFER.saturating_add(GAS_FOR_RESOLVE_TRANSFER).as_gas();
+        let mut returned = vec![];
+        // Walks the records from the end, so swap_remove only moves records already visited
+        let mut index = staker_info.stakes.len();
+        while index > 0 {
+            let stake = staker_info.stakes.get(index - 1).unwrap();
+            if stake.tokenized {
+                index -= 1;
+                continue;
+            }
+            let required =
+                transfer_gas * stake.nft_ids.len() as u64 + GAS_FOR_EMERGENCY_RETURN.as_gas();
+            let remaining = env::prepaid_gas().as_gas().saturating_sub(env::used_gas().as_gas());
+            if remaining < required {
+                break;
+            }
+            index -= 1;
+            staker_info.stakes.swap_remove(index);
+            // Vesting is cut short as well, so the record's rewards are owed in full
+            staker_info.unclaimed_rewards += stake.claimed_rewards;
+            for nft_id in stake.nft_ids.iter() {
+                self.internal_remove_staked_token(nft_id, &stake);
+            }
+            returned.push(stake);
+        }
+        let done = index == 0;
+        self.stakers.insert(staker_id, &staker_info);
+        self.internal_update_storage(staker_id, initial_storage, false);
+        self.internal_checkpoint_staker(staker_id);
+
+        for stake in returned.iter() {
+            StakingEvent::EmergencyReturn {
+                account_id: staker_id.clone(),
+                token_ids: stake.nft_ids.clone(),
+                rewards: U128(stake.claimed_rewards),
+            }
+            .emit();
+            for nft_id in stake.nft_ids.iter() {
+                self.internal_transfer_nft(staker_id, nft_id, stake);
+            }
+        }
+        done
+    }
+
+    // Pauses or resumes a single operation independently of the global switch
+    pub fn set_operation_paused(&mut self, operation: Operation, paused: bool) {
+        self.assert_role(Role::Owner);
+        self.paused_operations.retain(|op| *op != operation);
+        if paused {
+            self.paused_operations.push(operation);
+        }
+        StakingEvent::ConfigUpdate {
+            parameter: "operation_paused".to_string(),
+            value: json!({ "operation": operation, "paused": paused }),
+        }
+        .emit();
+    }
+
+    pub fn get_paused_operations(&self) -> Vec<Operation> {
+        self.paused_operations.clone()
+    }
+
+    pub fn is_operation_paused(&self, operation: Operation) -> bool {
+        self.paused || self.paused_operations.contains(&operation)
+    }
+
+    fn assert_operation_active(&self, operation: Operation) {
+        assert!(!self.paused, "Contract is paused");
+        assert!(
+            !self.paused_operations.contains(&operation),
+            "{:?} is paused",
+            operation
+        );
+    }
+
+    // Bars an account from staking, claiming, unstaking and moving its records, e.g. while a
+    // compromise is investigated. Its records keep earning and can be claimed once it is removed.
+    pub fn add_to_blacklist(&mut self, account_id: AccountId) {
+        self.assert_role(Role::Owner);
+        assert!(self.blacklist.insert(&account_id), "Account is already blacklisted");
+        StakingEvent::ConfigUpdate {
+            parameter: "blacklist_added".to_string(),
+            value: json!(account_id),
+        }
+        .emit();
+    }
+
+    pub fn remove_from_blacklist(&mut self, account_id: AccountId) {
+        self.assert_role(Role::Owner);
+        assert!(self.blacklist.remove(&account_id), "Account is not blacklisted");
+        StakingEvent::ConfigUpdate {
+            parameter: "blacklist_removed".to_string(),
+            value: json!(account_id),
+        }
+        .emit();
+    }
+
+    pub fn is_blacklisted(&self, account_id: AccountId) -> bool {
+        self.blacklist.contains(&account_id)
+    }
+
+    pub fn get_blacklist(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<AccountId> {
+        let from_index = from_index.unwrap_or(0);
+        self.blacklist
+            .iter()
+            .skip(from_index as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .collect()
+    }
+
+    fn assert_not_blacklisted(&self, account_id: &AccountId) {
+        assert!(
+            !self.blacklist.contains(account_id),
+            "Account {} is blacklisted",
+            account_id
+        );
+    }
+
+    // Flags a token, e.g. one reported stolen. It can no longer be staked and, if already staked,
+    // stops carrying reward weight while it stays on its record.
+    pub fn add_token_to_blacklist(&mut self, token_id: String) {
+        self.assert_role(Role::Owner);
+        self.internal_set_token_blacklisted(token_id, true);
+    }
+
+    pub fn remove_token_from_blacklist(&mut self, token_id: String) {
+        self.assert_role(Role::Owner);
+        self.internal_set_token_blacklisted(token_id, false);
+    }
+
+    fn internal_set_token_blacklisted(&mut self, token_id: String, blacklisted: bool) {
+        let staker_id = self.staked_tokens.get(&token_id);
+        if staker_id.is_some() {
+            // The holder's weight changes, which would skew a running round
+            self.assert_no_active_distribution();
+        }
+        if blacklisted {
+            assert!(self.token_blacklist.insert(&token_id), "NFT is already blacklisted");
+        } else {
+            assert!(self.token_blacklist.remove(&token_id), "NFT is not blacklisted");
+        }
+        if let Some(staker_id) = staker_id {
+            // Settle the emission earned so far at the old weight
+            self.internal_checkpoint_staker(&staker_id);
+        }
+        StakingEvent::ConfigUpdate {
+            parameter: if blacklisted { "token_blacklist_added" } else { "token_blacklist_removed" }
+                .to_string(),
+            value: json!(token_id),
+        }
+        .emit();
+    }
+
+    // Takes a blacklisted token off the record it is staked on and sends it to `escrow_id` for
+    // recovery. A record left empty is removed and its pending rewards stay with the staker as
+    // unclaimed rewards. Should the transfer fail, the token can be sent again with
+    // `return_unrecorded_nft`. Tokenized records must be redeemed first.
+    pub fn recover_blacklisted_nft(&mut self, token_id: String, escrow_id: AccountId) -> Promise {
+        self.assert_role(Role::Owner);
+        assert!(self.token_blacklist.contains(&token_id), "NFT is not blacklisted");
+        self.assert_no_active_distribution();
+        let staker_id = self.staked_tokens.get(&token_id).expect("NFT is not staked");
+        self.internal_checkpoint_staker(&staker_id);
+        let initial_storage = env::storage_usage();
+        let mut staker_info = self.stakers.get(&staker_id).unwrap();
+        let stake_index = staker_info
+            .stakes
+            .iter()
+            .position(|stake| stake.nft_ids.contains(&token_id))
+            .unwrap() as u64;
+        let mut stake = staker_info.stakes.get(stake_index).unwrap();
+        stake.assert_not_tokenized();
+        self.internal_remove_staked_token(&token_id, &stake);
+        let (nft_contract, collection_token_id) = self.internal_token_location(&stake, &token_id);
+        if stake.nft_ids.len() == 1 {
+            staker_info.unclaimed_rewards += stake.claimed_rewards;
+            staker_info.stakes.swap_remove(stake_index);
+        } else {
+            stake.nft_ids.retain(|id| *id != token_id);
+            stake.nft_types.remove(&token_id);
+            stake.rarity_scores.remove(&token_id);
+            stake.nft_contracts.remove(&token_id);
+            stake.staked_at.remove(&token_id);
+            staker_info.stakes.replace(stake_index, &stake);
+        }
+        self.stakers.insert(&staker_id, &staker_info);
+        self.internal_update_storage(&staker_id, initial_storage, false);
+        self.internal_checkpoint_staker(&staker_id);
+
+        StakingEvent::NftRecovered {
+            account_id: staker_id.clone(),
+            token_id: token_id.clone(),
+            escrow_id: escrow_id.clone(),
+        }
+        .emit();
+        Promise::new(nft_contract).function_call(
+            "nft_transfer".to_string(),
+            serde_json::to_vec(&json!({
+                "receiver_id": escrow_id,
+                "token_id": collection_token_id
+            }))
+            .unwrap(),
+            NearToken::from_yoctonear(1), // Attach 1 yoctoNEAR
+            GAS_FOR_NFT_TRANSFER,
+        )
+    }
+
+    pub fn is_token_blacklisted(&self, token_id: String) -> bool {
+        self.token_blacklist.contains(&token_id)
+    }
+
+    pub fn get_token_blacklist(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<String> {
+        let from_index = from_index.unwrap_or(0);
+        self.token_blacklist
+            .iter()
+            .skip(from_index as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .collect()
+    }
+
+    // Limits staking to whitelisted accounts, e.g. for a private beta. Existing records are not
+    // affected.
+    pub fn set_whitelist_only(&mut self, whitelist_only: bool) {
+        self.assert_role(Role::Owner);
+        self.whitelist_only = whitelist_only;
+        StakingEvent::ConfigUpdate {
+            parameter: "whitelist_only".to_string(),
+            value: json!(whitelist_only),
+        }
+        .emit();
+    }
+
+    pub fn add_to_whitelist(&mut self, account_id: AccountId) {
+        self.assert_role(Role::Owner);
+        assert!(self.whitelist.insert(&account_id), "Account is already whitelisted");
+        StakingEvent::ConfigUpdate {
+            parameter: "whitelist_added".to_string(),
+            value: json!(account_id),
+        }
+        .emit();
+    }
+
+    pub fn remove_from_whitelist(&mut self, account_id: AccountId) {
+        self.assert_role(Role::Owner);
+        assert!(self.whitelist.remove(&account_id), "Account is not whitelisted");
+        StakingEvent::ConfigUpdate {
+            parameter: "whitelist_removed".to_string(),
+            value: json!(account_id),
+        }
+        .emit();
+    }
+
+    pub fn is_whitelist_only(&self) -> bool {
+        self.whitelist_only
+    }
+
+    pub fn is_whitelisted(&self, account_id: AccountId) -> bool {
+        self.whitelist.contains(&account_id)
+    }
+
+    pub fn get_whitelist(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<AccountId> {
+        let from_index = from_index.unwrap_or(0);
+        self.whitelist
+            .iter()
+            .skip(from_index as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .collect()
+    }
+
+    // First step of an ownership transfer; the new owner must call `accept_ownership`
+    pub fn propose_owner(&mut self, new_owner: AccountId) {
+        self.assert_role(Role::Owner);
+        self.pending_owner = Some(new_owner.clone());
+        StakingEvent::ConfigUpdate {
+            parameter: "pending_owner".to_string(),
+            value: json!(new_owner),
+        }
+        .emit();
+    }
+
+    pub fn cancel_ownership_transfer(&mut self) {
+        self.assert_role(Role::Owner);
+        assert!(self.pending_owner.take().is_some(), "No ownership transfer pending");
+        StakingEvent::ConfigUpdate {
+            parameter: "pending_owner".to_string(),
+            value: Value::Null,
+        }
+        .emit();
+    }
+
+    pub fn accept_ownership(&mut self) {
+        let caller = env::predecessor_account_id();
+        assert_eq!(
+            self.pending_owner.as_ref(),
+            Some(&caller),
+            "Only the proposed owner can accept ownership"
+        );
+        self.owner = caller;
+        self.pending_owner = None;
+        StakingEvent::ConfigUpdate {
+            parameter: "owner".to_string(),
+            value: json!(self.owner),
+        }
+        .emit();
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner.clone()
+    }
+
+    pub fn grant_role(&mut self, account_id: AccountId, role: Role) {
+        self.assert_role(Role::Owner);
+        assert!(role != Role::Owner, "Ownership is transferred with propose_owner");
+        let mut roles = self.roles.get(&account_id).unwrap_or_default();
+        if !roles.contains(&role) {
+            roles.push(role);
+            self.roles.insert(&account_id, &roles);
+        }
+        StakingEvent::ConfigUpdate {
+            parameter: "role_granted".to_string(),
+            value: json!({ "account_id": account_id, "role": role }),
+        }
+        .emit();
+    }
+
+    pub fn revoke_role(&mut self, account_id: AccountId, role: Role) {
+        self.assert_role(Role::Owner);
+        let mut roles = self.roles.get(&account_id).expect("Account has no roles");
+        assert!(roles.contains(&role), "Account does not have this role");
+        roles.retain(|r| *r != role);
+        if roles.is_empty() {
+            self.roles.remove(&account_id);
+        } else {
+            self.roles.insert(&account_id, &roles);
+        }
+        StakingEvent::ConfigUpdate {
+            parameter: "role_revoked".to_string(),
+            value: json!({ "account_id": account_id, "role": role }),
+        }
+        .emit();
+    }
+
+    pub fn has_role(&self, account_id: AccountId, role: Role) -> bool {
+        self.internal_has_role(&account_id, role)
+    }
+
+    fn internal_has_role(&self, account_id: &AccountId, role: Role) -> bool {
+        *account_id == self.owner
+            || self
+                .roles
+                .get(account_id)
+                .is_some_and(|roles| roles.contains(&role))
+    }
+
+    pub fn get_roles(&self, account_id: AccountId) -> Vec<Role> {
+        if account_id == self.owner {
+            return vec![Role::Owner, Role::Distributor, Role::Treasurer];
+        }
+        self.roles.get(&account_id).unwrap_or_default()
+    }
+
+    pub fn get_role_members(
+        &self,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<(AccountId, Vec<Role>)> {
+        let from_index = from_index.unwrap_or(0);
+        self.roles
+            .iter()
+            .skip(from_index as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .collect()
+    }
+
+    fn assert_role(&self, role: Role) {
+        assert!(
+            self.internal_has_role(&env::predecessor_account_id(), role),
+            "Caller does not have the {:?} role",
+            role
+        );
+    }
+
+    pub fn get_config(&self) -> ContractConfig {
+        ContractConfig {
+            owner: self.owner.clone(),
+            sin_token: self.sin_token.clone(),
+            sin_nft_contract: self.sin_nft_contract.clone(),
+            distribution_interval: self.distribution_interval,
+            reward_per_round: U128(self.reward_per_round),
+            keeper_bounty_bps: self.keeper_bounty_bps,
+            timelock_duration: self.timelock_duration,
+            set_bonus_bps: self.set_bonus_bps,
+            early_unstake_penalty_bps: self.early_unstake_penalty_bps,
+            early_unstake_fee_bps: self.early_unstake_fee_bps,
+            flexible_weight_bps: self.flexible_weight_bps,
+            position_manager: self.position_manager.clone(),
+            rarity_oracle: self.rarity_oracle.clone(),
+            max_nfts_per_account: self.max_nfts_per_account,
+            max_new_stakes_per_day: self.max_new_stakes_per_day,
+            emission_rate_per_second: U128(self.emission.rate_per_second),
+            emission_halving_interval: self.emission.halving_interval,
+            type_pool_shares: self.type_pool_shares.clone(),
+            type_caps: self.type_caps.clone(),
+            claim_fee_bps: self.claim_fee_bps,
+            treasury: self.treasury.clone(),
+            compound_unit: U128(self.compound_unit),
+            compound_boost_bps: self.compound_boost_bps,
+            max_compound_boost_bps: self.max_compound_boost_bps,
+            reward_vesting_period: self.reward_vesting_period,
+            claim_cooldown: self.claim_cooldown,
+            min_claim_amount: U128(self.min_claim_amount),
+            reward_expiry: self.reward_expiry,
+            max_account_share_bps: self.max_account_share_bps,
+            min_stake_age: self.min_stake_age,
+            scarcity_weights: self.scarcity_weights,
+            max_reward_pool: self.max_reward_pool.map(U128),
+            community_funding: self.community_funding,
+        }
+    }
+
+    pub fn get_last_reward_distribution(&self) -> U64 {
+        U64(self.last_distributed)
+    }
+
+    // Seconds that must pass between two rounds
+    pub fn get_distribution_interval(&self) -> u64 {
+        self.distribution_interval
+    }
+
+    pub fn get_next_reward_distribution(&self) -> NextDistribution {
+        let now = env::block_timestamp();
+        let next_distribution = self.last_distributed + self.distribution_interval * 1_000_000_000;
+        NextDistribution {
+            timestamp: U64(next_distribution),
+            seconds_remaining: next_distribution.saturating_sub(now) / 1_000_000_000,
+        }
+    }
+    pub fn get_active_distribution(&self) -> Option<DistributionRoundView> {
+        self.active_distribution
+            .as_ref()
+            .map(|round| DistributionRoundView {
+                amount: U128(round.amount),
+                phase: round.phase.clone(),
+                cursor: round.cursor,
+                total_weight: U128(round.total_weight),
+                started_at: U64(round.started_at),
+                staker_count: round.staker_count,
+                type_amounts: round
+                    .type_amounts
+                    .iter()
+                    .map(|(nft_type, amount)| (nft_type.clone(), U128(*amount)))
+                    .collect(),
+                capped_accounts: round.capped_rewards.keys().cloned().collect(),
+            })
+    }
+
+    // Completed rounds, oldest first
+    pub fn get_distribution_history(
+        &self,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<DistributionRecord> {
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(50);
+        (from_index..self.distribution_history.len().min(from_index.saturating_add(limit)))
+            .map(|index| self.distribution_history.get(index).unwrap())
+            .collect()
+    }
+
+    pub fn get_distribution_count(&self) -> u64 {
+        self.distribution_history.len()
+    }
+
+    pub fn get_available_reward(&self) -> U128 {
+        U128(self.reward_distribution.total_reward_pool)
+    }
+
+    // Splits the SIN held for rewards into what is still free and what is already committed to
+    // rounds or owed to stakers. Only the unallocated pool can be distributed.
+    pub fn get_pool_accounting(&self) -> PoolAccounting {
+        let emitted = if self.emission.total_weight > 0 {
+            self.internal_emitted_since_update(env::block_timestamp())
+        } else {
+            0
+        };
+        let active_round = self
+            .active_distribution
+            .as_ref()
+            .map_or(0, |round| round.amount - round.distributed);
+        PoolAccounting {
+            unallocated_pool: U128(self.reward_distribution.total_reward_pool - emitted),
+            reserved_for_rounds: U128(active_round + self.carried_remainder),
+            pending_claims_total: U128(self.pending_claims_total + emitted),
+        }
+    }
+
+    // SIN the contract should hold: the pool, round reservations, what is owed to stakers and
+    // what is left of SIN bonus farms
+    fn internal_accounted_sin(&self) -> Balance {
+        let active_round = self
+            .active_distribution
+            .as_ref()
+            .map_or(0, |round| round.amount - round.distributed);
+        let campaigns: Balance = self
+            .campaigns
+            .values()
+            .filter(|campaign| campaign.token_id == self.sin_token)
+            .map(|campaign| campaign.total_reward - campaign.claimed)
+            .sum();
+        self.reward_distribution.total_reward_pool
+            + active_round
+            + self.carried_remainder
+            + self.pending_claims_total
+            + campaigns
+    }
+
+    // Compares the contract's SIN balance with its accounting. The result is stored for
+    // `get_last_reconciliation` and a discrepancy is flagged with an event. Callable by anyone.
+    pub fn reconcile(&mut self) -> Promise {
+        Promise::new(self.sin_token.clone())
+            .function_call(
+                "ft_balance_of".to_string(),
+                serde_json::to_vec(&json!({ "account_id": env::current_account_id() })).unwrap(),
+                NearToken::from_yoctonear(0),
+                GAS_FOR_FT_BALANCE_OF,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RECONCILE)
+                    .reconcile_callback(),
+            )
+    }
+
+    #[private]
+    pub fn reconcile_callback(
+        &mut self,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) -> Reconciliation {
+        let balance = balance.expect("Failed to fetch the SIN balance").0;
+        // Transfers still in flight are in the balance until they resolve, but no longer in the
+        // accounting
+        let accounted = self.internal_accounted_sin() + self.sin_in_flight;
+        let reconciliation = Reconciliation {
+            timestamp: U64(env::block_timestamp()),
+            balance: U128(balance),
+            accounted: U128(accounted),
+            surplus: U128(balance.saturating_sub(accounted)),
+            deficit: U128(accounted.saturating_sub(balance)),
+        };
+        if balance != accounted {
+            StakingEvent::BalanceDiscrepancy {
+                balance: U128(balance),
+                accounted: U128(accounted),
+            }
+            .emit();
+        }
+        env::log_str(&format!(
+            "SIN balance {}, accounted for {}",
+            balance, accounted
+        ));
+        self.last_reconciliation = Some(reconciliation.clone());
+        reconciliation
+    }
+
+    pub fn get_last_reconciliation(&self) -> Option<Reconciliation> {
+        self.last_reconciliation.clone()
+    }
+
+    // Recovers NEP-141 tokens sent to the contract with a plain `ft_transfer`. Only the part of
+    // the balance not owed to stakers or sponsors can be rescued: for SIN everything the
+    // accounting holds, for other tokens what is left of their bonus farms.
+    pub fn rescue_ft(
+        &mut self,
+        token_contract: AccountId,
+        amount: U128,
+        receiver_id: AccountId,
+    ) -> Promise {
+        self.assert_role(Role::Owner);
+        assert!(amount.0 > 0, "Rescue amount must be greater than zero");
+        // Unclaimed rewards of these tokens are not tracked as a total
+        assert!(
+            self.reward_tokens.get(&token_contract).is_none(),
+            "Registered reward tokens can't be rescued"
+        );
+        Promise::new(token_contract.clone())
+            .function_call(
+                "ft_balance_of".to_string(),
+                serde_json::to_vec(&json!({ "account_id": env::current_account_id() })).unwrap(),
+                NearToken::from_yoctonear(0),
+                GAS_FOR_FT_BALANCE_OF,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_RESCUE)
+                    .rescue_ft_callback(token_contract, amount, receiver_id),
+            )
+    }
+
+    #[private]
+    pub fn rescue_ft_callback(
+        &mut self,
+        token_contract: AccountId,
+        amount: U128,
+        receiver_id: AccountId,
+        #[callback_result] balance: Result<U128, PromiseError>,
+    ) {
+        let balance = balance.expect("Failed to fetch the token balance").0;
+        // SIN of claims that are still being transferred is in the balance but no longer accounted
+        let owed = if token_contract == self.sin_token {
+            self.internal_accounted_sin() + self.sin_in_flight
+        } else {
+            self.campaigns
+                .values()
+                .filter(|campaign| campaign.token_id == token_contract)
+                .map(|campaign| campaign.total_reward - campaign.claimed)
+                .sum()
+        };
+        let rescuable = balance.saturating_sub(owed);
+        assert!(
+            amount.0 <= rescuable,
+            "Only {} of the balance is not owed and can be rescued",
+            rescuable
+        );
+        StakingEvent::TokensRescued {
+            token_id: token_contract.clone(),
+            receiver_id: receiver_id.clone(),
+            amount,
+        }
+        .emit();
+        Promise::new(token_contract).function_call(
+            "ft_transfer".to_string(),
+            serde_json::to_vec(&json!({
+                "receiver_id": receiver_id,
+                "amount": amount,
+            }))
+            .unwrap(),
+            NearToken::from_yoctonear(1), // Attach 1 yoctoNEAR
+            GAS_FOR_FT_TRANSFER,
+        );
+    }
+
+    // Sends back an NFT that was transferred with a plain `nft_transfer` and so never staked.
+    // `nft_contract` defaults to the SIN collection. Staked tokens can't be returned this way.
+    pub fn return_unrecorded_nft(
+        &mut self,
+        token_id: String,
+        receiver_id: AccountId,
+        nft_contract: Option<AccountId>,
+    ) -> Promise {
+        self.assert_role(Role::Owner);
+        let nft_contract = nft_contract.unwrap_or_else(|| self.sin_nft_contract.clone());
+        assert!(
+            self.staked_tokens
+                .get(&self.internal_token_key(&nft_contract, &token_id))
+                .is_none(),
+            "NFT is staked and can only be returned by unstaking it"
+        );
+        StakingEvent::NftReturned {
+            nft_contract: nft_contract.clone(),
+            token_id: token_id.clone(),
+            receiver_id: receiver_id.clone(),
+        }
+        .emit();
+        Promise::new(nft_contract).function_call(
+            "nft_transfer".to_string(),
+            serde_json::to_vec(&json!({
+                "receiver_id": receiver_id,
+                "token_id": token_id
+            }))
+            .unwrap(),
+            NearToken::from_yoctonear(1), // Attach 1 yoctoNEAR
+            GAS_FOR_NFT_TRANSFER,
+        )
+    }
+
+    // Consistency checks for monitoring over a page of stakers; check every page for the whole
+    // contract. The balance is checked against the accounting of the last `reconcile`. Any
+    // violation is listed in the report and clears `healthy`.
+    pub fn check_invariants(&self, from_index: Option<u64>, limit: Option<u64>) -> InvariantReport {
+        let mut violations = vec![];
+        let accounting = self.get_pool_accounting();
+        let acc = self.internal_current_emission_acc();
+
+        let mut staker_rewards = 0;
+        let mut staked_nfts: HashSet<String> = HashSet::new();
+        for account_id in self.internal_staker_page(from_index, limit) {
+            let staker_info = self.stakers.get(&account_id).unwrap();
+            staker_rewards += staker_info.unclaimed_rewards;
+            for (stake_index, stake) in staker_info.stakes.iter().enumerate() {
+                staker_rewards +=
+                    stake.claimed_rewards + Self::internal_unsettled_emission(&stake, acc);
+                if stake.nft_types.len() != stake.nft_ids.len() {
+                    violations.push(format!(
+                        "Record {} of {} has {} NFTs but {} types",
+                        stake_index,
+                        account_id,
+                        stake.nft_ids.len(),
+                        stake.nft_types.len()
+                    ));
+                }
+                for nft_id in stake.nft_ids.iter() {
+                    if !staked_nfts.insert(nft_id.clone()) {
+                        violations
+                            .push(format!("NFT {} is staked in more than one record", nft_id));
+                    }
+                    if self.staked_tokens.get(nft_id).as_ref() != Some(&account_id) {
+                        violations.push(format!("NFT {} is not indexed to {}", nft_id, account_id));
+                    }
+                }
+            }
+        }
+        if staker_rewards > accounting.pending_claims_total.0 {
+            violations.push(format!(
+                "Staker rewards of {} exceed the tracked liability of {}",
+                staker_rewards, accounting.pending_claims_total.0
+            ));
+        }
+
+        // SIN missing from the balance at the last reconciliation is owed but can't be paid
+        match self.last_reconciliation.as_ref() {
+            Some(reconciliation) if reconciliation.deficit.0 > 0 => violations.push(format!(
+                "The balance of {} fell {} short of the accounted {} at {}",
+                reconciliation.balance.0,
+                reconciliation.deficit.0,
+                reconciliation.accounted.0,
+                reconciliation.timestamp.0
+            )),
+            Some(_) => {}
+            None => violations.push("The balance was never reconciled".to_string()),
+        }
+
+        InvariantReport {
+            healthy: violations.is_empty(),
+            staker_rewards: U128(staker_rewards),
+            pending_claims_total: accounting.pending_claims_total,
+            accounted: U128(self.internal_accounted_sin()),
+            last_reconciliation: self.last_reconciliation.clone(),
+            staked_nfts: staked_nfts.len() as u64,
+            violations,
+        }
+    }
+    // Folds up to `limit` of the oldest SIN funding records older than `older_than_days` into
+    // per-month totals, keeping storage and `get_funding_details` bounded. Records are in funding
+    // order, so repeated calls archive them oldest first. Returns the number of records archived.
+    pub fn archive_funding_records(&mut self, older_than_days: u32, limit: Option<u64>) -> u64 {
+        self.assert_role(Role::Treasurer);
+        assert!(older_than_days > 0, "Only records older than a day can be archived");
+        let cutoff = env::block_timestamp()
+            .saturating_sub(older_than_days as u64 * DAY * 1_000_000_000);
+
+        let funding_records = &mut self.reward_distribution.funding_records;
+        let mut archived = 0;
+        while archived < limit.unwrap_or(50) {
+            let record = match funding_records.get(0) {
+                Some(record) if record.timestamp < cutoff => record,
+                _ => break,
+            };
+            funding_records.pop_front();
+            archived += 1;
+            add_to_funding_periods(&mut self.funding_archive, record.timestamp, record.amount);
+        }
+        env::log_str(&format!("Archived {} funding records", archived));
+        archived
+    }
+
+    pub fn get_funding_archive(
+        &self,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<FundingPeriod> {
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(50);
+        (from_index..self.funding_archive.len().min(from_index.saturating_add(limit)))
+            .map(|index| self.funding_archive.get(index).unwrap())
+            .collect()
+    }
+
+    // Treasury totals for dashboards: lifetime funding and distribution, and a page of the
+    // funding per month. All of it is kept as running totals, archived records included.
+    pub fn get_funding_summary(
+        &self,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> FundingSummary {
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(50);
+        FundingSummary {
+            total_funded: U128(self.total_funded),
+            total_distributed: U128(self.total_rewards_distributed),
+            period_count: self.funding_periods.len(),
+            monthly: (from_index..self.funding_periods.len().min(from_index.saturating_add(limit)))
+                .map(|index| self.funding_periods.get(index).unwrap())
+                .collect(),
+        }
+    }
+
+    pub fn get_funding_details(&self, from_index: Option<u64>, limit: Option<u64>) -> FundingDetails {
+        let funding_records = &self.reward_distribution.funding_records;
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(50);
+        FundingDetails {
+            total_count: funding_records.len(),
+            records: (from_index..funding_records.len().min(from_index.saturating_add(limit)))
+                .map(|index| {
+                    let record = funding_records.get(index).unwrap();
+                    FundingView {
+                        amount: U128(record.amount),
+                        timestamp: U64(record.timestamp),
+                        sender_id: record.sender_id,
+                        memo: record.memo,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for NFTStakingContract {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let amount = env::attached_deposit().as_yoctonear();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+
+        if let Some(mut account) = self.storage_accounts.get(&account_id) {
+            if registration_only.unwrap_or(false) {
+                if amount > 0 {
+                    Promise::new(env::predecessor_account_id())
+                        .transfer(NearToken::from_yoctonear(amount));
+                }
+            } else {
+                account.deposit += amount;
+                self.total_storage_deposits += amount;
+                self.storage_accounts.insert(&account_id, &account);
+            }
         } else {
-            0
+            let min_deposit = STORAGE_MIN_DEPOSIT.as_yoctonear();
+            assert!(
+                amount >= min_deposit,
+                "The attached deposit is less than the minimum storage balance"
+            );
+            let deposit = if registration_only.unwrap_or(false) {
+                let refund = amount - min_deposit;
+                if refund > 0 {
+                    Promise::new(env::predecessor_account_id())
+                        .transfer(NearToken::from_yoctonear(refund));
+                }
+                min_deposit
+            } else {
+                amount
+            };
+
+            self.total_storage_deposits += deposit;
+            let initial_storage = env::storage_usage();
+            self.storage_accounts.insert(
+                &account_id,
+                &AccountStorage {
+                    deposit,
+                    used_bytes: 0,
+                },
+            );
+            self.internal_update_storage(&account_id, initial_storage, true);
         }
+
+        self.storage_balance_of(account_id).unwrap()
     }
-    pub fn get_available_reward(&self) -> u128 {
-        self.reward_distribution.total_reward_pool
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account = self
+            .storage_accounts
+            .get(&account_id)
+            .expect("Account is not registered");
+
+        let available = account.available();
+        let amount = amount.map_or(available, |amount| amount.as_yoctonear());
+        assert!(
+            amount <= available,
+            "The amount is greater than the available storage balance"
+        );
+
+        if amount > 0 {
+            account.deposit -= amount;
+            self.total_storage_deposits -= amount;
+            self.storage_accounts.insert(&account_id, &account);
+            Promise::new(account_id.clone()).transfer(NearToken::from_yoctonear(amount));
+        }
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        assert!(
+            !force.unwrap_or(false),
+            "Force unregistration is not supported, unstake and claim first"
+        );
+        let account_id = env::predecessor_account_id();
+        let Some(account) = self.storage_accounts.get(&account_id) else {
+            return false;
+        };
+
+        if let Some(staker_info) = self.stakers.get(&account_id) {
+            assert!(
+                staker_info.stakes.is_empty()
+                    && staker_info.unclaimed_rewards == 0
+                    && staker_info.token_rewards.values().all(|reward| reward.unclaimed == 0)
+                    && staker_info.near_reward.unclaimed == 0
+                    && staker_info.campaign_rewards.iter().all(|(campaign_id, reward)| {
+                        reward.unclaimed == 0 || self.campaigns.get(campaign_id).is_none()
+                    }),
+                "Cannot unregister an account with active stakes or unclaimed rewards"
+            );
+            // Removing from the staker map reorders it, which would corrupt a running round
+            self.assert_no_active_distribution();
+            let mut claims = staker_info.claims;
+            claims.clear();
+            self.stakers.remove(&account_id);
+        }
+        self.storage_accounts.remove(&account_id);
+        self.total_storage_deposits -= account.deposit;
+
+        Promise::new(account_id.clone()).transfer(NearToken::from_yoctonear(account.deposit));
+        env::log_str(&format!(
+            "Unregistered {} and refunded {} yoctoNEAR",
+            account_id, account.deposit
+        ));
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: STORAGE_MIN_DEPOSIT,
+            max: None,
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_accounts
+            .get(&account_id)
+            .map(|account| StorageBalance {
+                total: NearToken::from_yoctonear(account.deposit),
+                available: NearToken::from_yoctonear(account.available()),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    const START: u64 = 1_000; // Seconds
+
+    fn account(name: &str) -> AccountId {
+        name.parse().unwrap()
+    }
+
+    fn set_context(predecessor: &str, seconds: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(account("staking.near"))
+            .predecessor_account_id(account(predecessor))
+            .block_timestamp(seconds * 1_000_000_000)
+            .build());
     }
-    pub fn get_funding_details(&self) -> Vec<FundingRecord> {
-        self.reward_distribution
-            .funding_records
+
+    // A contract with its config changes applied right away and its rounds a day apart, funded
+    // with `pool` SIN
+    fn setup(pool: Balance) -> NFTStakingContract {
+        set_context("owner.near", START);
+        let (owner, sin_token) = (account("owner.near"), account("sin.near"));
+        let mut contract = NFTStakingContract::new(owner, sin_token, account("nft.near"));
+        contract.timelock_duration = 0;
+        contract.set_distribution_interval(DAY);
+        apply_changes(&mut contract);
+        set_context("sin.near", START);
+        contract.ft_on_transfer(account("owner.near"), U128(pool), String::new());
+        contract
+    }
+
+    fn apply_changes(contract: &mut NFTStakingContract) {
+        for pending in contract.get_pending_changes() {
+            contract.execute_config_change(pending.id);
+        }
+    }
+
+    fn attributes(traits: &[(&str, &str)]) -> Value {
+        let attributes: Vec<Value> = traits
             .iter()
-            .collect::<Vec<FundingRecord>>()
+            .map(|(trait_type, value)| json!({ "trait_type": trait_type, "value": value }))
+            .collect();
+        json!({ "attributes": attributes })
+    }
+
+    fn set_deposit_context(predecessor: &str, deposit: NearToken) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(account("staking.near"))
+            .predecessor_account_id(account(predecessor))
+            .attached_deposit(deposit)
+            .block_timestamp(START * 1_000_000_000)
+            .build());
+    }
+
+    // Registers the account with the minimum storage deposit
+    fn register(contract: &mut NFTStakingContract, account_id: &str) {
+        set_deposit_context(account_id, STORAGE_MIN_DEPOSIT);
+        contract.storage_deposit(None, None);
+    }
+
+    fn stake(
+        contract: &mut NFTStakingContract,
+        staker_id: &str,
+        nft_contract: &str,
+        token_id: &str,
+        metadata: Value,
+    ) {
+        let returned = try_stake(contract, staker_id, nft_contract, token_id, metadata);
+        assert!(!returned, "NFT {} was returned", token_id);
+    }
+
+    // Resolves a stake of the NFT, returning whether it was sent back
+    fn try_stake(
+        contract: &mut NFTStakingContract,
+        staker_id: &str,
+        nft_contract: &str,
+        token_id: &str,
+        metadata: Value,
+    ) -> bool {
+        set_context("staking.near", START);
+        let nft_contract = account(nft_contract);
+        let token_key = contract.internal_token_key(&nft_contract, token_id);
+        let token = json!({ "owner_id": "staking.near", "metadata": metadata });
+        contract.resolve_nft_stake(
+            account(staker_id),
+            nft_contract,
+            token_key,
+            StakeMsg::default(),
+            Ok(Some(token)),
+        )
+    }
+
+    // A callback of the contract whose promise failed
+    fn set_failed_callback_context(seconds: u64) {
+        testing_env!(
+            VMContextBuilder::new()
+                .current_account_id(account("staking.near"))
+                .predecessor_account_id(account("staking.near"))
+                .block_timestamp(seconds * 1_000_000_000)
+                .build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            HashMap::default(),
+            vec![PromiseResult::Failed]
+        );
+    }
+
+    fn distribute(contract: &mut NFTStakingContract, amount: Balance, seconds: u64) {
+        set_context("owner.near", seconds);
+        contract.distribute_rewards(U128(amount));
+    }
+
+    fn record_rewards(contract: &NFTStakingContract, staker_id: &str) -> Vec<Balance> {
+        let staker_info = contract.stakers.get(&account(staker_id)).unwrap();
+        staker_info.stakes.iter().map(|stake| stake.claimed_rewards).collect()
+    }
+
+    #[test]
+    fn token_distribution_dust_is_carried_forward() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        let total_weight = contract.emission.total_weight;
+        let usdc = account("usdc.near");
+        set_context("owner.near", START);
+        contract.add_reward_token(usdc.clone());
+        set_context("usdc.near", START);
+        contract.ft_on_transfer(account("owner.near"), U128(10), String::new());
+        set_context("owner.near", START);
+        contract.distribute_token_rewards(usdc.clone(), U128(3));
+        contract.distribute_token_rewards(usdc.clone(), U128(7));
+
+        let token_rewards = contract.reward_tokens.get(&usdc).unwrap();
+        let scaled =
+            token_rewards.acc_reward_per_weight * total_weight + token_rewards.acc_remainder;
+        assert_eq!(scaled, 10 * EMISSION_PRECISION);
+    }
+
+    #[test]
+    fn near_distribution_dust_is_carried_forward() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        let total_weight = contract.emission.total_weight;
+        contract.near_rewards.distribution.total_reward_pool = 10;
+        set_context("owner.near", START);
+        contract.distribute_near_rewards(U128(3));
+        contract.distribute_near_rewards(U128(7));
+
+        let near_rewards = &contract.near_rewards;
+        let scaled = near_rewards.acc_reward_per_weight * total_weight + near_rewards.acc_remainder;
+        assert_eq!(scaled, 10 * EMISSION_PRECISION);
+    }
+
+    #[test]
+    fn migrate_rebuilds_totals_from_v1_state() {
+        set_context("staking.near", START);
+        let staker_id = account("alice.near");
+        let mut stakes = Vector::new(format!("stakes_{}", staker_id).as_bytes().to_vec());
+        for (token_id, nft_type, claimed_rewards) in [("1", "Queen", 700), ("2", "Drone", 300)] {
+            stakes.push(&NFTStakingRecordV1 {
+                nft_ids: vec![token_id.to_string()],
+                nft_types: HashMap::from([(token_id.to_string(), nft_type.to_string())]),
+                start_timestamp: 0,
+                lockup_period: MONTH,
+                claimed_rewards,
+            });
+        }
+        let mut stakers = UnorderedMap::new(b"s".to_vec());
+        stakers.insert(&staker_id, &StakerInfoV1 { stakes, total_rewards_claimed: 50 });
+        let mut funding_records = Vector::new(b"fundings".to_vec());
+        funding_records.push(&FundingRecordV1 { amount: 5_000, timestamp: 10 });
+        env::state_write(&NFTStakingContractV1 {
+            owner: account("owner.near"),
+            sin_token: account("sin.near"),
+            sin_nft_contract: account("nft.near"),
+            stakers,
+            reward_pool: 0,
+            last_distributed: 0,
+            reward_distribution: RewardDistributionV1 {
+                total_reward_pool: 4_000,
+                last_distributed: 0,
+                funding_records,
+            },
+            nft_weights: HashMap::from([("Queen".to_string(), 50), ("Drone".to_string(), 20)]),
+        });
+
+        let mut contract = NFTStakingContract::migrate();
+        assert!(contract.paused && contract.is_migrating());
+        set_context("owner.near", START);
+        let mut batches = 1;
+        while !contract.migrate_state(Some(1)) {
+            batches += 1;
+        }
+        // One funding record and one staker
+        assert_eq!(batches, 2);
+        assert!(!contract.is_migrating());
+        assert_eq!(contract.pending_claims_total, 1_000);
+        assert_eq!(contract.staked_type_counts["Queen"], 1);
+        assert_eq!(contract.staked_type_counts["Drone"], 1);
+        assert_eq!(contract.staked_tokens.get(&"2".to_string()), Some(staker_id.clone()));
+        let staker_info = contract.stakers.get(&staker_id).unwrap();
+        assert_eq!(staker_info.total_rewards_claimed, 50);
+        assert_eq!(staker_info.stakes.get(1).unwrap().claimed_rewards, 300);
+        assert!(staker_info.emission_weight > 0);
+        assert_eq!(contract.emission.total_weight, staker_info.emission_weight);
+        assert_eq!(contract.reward_distribution.total_reward_pool, 4_000);
+        assert_eq!(contract.reward_distribution.funding_records.len(), 1);
+        let funding = contract.reward_distribution.funding_records.get(0).unwrap();
+        assert_eq!((funding.amount, funding.sender_id), (5_000, account("owner.near")));
+        assert_eq!(contract.total_funded, 5_000);
+        contract.unpause();
+    }
+
+    #[test]
+    #[should_panic(expected = "State migration is in progress")]
+    fn unpause_waits_for_the_state_migration() {
+        set_context("staking.near", START);
+        env::state_write(&NFTStakingContractV1 {
+            owner: account("owner.near"),
+            sin_token: account("sin.near"),
+            sin_nft_contract: account("nft.near"),
+            stakers: UnorderedMap::new(b"s".to_vec()),
+            reward_pool: 0,
+            last_distributed: 0,
+            reward_distribution: RewardDistributionV1 {
+                total_reward_pool: 0,
+                last_distributed: 0,
+                funding_records: Vector::new(b"fundings".to_vec()),
+            },
+            nft_weights: HashMap::new(),
+        });
+        let mut contract = NFTStakingContract::migrate();
+        set_context("owner.near", START);
+        contract.unpause();
+    }
+
+    #[test]
+    fn rounds_split_by_type_weight() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        distribute(&mut contract, 7_000, START + DAY);
+
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![5_000]);
+        assert_eq!(record_rewards(&contract, "bob.near"), vec![2_000]);
+        assert_eq!(contract.reward_distribution.total_reward_pool, 3_000);
+        assert_eq!(contract.pending_claims_total, 7_000);
+        assert_eq!(contract.carried_remainder, 0);
+    }
+
+    #[test]
+    fn rounding_dust_is_carried_into_the_next_round() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        distribute(&mut contract, 1_000, START + DAY);
+
+        let paid =
+            record_rewards(&contract, "alice.near")[0] + record_rewards(&contract, "bob.near")[0];
+        assert_eq!(paid + contract.carried_remainder, 1_000);
+        assert_eq!(contract.pending_claims_total, paid);
+    }
+
+    #[test]
+    fn claims_and_unstakes_settle_the_liability() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        distribute(&mut contract, 7_000, START + DAY);
+
+        set_context("alice.near", START + DAY);
+        contract.claim_reward(0, None, None, None);
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![0]);
+        assert_eq!(contract.pending_claims_total, 2_000);
+        assert_eq!(contract.sin_in_flight, 5_000);
+
+        // Bob leaves before his lockup ends and forfeits the default 25% of his rewards
+        set_context("bob.near", START + DAY);
+        contract.unstake_nfts(0, Some(true), None);
+        assert_eq!(contract.pending_claims_total, 0);
+        assert_eq!(contract.sin_in_flight, 6_500);
+        assert_eq!(contract.reward_distribution.total_reward_pool, 3_500);
+        assert_eq!(contract.stakers.get(&account("bob.near")).unwrap().stakes.len(), 0);
+        assert!(contract.staked_tokens.get(&"2".to_string()).is_none());
+        assert_eq!(contract.staked_type_counts["Drone"], 0);
+        assert_eq!(contract.staked_type_counts["Queen"], 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot unstake before lockup period")]
+    fn early_unstake_requires_accepting_the_penalty() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        set_context("bob.near", START + DAY);
+        contract.unstake_nfts(0, None, None);
+    }
+
+    #[test]
+    fn trait_points_replace_the_type_weight() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        for (trait_type, value, points) in [("Eyes", "Laser", 30), ("Hat", "Crown", 15)] {
+            contract.add_trait_points(TraitPoints {
+                trait_type: trait_type.to_string(),
+                value: value.to_string(),
+                points,
+            });
+        }
+        let laser_crown = attributes(&[("Eyes", "Laser"), ("Hat", "Crown")]);
+        stake(&mut contract, "alice.near", "nft.near", "1", laser_crown);
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[("Hat", "Crown")]));
+        // Without points the Drone type weight applies
+        stake(&mut contract, "carol.near", "nft.near", "3", attributes(&[("Eyes", "Blue")]));
+        distribute(&mut contract, 800, START + DAY);
+
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![450]);
+        assert_eq!(record_rewards(&contract, "bob.near"), vec![150]);
+        assert_eq!(record_rewards(&contract, "carol.near"), vec![200]);
+    }
+
+    #[test]
+    fn rarity_scores_are_parsed_and_clamped() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_rarity_score(Some(RarityScoreConfig {
+            trait_type: "Rarity".to_string(),
+            min_weight: 10,
+            max_weight: 100,
+        }));
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Rarity", "37.9")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[("Rarity", "500")]));
+        stake(&mut contract, "carol.near", "nft.near", "3", attributes(&[("Rarity", "1e3")]));
+        distribute(&mut contract, 1_570, START + DAY);
+
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![370]);
+        assert_eq!(record_rewards(&contract, "bob.near"), vec![1_000]);
+        // A malformed score falls back to the type weight
+        assert_eq!(record_rewards(&contract, "carol.near"), vec![200]);
+    }
+
+    #[test]
+    fn collections_are_weighted_by_their_multiplier() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        let partner = account("partner.near");
+        let nft_weights = HashMap::from([("Gold".to_string(), 40)]);
+        contract.register_collection(partner.clone(), nft_weights, "Gold".to_string());
+        apply_changes(&mut contract);
+        contract.set_collection_multiplier(partner, 5_000);
+        apply_changes(&mut contract);
+
+        stake(&mut contract, "alice.near", "partner.near", "7", attributes(&[]));
+        stake(&mut contract, "bob.near", "nft.near", "7", attributes(&[("Body", "Queen")]));
+        distribute(&mut contract, 700, START + DAY);
+
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![200]);
+        assert_eq!(record_rewards(&contract, "bob.near"), vec![500]);
+        assert_eq!(contract.staked_type_counts["partner.near:Gold"], 1);
+        let staker = contract.staked_tokens.get(&"partner.near:7".to_string());
+        assert_eq!(staker, Some(account("alice.near")));
+    }
+
+    #[test]
+    #[should_panic(expected = "Reward distribution in progress")]
+    fn lockup_tiers_cannot_change_mid_round() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        set_context("owner.near", START + DAY);
+        contract.start_distribution(U128(7_000));
+        assert!(!contract.distribute_rewards_chunk(1));
+
+        contract.set_lockup_tier(LockupTier {
+            lockup_period: MONTH,
+            early_exit_penalty_bps: 0,
+            reward_multiplier_bps: 20_000,
+        });
+        apply_changes(&mut contract);
+    }
+
+    #[test]
+    fn failed_unstake_transfers_are_restored_after_the_round() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        stake(&mut contract, "carol.near", "nft.near", "3", attributes(&[]));
+        let carol = account("carol.near");
+        let stake = contract.stakers.get(&carol).unwrap().stakes.get(0).unwrap();
+        set_context("carol.near", START + DAY);
+        contract.unstake_nfts(0, Some(true), None);
+        set_context("owner.near", START + DAY);
+        contract.start_distribution(U128(7_000));
+        assert!(!contract.distribute_rewards_chunk(2));
+
+        // The NFT transfer back to Carol fails once the round has weighed the stakers
+        set_failed_callback_context(START + DAY);
+        assert!(!contract.unstake_nft_callback(carol.clone(), stake));
+        assert_eq!(contract.pending_restores.len(), 1);
+        assert_eq!(contract.staked_tokens.get(&"3".to_string()), Some(carol.clone()));
+        assert_eq!(contract.stakers.get(&carol).unwrap().stakes.len(), 0);
+
+        // The restored record is not paid on top of the weighed total
+        set_context("owner.near", START + DAY);
+        assert!(contract.distribute_rewards_chunk(10));
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![5_000]);
+        assert_eq!(record_rewards(&contract, "bob.near"), vec![2_000]);
+        assert_eq!(contract.carried_remainder, 0);
+        assert!(contract.pending_restores.is_empty());
+        assert_eq!(record_rewards(&contract, "carol.near"), vec![0]);
+        assert_eq!(contract.staked_type_counts["Drone"], 2);
+    }
+
+    #[test]
+    fn campaigns_stream_to_stakers() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        set_context("owner.near", START);
+        contract.add_campaign_sponsor(account("partner.near"), account("partner-token.near"));
+        set_context("partner-token.near", START);
+        let msg = json!({ "duration": DAY }).to_string();
+        contract.ft_on_transfer(account("partner.near"), U128(8_640), msg);
+        assert_eq!(contract.get_campaigns().len(), 1);
+
+        set_context("alice.near", START + DAY / 2);
+        let rewards = contract.get_campaign_rewards(account("alice.near"));
+        assert_eq!(rewards[0].token_id, account("partner-token.near"));
+        assert_eq!(rewards[0].amount.0, 4_320);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only approved sponsors can open a bonus farm in this token")]
+    fn campaigns_reject_tokens_the_sponsor_was_not_approved_for() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.add_campaign_sponsor(account("partner.near"), account("partner-token.near"));
+        // Any contract can call ft_on_transfer claiming to be a transfer from the sponsor
+        set_context("worthless-token.near", START);
+        let msg = json!({ "duration": DAY }).to_string();
+        contract.ft_on_transfer(account("partner.near"), U128(1), msg);
+    }
+
+    #[test]
+    fn rewards_expire_once_a_record_goes_unclaimed_across_rounds() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_reward_expiry(MIN_REWARD_EXPIRY);
+        apply_changes(&mut contract);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        distribute(&mut contract, 7_000, START + DAY);
+        // Only a claim restarts the window, here Alice's
+        set_context("alice.near", START + 6 * DAY);
+        contract.claim_reward(0, None, None, Some(U128(1_000)));
+        // Later rounds keep crediting Bob's record without restarting its window
+        for month in 1..=2 {
+            distribute(&mut contract, 700, START + DAY + month * MONTH);
+        }
+
+        set_context("owner.near", START + MIN_REWARD_EXPIRY - 1);
+        assert_eq!(contract.sweep_expired_rewards(None, None).0, 0);
+        set_context("owner.near", START + MIN_REWARD_EXPIRY);
+        assert_eq!(contract.sweep_expired_rewards(None, None).0, 2_400);
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![5_000]);
+        assert_eq!(record_rewards(&contract, "bob.near"), vec![0]);
+
+        distribute(&mut contract, 700, START + DAY + 3 * MONTH);
+        set_context("owner.near", START + 6 * DAY + MIN_REWARD_EXPIRY);
+        assert_eq!(contract.sweep_expired_rewards(None, None).0, 5_700);
+        assert_eq!(contract.pending_claims_total, 0);
+        assert_eq!(contract.reward_distribution.total_reward_pool, 9_000);
+    }
+
+    #[test]
+    fn stats_are_contract_wide_except_the_paged_weight() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        stake(&mut contract, "bob.near", "nft.near", "3", attributes(&[]));
+
+        let first_page = contract.get_stats(Some(0), Some(1));
+        assert_eq!(first_page.total_stakers, 2);
+        assert_eq!(first_page.total_nfts_staked, 3);
+        assert_eq!(first_page.nfts_per_type["Drone"], 2);
+        assert_eq!(first_page.nfts_per_type["Queen"], 1);
+        let second_page = contract.get_stats(Some(1), Some(1));
+        let whole = contract.get_stats(None, None);
+        assert_eq!(first_page.total_weight.0 + second_page.total_weight.0, whole.total_weight.0);
+    }
+
+    #[test]
+    fn funding_memos_are_truncated_whatever_the_intent() {
+        let (funding_msg, memo) = FundingMsg::parse(&format!("fund:{}", "a".repeat(300)));
+        assert!(matches!(funding_msg, FundingMsg::Fund));
+        assert_eq!(memo.unwrap().len(), MAX_MEMO_LENGTH);
+        let (funding_msg, memo) = FundingMsg::parse(&"é".repeat(200));
+        assert!(matches!(funding_msg, FundingMsg::Fund));
+        assert_eq!(memo.unwrap().len(), MAX_MEMO_LENGTH);
+        let (funding_msg, memo) = FundingMsg::parse("campaign:3");
+        assert!(matches!(funding_msg, FundingMsg::TopUpCampaign(3)));
+        assert!(memo.is_none());
+    }
+
+    #[test]
+    fn campaign_top_ups_stream_over_the_remaining_time() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        set_context("owner.near", START);
+        contract.add_campaign_sponsor(account("partner.near"), account("partner-token.near"));
+        set_context("partner-token.near", START);
+        let msg = json!({ "duration": 2 * DAY }).to_string();
+        contract.ft_on_transfer(account("partner.near"), U128(1_000), msg);
+
+        // Half of the campaign has streamed; the top-up streams over the other half
+        set_context("partner-token.near", START + DAY);
+        contract.ft_on_transfer(account("partner.near"), U128(2_000), "campaign:0".to_string());
+        set_context("alice.near", START + DAY + DAY / 2);
+        assert_eq!(contract.get_campaign_rewards(account("alice.near"))[0].amount.0, 1_750);
+        set_context("alice.near", START + 2 * DAY);
+        assert_eq!(contract.get_campaign_rewards(account("alice.near"))[0].amount.0, 3_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "No staked weight to snapshot")]
+    fn snapshots_without_weight_are_rejected() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START + DAY);
+        contract.take_snapshot();
+    }
+
+    #[test]
+    fn snapshots_weighed_in_batches_drop_when_nothing_qualifies() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_min_stake_age(DAY);
+        apply_changes(&mut contract);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        // Alice's NFT is too young for a snapshot weighed a batch at a time
+        set_context("owner.near", START + DAY - 1);
+        let round = contract.internal_new_round(0, env::block_timestamp());
+        contract.active_distribution = Some(round);
+        assert!(contract.distribute_rewards_chunk(1));
+
+        assert!(contract.active_distribution.is_none());
+        assert_eq!(contract.last_distributed, START * 1_000_000_000);
+    }
+
+    #[test]
+    fn unstaking_while_claims_are_paused_keeps_the_rewards() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        distribute(&mut contract, 7_000, START + DAY);
+        set_context("owner.near", START + DAY);
+        contract.set_operation_paused(Operation::Claims, true);
+
+        set_context("bob.near", START + DAY);
+        contract.unstake_nfts(0, Some(true), None);
+        let bob = contract.stakers.get(&account("bob.near")).unwrap();
+        assert_eq!(bob.unclaimed_rewards, 1_500);
+        assert_eq!(contract.sin_in_flight, 0);
+        assert_eq!(contract.pending_claims_total, 6_500);
+    }
+
+    #[test]
+    fn reconciliation_counts_transfers_in_flight() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        distribute(&mut contract, 7_000, START + DAY);
+        set_context("alice.near", START + DAY);
+        contract.claim_reward(0, None, None, None);
+
+        set_context("staking.near", START + DAY);
+        let reconciliation = contract.reconcile_callback(Ok(U128(10_000)));
+        assert_eq!(reconciliation.accounted.0, 10_000);
+        assert_eq!(reconciliation.surplus.0 + reconciliation.deficit.0, 0);
+    }
+
+    #[test]
+    fn distributors_can_run_rounds_until_revoked() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        set_context("owner.near", START);
+        contract.grant_role(account("keeper.near"), Role::Distributor);
+        assert!(contract.has_role(account("keeper.near"), Role::Distributor));
+        assert!(!contract.has_role(account("keeper.near"), Role::Treasurer));
+        // The owner holds every role
+        assert!(contract.has_role(account("owner.near"), Role::Treasurer));
+
+        set_context("keeper.near", START + DAY);
+        contract.distribute_rewards(U128(1_000));
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![1_000]);
+        set_context("owner.near", START + DAY);
+        contract.revoke_role(account("keeper.near"), Role::Distributor);
+        assert!(contract.get_roles(account("keeper.near")).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Caller does not have the Owner role")]
+    fn distributors_cannot_change_the_configuration() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.grant_role(account("keeper.near"), Role::Distributor);
+        set_context("keeper.near", START);
+        contract.set_nft_weight("Drone".to_string(), 100);
+    }
+
+    #[test]
+    fn config_changes_wait_out_the_timelock() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_timelock_duration(DAY);
+        contract.set_nft_weight("Drone".to_string(), 30);
+        contract.set_nft_weight("Worker".to_string(), 5);
+        let pending = contract.get_pending_changes();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].executable_at.0, (START + DAY) * 1_000_000_000);
+
+        contract.cancel_config_change(pending[1].id);
+        // Executing a due change is open to anyone
+        set_context("alice.near", START + DAY);
+        contract.execute_config_change(pending[0].id);
+        assert!(contract.get_pending_changes().is_empty());
+        assert_eq!(contract.nft_weights["Drone"], 30);
+        assert_ne!(contract.nft_weights.get("Worker"), Some(&5));
+    }
+
+    #[test]
+    #[should_panic(expected = "Config change is still timelocked")]
+    fn config_changes_cannot_run_early() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_timelock_duration(DAY);
+        contract.set_nft_weight("Drone".to_string(), 30);
+        let id = contract.get_pending_changes()[0].id;
+        set_context("owner.near", START + DAY - 1);
+        contract.execute_config_change(id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract is paused")]
+    fn pausing_stops_claims() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        distribute(&mut contract, 1_000, START + DAY);
+        set_context("owner.near", START + DAY);
+        contract.pause();
+        set_context("alice.near", START + DAY);
+        contract.claim_reward(0, None, None, None);
+    }
+
+    #[test]
+    fn claims_resume_once_unpaused() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        distribute(&mut contract, 1_000, START + DAY);
+        set_context("owner.near", START + DAY);
+        contract.pause();
+        assert!(contract.is_operation_paused(Operation::Claims));
+        contract.unpause();
+        assert!(!contract.is_operation_paused(Operation::Claims));
+
+        set_context("alice.near", START + DAY);
+        contract.claim_reward(0, None, None, None);
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Claims is paused")]
+    fn paused_operations_are_rejected_individually() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        set_context("owner.near", START);
+        contract.set_operation_paused(Operation::Claims, true);
+        assert_eq!(contract.get_paused_operations(), vec![Operation::Claims]);
+        // Other operations carry on
+        distribute(&mut contract, 1_000, START + DAY);
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![1_000]);
+
+        set_context("alice.near", START + DAY);
+        contract.claim_reward(0, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Account alice.near is blacklisted")]
+    fn blacklisted_accounts_cannot_claim() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        distribute(&mut contract, 1_000, START + DAY);
+        set_context("owner.near", START + DAY);
+        contract.add_to_blacklist(account("alice.near"));
+        assert!(contract.is_blacklisted(account("alice.near")));
+        set_context("alice.near", START + DAY);
+        contract.claim_reward(0, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Staking is limited to whitelisted accounts")]
+    fn whitelist_only_staking_rejects_other_accounts() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_whitelist_only(true);
+        contract.add_to_whitelist(account("alice.near"));
+        assert!(contract.is_whitelisted(account("alice.near")));
+        set_context("nft.near", START);
+        contract.nft_on_transfer(account("bob.near"), "2".to_string(), String::new());
+    }
+
+    #[test]
+    fn blacklisted_tokens_stop_carrying_weight() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        set_context("owner.near", START);
+        contract.add_token_to_blacklist("1".to_string());
+        assert!(contract.is_token_blacklisted("1".to_string()));
+        distribute(&mut contract, 1_000, START + DAY);
+
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![0]);
+        assert_eq!(record_rewards(&contract, "bob.near"), vec![1_000]);
+    }
+
+    #[test]
+    #[should_panic(expected = "NFT 9 is blacklisted")]
+    fn blacklisted_tokens_cannot_be_staked() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.add_token_to_blacklist("9".to_string());
+        register(&mut contract, "alice.near");
+        set_context("nft.near", START);
+        contract.nft_on_transfer(account("alice.near"), "9".to_string(), String::new());
+    }
+
+    #[test]
+    fn blacklisted_nfts_are_recovered_off_their_records() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        distribute(&mut contract, 7_000, START + DAY);
+        set_context("owner.near", START + DAY);
+        contract.add_token_to_blacklist("1".to_string());
+        contract.recover_blacklisted_nft("1".to_string(), account("escrow.near"));
+
+        // The emptied record is removed; its rewards stay with Alice
+        let alice = contract.stakers.get(&account("alice.near")).unwrap();
+        assert_eq!(alice.stakes.len(), 0);
+        assert_eq!(alice.unclaimed_rewards, 5_000);
+        assert!(contract.staked_tokens.get(&"1".to_string()).is_none());
+        assert_eq!(contract.staked_type_counts["Queen"], 0);
+        assert_eq!(contract.pending_claims_total, 7_000);
+    }
+
+    #[test]
+    fn storage_deposits_can_be_topped_up_withdrawn_and_unregistered() {
+        let mut contract = setup(10_000);
+        register(&mut contract, "alice.near");
+        let min_deposit = STORAGE_MIN_DEPOSIT.as_yoctonear();
+        let balance = contract.storage_balance_of(account("alice.near")).unwrap();
+        assert_eq!(balance.total.as_yoctonear(), min_deposit);
+
+        set_deposit_context("alice.near", STORAGE_MIN_DEPOSIT);
+        let balance = contract.storage_deposit(None, None);
+        assert_eq!(balance.total.as_yoctonear(), 2 * min_deposit);
+        assert_eq!(contract.total_storage_deposits, 2 * min_deposit);
+
+        set_deposit_context("alice.near", NearToken::from_yoctonear(1));
+        // Only what the account's storage doesn't use is withdrawn
+        let balance = contract.storage_withdraw(None);
+        assert_eq!(balance.available.as_yoctonear(), 0);
+        assert!(balance.total.as_yoctonear() < 2 * min_deposit);
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(account("alice.near")).is_none());
+        assert_eq!(contract.total_storage_deposits, 0);
+    }
+
+    #[test]
+    fn reward_tokens_are_shared_by_weight_and_claimed() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        let usdc = account("usdc.near");
+        set_context("owner.near", START);
+        contract.add_reward_token(usdc.clone());
+        set_context("usdc.near", START);
+        contract.ft_on_transfer(account("owner.near"), U128(700), String::new());
+        set_context("owner.near", START);
+        contract.distribute_token_rewards(usdc.clone(), U128(700));
+
+        assert_eq!(contract.get_token_rewards(account("alice.near"))[0].amount.0, 500);
+        assert_eq!(contract.get_token_rewards(account("bob.near"))[0].amount.0, 200);
+        set_context("alice.near", START);
+        contract.claim_token_rewards(usdc.clone());
+        assert_eq!(contract.get_token_rewards(account("alice.near"))[0].amount.0, 0);
+        // A failed transfer is credited back
+        set_failed_callback_context(START);
+        contract.claim_token_callback(account("alice.near"), usdc, U128(500));
+        assert_eq!(contract.get_token_rewards(account("alice.near"))[0].amount.0, 500);
+    }
+
+    #[test]
+    fn near_rewards_are_shared_by_weight() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        set_deposit_context("owner.near", NearToken::from_yoctonear(700));
+        contract.fund_near(Some("March".to_string()));
+        assert_eq!(contract.near_rewards.distribution.total_reward_pool, 700);
+        set_context("owner.near", START);
+        contract.distribute_near_rewards(U128(700));
+
+        assert_eq!(contract.get_near_rewards(account("alice.near")).0, 500);
+        assert_eq!(contract.get_near_rewards(account("bob.near")).0, 200);
+        assert_eq!(contract.near_rewards.distribution.total_reward_pool, 0);
+    }
+
+    #[test]
+    fn distributed_rewards_vest_linearly() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_reward_vesting(10 * DAY);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        distribute(&mut contract, 1_000, START + DAY);
+
+        let stake = contract.stakers.get(&account("alice.near")).unwrap().stakes.get(0).unwrap();
+        assert_eq!(stake.claimable_rewards((START + DAY) * 1_000_000_000), 0);
+        set_context("alice.near", START + 6 * DAY);
+        contract.claim_reward(0, None, None, None);
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![500]);
+        assert_eq!(contract.sin_in_flight, 500);
+    }
+
+    #[test]
+    fn nfts_over_the_account_cap_are_returned() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_max_nfts_per_account(Some(2));
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[]));
+        stake(&mut contract, "alice.near", "nft.near", "2", attributes(&[]));
+        assert!(try_stake(&mut contract, "alice.near", "nft.near", "3", attributes(&[])));
+        assert!(!try_stake(&mut contract, "bob.near", "nft.near", "3", attributes(&[])));
+        assert!(contract.staked_tokens.get(&"3".to_string()) == Some(account("bob.near")));
+    }
+
+    #[test]
+    fn nfts_over_the_type_cap_are_returned() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_type_cap("Queen".to_string(), Some(1));
+        let queen = || attributes(&[("Body", "Queen")]);
+        stake(&mut contract, "alice.near", "nft.near", "1", queen());
+        assert!(try_stake(&mut contract, "bob.near", "nft.near", "2", queen()));
+        // Drones are not capped
+        stake(&mut contract, "bob.near", "nft.near", "3", attributes(&[]));
+
+        // Unstaking frees the place under the cap
+        set_context("alice.near", START);
+        contract.unstake_nfts(0, Some(true), None);
+        stake(&mut contract, "bob.near", "nft.near", "2", queen());
+        assert_eq!(contract.get_staked_type_counts()["Queen"], 1);
+    }
+
+    #[test]
+    fn account_shares_above_the_cap_go_to_the_others() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_max_account_share(5_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        stake(&mut contract, "carol.near", "nft.near", "3", attributes(&[]));
+        distribute(&mut contract, 9_000, START + DAY);
+
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![4_500]);
+        assert_eq!(record_rewards(&contract, "bob.near"), vec![2_250]);
+        assert_eq!(record_rewards(&contract, "carol.near"), vec![2_250]);
+    }
+
+    #[test]
+    fn type_pools_are_earmarked_for_their_type() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_type_pool_share("Queen".to_string(), 6_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        distribute(&mut contract, 1_400, START + DAY);
+
+        // 840 for Queens only, the other 560 split 50:20 across every NFT
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![1_240]);
+        assert_eq!(record_rewards(&contract, "bob.near"), vec![160]);
+    }
+
+    #[test]
+    fn rounds_pay_against_a_waiting_snapshot() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        set_context("owner.near", START + DAY);
+        assert!(contract.take_snapshot());
+        // The locked-in total is reported whatever the page
+        assert_eq!(contract.get_total_weight(None, None), contract.get_total_weight(Some(5), None));
+        // Staking changes wait until the round pays out
+        assert!(try_stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[])));
+
+        distribute(&mut contract, 1_000, START + DAY);
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![1_000]);
+        assert!(contract.active_distribution.is_none());
+    }
+
+    #[test]
+    fn merged_records_keep_their_nfts_and_rewards() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "alice.near", "nft.near", "2", attributes(&[]));
+        distribute(&mut contract, 1_400, START + DAY);
+
+        set_context("alice.near", START + DAY);
+        assert_eq!(contract.merge_stakes(vec![1, 0]), 0);
+        let staker_info = contract.stakers.get(&account("alice.near")).unwrap();
+        assert_eq!(staker_info.stakes.len(), 1);
+        let merged = staker_info.stakes.get(0).unwrap();
+        assert_eq!(merged.nft_ids.len(), 2);
+        assert_eq!(merged.claimed_rewards, 1_400);
+        // The merged record earns what both did
+        distribute(&mut contract, 1_400, START + 2 * DAY);
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![2_800]);
+    }
+
+    #[test]
+    fn split_records_leave_the_rewards_on_the_original() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "alice.near", "nft.near", "2", attributes(&[]));
+        set_context("alice.near", START);
+        contract.merge_stakes(vec![0, 1]);
+        distribute(&mut contract, 1_400, START + DAY);
+
+        set_context("alice.near", START + DAY);
+        assert_eq!(contract.split_stake(0, vec!["2".to_string()]), 1);
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![1_400, 0]);
+        let staker_info = contract.stakers.get(&account("alice.near")).unwrap();
+        assert_eq!(staker_info.stakes.get(0).unwrap().nft_ids, vec!["1".to_string()]);
+        assert_eq!(staker_info.stakes.get(1).unwrap().nft_ids, vec!["2".to_string()]);
+        distribute(&mut contract, 700, START + 2 * DAY);
+        assert_eq!(record_rewards(&contract, "alice.near"), vec![1_900, 200]);
+    }
+
+    #[test]
+    fn transferred_records_move_with_their_rewards() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[]));
+        distribute(&mut contract, 7_000, START + DAY);
+
+        register(&mut contract, "bob.near");
+        set_deposit_context("alice.near", NearToken::from_yoctonear(1));
+        contract.transfer_stake(0, account("bob.near"));
+        assert!(record_rewards(&contract, "alice.near").is_empty());
+        assert_eq!(contract.staked_tokens.get(&"1".to_string()), Some(account("bob.near")));
+        assert_eq!(record_rewards(&contract, "bob.near"), vec![2_000, 5_000]);
+    }
+
+    fn tokenized_contract() -> NFTStakingContract {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_position_manager(Some(account("positions.near")));
+        apply_changes(&mut contract);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[]));
+        set_deposit_context("alice.near", NearToken::from_yoctonear(1));
+        let _ = contract.tokenize_position(0);
+        contract
+    }
+
+    #[test]
+    fn tokenized_records_follow_their_receipt() {
+        let mut contract = tokenized_contract();
+        let position_id = contract.stakers.get(&account("alice.near")).unwrap().stakes.get(0);
+        let position_id = position_id.unwrap().position_id;
+
+        set_context("positions.near", START);
+        contract.position_transfer(account("alice.near"), position_id, account("bob.near"));
+        assert!(record_rewards(&contract, "alice.near").is_empty());
+        assert_eq!(contract.staked_tokens.get(&"1".to_string()), Some(account("bob.near")));
+
+        contract.position_release(account("bob.near"), position_id);
+        let staker_info = contract.stakers.get(&account("bob.near")).unwrap();
+        assert!(!staker_info.stakes.get(0).unwrap().tokenized);
     }
-}
\ No newline at end of file
+
+    #[test]
+    #[should_panic(expected = "is tokenized, redeem its receipt first")]
+    fn tokenized_records_cannot_be_unstaked() {
+        let mut contract = tokenized_contract();
+        set_context("alice.near", START + 400 * DAY);
+        contract.unstake_nfts(0, Some(true), None);
+    }
+
+    #[test]
+    fn failed_claim_transfers_are_restored() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[]));
+        distribute(&mut contract, 1_000, START + DAY);
+        set_context("alice.near", START + DAY);
+        contract.claim_reward(0, None, None, None);
+        assert_eq!(contract.sin_in_flight, 1_000);
+        assert_eq!(contract.pending_claims_total, 0);
+
+        set_failed_callback_context(START + DAY);
+        let claim = ClaimRecord::new(1_000, Some(0));
+        let paid = contract.claim_reward_callback(account("alice.near"), U128(1_000), Some(claim));
+        assert_eq!(paid, U128(0));
+        assert_eq!(contract.sin_in_flight, 0);
+        assert_eq!(contract.pending_claims_total, 1_000);
+        let staker_info = contract.stakers.get(&account("alice.near")).unwrap();
+        assert_eq!(staker_info.unclaimed_rewards, 1_000);
+        assert_eq!(staker_info.total_rewards_claimed, 0);
+    }
+
+    #[test]
+    fn emergency_shutdown_returns_every_nft_with_its_rewards() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[("Body", "Queen")]));
+        stake(&mut contract, "alice.near", "nft.near", "2", attributes(&[]));
+        stake(&mut contract, "bob.near", "nft.near", "3", attributes(&[]));
+        distribute(&mut contract, 9_000, START + DAY);
+
+        set_context("owner.near", START + DAY);
+        contract.start_emergency_shutdown();
+        assert_eq!(contract.emergency_return_nfts(None), None);
+        for token_id in ["1", "2", "3"] {
+            assert!(contract.staked_tokens.get(&token_id.to_string()).is_none());
+        }
+        let alice = contract.stakers.get(&account("alice.near")).unwrap();
+        assert!(alice.stakes.is_empty());
+        assert_eq!(alice.unclaimed_rewards, 7_000);
+        let bob = contract.stakers.get(&account("bob.near")).unwrap();
+        assert_eq!(bob.unclaimed_rewards, 2_000);
+        assert!(try_stake(&mut contract, "carol.near", "nft.near", "4", attributes(&[])));
+    }
+
+    #[test]
+    fn sunset_waives_lockups_and_refuses_new_stakes() {
+        let mut contract = setup(10_000);
+        stake(&mut contract, "alice.near", "nft.near", "1", attributes(&[]));
+        distribute(&mut contract, 1_000, START + DAY);
+
+        set_context("owner.near", START + DAY);
+        contract.set_sunset();
+        apply_changes(&mut contract);
+        assert!(contract.is_sunset());
+        assert!(try_stake(&mut contract, "bob.near", "nft.near", "2", attributes(&[])));
+
+        // A month-long lockup, yet the record leaves without a penalty
+        set_context("alice.near", START + DAY);
+        contract.unstake_nfts(0, None, None);
+        assert!(contract.staked_tokens.get(&"1".to_string()).is_none());
+        assert_eq!(contract.sin_in_flight, 1_000);
+        assert_eq!(contract.reward_distribution.total_reward_pool, 9_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Funding is closed during sunset")]
+    fn sunset_refuses_new_funding() {
+        let mut contract = setup(10_000);
+        set_context("owner.near", START);
+        contract.set_sunset();
+        apply_changes(&mut contract);
+        set_context("sin.near", START);
+        contract.ft_on_transfer(account("owner.near"), U128(1_000), String::new());
+    }
+}